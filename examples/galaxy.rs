@@ -1,5 +1,6 @@
 use relational_ecs::*;
 use relational_ecs::prelude::*;
+use relational_ecs::hierarchy::propagate;
 use crate::state::*;
 use crate::entities::*;
 use crate::components::*;
@@ -36,6 +37,7 @@ pub mod state {
         pub orbit_period: IndexedVec<OrbitId, Period>,
         pub orbit_angle_offset: IndexedVec<OrbitId, Angle>,
         pub orbit_relative_position: IndexedVec<OrbitId, Position>,
+        pub orbit_position: IndexedVec<OrbitId, Position>,
         pub orbit_parent: IndexedVec<OrbitId, Option<OrbitId>>,
 
         pub transit_location: IndexedVec<TransitId, LocationId>,
@@ -87,7 +89,7 @@ pub mod state {
         pub pressure: Pressure
     }
 
-    impl<'a> OrbitRow {
+    impl OrbitRow {
         pub fn from_parent(radius: Radius, period: Period, angle: Angle, galaxy: &Galaxy, parent: BodyId) -> Self {
             let parent_orbit = galaxy.state
                 .lookup2(parent, &galaxy.entities.bodies, &galaxy.entities.locations, &galaxy.entities.orbits)
@@ -102,14 +104,49 @@ pub mod state {
         }
     }
 
-    link_to_many!(SystemId, system_locations, LocationId, location_system);
+    links! {
+        link_to_many(SystemId, system_locations, LocationId, location_system, owns: Cascade);
 
-    link!(LocationId, location_transit, TransitId, transit_location);
-    link!(LocationId, location_orbit, OrbitId, orbit_location);
-    link!(LocationId, location_body, BodyId, body_location);
+        link(LocationId, location_transit, TransitId, transit_location, owns: Cascade);
+        link(LocationId, location_orbit, OrbitId, orbit_location, owns: Cascade);
+        link(LocationId, location_body, BodyId, body_location, owns: Cascade, owned_by: Cascade);
 
-    link!(BodyId, body_surface, SurfaceId, surface_body);
-    link!(BodyId, body_atmosphere, AtmosphereId, atmosphere_body);
+        link(BodyId, body_surface, SurfaceId, surface_body, owns: Cascade);
+        link(BodyId, body_atmosphere, AtmosphereId, atmosphere_body, owns: Cascade);
+    }
+
+    /// The ownership edges declared above, walked by `kill_cascade`.
+    pub fn ownership() -> Ownership<State, Allocators> {
+        Ownership::new()
+    }
+
+    /// Every allocator and column by name, with the links declared above, for inspecting entities.
+    pub fn registry() -> Registry<State, Allocators> {
+        registry!(State, Allocators;
+            tables: systems, locations, orbits, transits, bodies, surfaces, atmospheres;
+            columns:
+                system_name, system_position,
+                location_position,
+                orbit_radius, orbit_period, orbit_angle_offset, orbit_relative_position, orbit_position,
+                transit_ends, transit_duration,
+                body_radius, body_mass,
+                surface_albedo, surface_area,
+                atmosphere_greenhouse, atmosphere_pressure;
+            references:
+                orbit_parent,
+                system_locations, location_system,
+                location_transit, transit_location,
+                location_orbit, orbit_location,
+                location_body, body_location,
+                body_surface, surface_body,
+                body_atmosphere, atmosphere_body)
+            .links()
+    }
+
+    /// The registry, for integrity checks.
+    pub fn schema() -> Schema<State, Allocators> {
+        Schema::new(registry())
+    }
 
     impl Insert<BodyId, BodyRow> for State {
         fn insert(&mut self, id: &VerifiedEntity<BodyId>, value: BodyRow) {
@@ -118,6 +155,7 @@ pub mod state {
         }
     }
     impl Create<'_, BodyId, BodyRow> for State {}
+    insert_rows!(State, BodyId, BodyRow: body_radius, body_mass);
 
     impl Insert<SurfaceId, SurfaceRow> for State {
         fn insert(&mut self, id: &VerifiedEntity<SurfaceId>, value: SurfaceRow) {
@@ -126,6 +164,7 @@ pub mod state {
         }
     }
     impl Create<'_, SurfaceId, SurfaceRow> for State {}
+    insert_rows!(State, SurfaceId, SurfaceRow: surface_albedo, surface_area);
 
     impl Insert<TransitId, TransitRow> for State {
         fn insert(&mut self, id: &VerifiedEntity<TransitId>, value: TransitRow) {
@@ -142,6 +181,7 @@ pub mod state {
         }
     }
     impl Create<'_, AtmosphereId, AtmosphereRow> for State {}
+    insert_rows!(State, AtmosphereId, AtmosphereRow: atmosphere_greenhouse, atmosphere_pressure);
 
     impl Insert<SystemId, SystemRow> for State {
         fn insert(&mut self, id: &VerifiedEntity<SystemId>, value: (String, LightYears)) {
@@ -158,6 +198,7 @@ pub mod state {
         }
     }
     impl Create<'_, LocationId, LocationRow> for State {}
+    insert_rows!(State, LocationId, LocationRow: location_position);
 
     impl Insert<OrbitId, OrbitRow> for State {
         fn insert(&mut self, id: &VerifiedEntity<OrbitId>, value: OrbitRow) {
@@ -165,10 +206,13 @@ pub mod state {
             self.orbit_period.insert(id, value.period);
             self.orbit_angle_offset.insert(id, value.angle);
             self.orbit_relative_position.insert(id, Position::default());
+            self.orbit_position.insert(id, Position::default());
             self.orbit_parent.insert(id, value.parent);
         }
     }
     impl Create<'_, OrbitId, OrbitRow> for State {}
+    insert_rows!(State, OrbitId, OrbitRow:
+        orbit_radius, orbit_period, orbit_angle_offset, orbit_relative_position, orbit_position, orbit_parent);
 
     /// Complicated entity graphs constructed in one flat "layer", rather than a nested structure.
     /// This keeps allocator borrows simple
@@ -181,33 +225,39 @@ pub mod state {
         pub atmosphere: Option<AtmosphereRow>,
     }
     
-    impl Construct<BodyId, Planet> for Galaxy {
-        fn construct(&mut self, planet: Planet) -> BodyId {
+    impl TryConstruct<BodyId, Planet> for Galaxy {
+        fn try_construct(&mut self, planet: Planet) -> Result<BodyId, ConstructError> {
             let (entities, state) = self.split();
-            
-            let system = entities.systems.verify(planet.system)
-                .expect("invalid system id");
+            let mut builder = Builder::new(state, entities);
 
-            if let Some(parent) = &planet.orbit.parent {
-                let _parent = entities.orbits.verify(*parent)
-                    .expect("invalid parent orbit");
+            builder.verify(planet.system)?;
+
+            if let Some(parent) = planet.orbit.parent {
+                builder.verify(parent)?;
             }
 
-            let location = state.create_and_link(&system, Position::default(), &mut entities.locations);
+            let location: LocationId = builder.create_and_link(planet.system, Position::default())?;
 
-            let _orbit = state.create_and_link(&location, planet.orbit, &mut entities.orbits);
+            let _orbit: OrbitId = builder.create_and_link(location, planet.orbit)?;
 
-            let body = state.create_and_link(&location, planet.body, &mut entities.bodies);
+            let body: BodyId = builder.create_and_link(location, planet.body)?;
 
             if let Some(surface) = planet.surface {
-                let _surface = state.create_and_link(&body, surface, &mut entities.surfaces);
+                let _surface: SurfaceId = builder.create_and_link(body, surface)?;
             }
 
             if let Some(atmosphere) = planet.atmosphere {
-                let _atmosphere = state.create_and_link(&body, atmosphere, &mut entities.atmospheres);
+                let _atmosphere: AtmosphereId = builder.create_and_link(body, atmosphere)?;
             }
 
-            body.entity
+            builder.commit();
+            Ok(body)
+        }
+    }
+
+    impl Construct<BodyId, Planet> for Galaxy {
+        fn construct(&mut self, planet: Planet) -> BodyId {
+            self.try_construct(planet).expect("invalid planet")
         }
     }
 
@@ -220,51 +270,42 @@ pub mod state {
 
     /// Similar entities with different requirements can be built from different types.
     /// Planet and Moon are both used to Construct a BodyId, but have different requirements.
-    impl Construct<BodyId, Moon> for Galaxy {
-        fn construct(&mut self, moon: Moon) -> BodyId {
-            let system = self.entities.systems.verify(moon.system)
-                .expect("invalid system id");
+    impl TryConstruct<BodyId, Moon> for Galaxy {
+        fn try_construct(&mut self, moon: Moon) -> Result<BodyId, ConstructError> {
+            let mut builder = Builder::new(&mut self.state, &mut self.entities);
 
-            // This requirement could be tested when initializing Moon, but is here for the sake of example.
-            let parent = &moon.orbit.parent.expect("moons must have a parent orbit");
-            let _parent = self.entities.orbits.verify(*parent)
-                .expect("Planet::create - invalid parent orbit");
+            builder.verify(moon.system)?;
 
-            let location = self.state.create_and_link(&system, Position::default(), &mut self.entities.locations);
+            // This requirement could be tested when initializing Moon, but is here for the sake of example.
+            let parent = moon.orbit.parent.ok_or(ConstructError::Invalid("moons must have a parent orbit"))?;
+            builder.verify(parent)?;
 
-            let _orbit = self.state.create_and_link(&location, moon.orbit, &mut self.entities.orbits);
+            let location: LocationId = builder.create_and_link(moon.system, Position::default())?;
 
-            let body = self.state.create_and_link(&location, moon.body, &mut self.entities.bodies);
+            let _orbit: OrbitId = builder.create_and_link(location, moon.orbit)?;
 
-            let _surface = self.state.create_and_link(&body, moon.surface, &mut self.entities.surfaces);
+            let body: BodyId = builder.create_and_link(location, moon.body)?;
 
-            body.entity
-        }
-    }
+            let _surface: SurfaceId = builder.create_and_link(body, moon.surface)?;
 
-    impl Remove<BodyId, SurfaceId> for State {
-        fn remove(&mut self, id: &VerifiedEntity<BodyId>) {
-            self.body_surface.remove(id);
+            builder.commit();
+            Ok(body)
         }
     }
-    impl Delete<BodyId, SurfaceId> for State {}
 
-    impl Remove<BodyId, AtmosphereId> for State {
-        fn remove(&mut self, id: &VerifiedEntity<BodyId>) {
-            self.body_atmosphere.remove(id);
+    impl Construct<BodyId, Moon> for Galaxy {
+        fn construct(&mut self, moon: Moon) -> BodyId {
+            self.try_construct(moon).expect("invalid moon")
         }
     }
-    impl Delete<BodyId, AtmosphereId> for State {}
 
+    /// Kills the body along with its location, orbit, surface and atmosphere.
     impl Deconstruct<BodyId> for Galaxy {
         fn deconstruct(&mut self, id: BodyId) {
             let (e, s) = self.split();
 
-            if let Some(body) = e.bodies.verify(id) {
-                s.delete(&body, &mut e.surfaces);
-                s.delete(&body, &mut e.atmospheres);
-                e.bodies.kill(id);
-            }
+            ownership().kill_cascade(s, e, id)
+                .expect("bodies have no restricted edges");
         }
     }
 
@@ -291,20 +332,17 @@ pub mod state {
         fn update(state: &mut Galaxy) {
             let (e, s) = state.split();
 
-            s.location_orbit
-                .verified_both(&e.locations, &e.orbits)
-                .for_each(|(_loc, orbit)| {
-                    let mut _position = s.orbit_relative_position[&orbit];
-                    let mut orbit = orbit;
-
-                    while let Some(parent) = s.orbit_parent[&orbit] {
-                        if let Some(parent) = e.orbits.verify(parent) {
-                            orbit = parent;
-                            dbg!(&orbit);
-                            unimplemented!()
-                        }
-                    }
-                });
+            propagate(
+                &e.orbits,
+                &s.orbit_parent,
+                &s.orbit_relative_position,
+                &mut s.orbit_position,
+                |parent, relative| *parent + *relative,
+            ).expect("orbits form a tree with a relative position each");
+
+            for (location, orbit) in s.location_orbit.verified_both(&e.locations, &e.orbits) {
+                s.location_position.insert(&location, s.orbit_position[&orbit]);
+            }
         }
     }
 }
@@ -312,15 +350,15 @@ pub mod state {
 mod entities {
     use super::*;
 
-    id_type!(SystemId);
+    id_type!(SystemId, snapshot);
 
-    id_type!(LocationId);
-    id_type!(OrbitId);
-    id_type!(TransitId);
+    id_type!(LocationId, snapshot);
+    id_type!(OrbitId, snapshot);
+    id_type!(TransitId, snapshot);
 
-    id_type!(BodyId);
-    id_type!(SurfaceId);
-    id_type!(AtmosphereId);
+    id_type!(BodyId, snapshot);
+    id_type!(SurfaceId, snapshot);
+    id_type!(AtmosphereId, snapshot);
 
     #[derive(Debug, Default)]
     pub struct Allocators {
@@ -334,6 +372,17 @@ mod entities {
         pub surfaces: Allocator<SurfaceId>,
         pub atmospheres: Allocator<AtmosphereId>,
     }
+
+    entities!(
+        Allocators,
+        systems: SystemId,
+        locations: LocationId,
+        orbits: OrbitId,
+        transits: TransitId,
+        bodies: BodyId,
+        surfaces: SurfaceId,
+        atmospheres: AtmosphereId
+    );
 }
 
 mod components {
@@ -351,6 +400,14 @@ mod components {
     #[derive(Debug, Default, Copy, Clone, PartialEq)] pub struct Greenhouse(f64);
     #[derive(Debug, Default, Copy, Clone, PartialEq)] pub struct Pressure(f64);
 
+    impl std::ops::Add for Position {
+        type Output = Position;
+
+        fn add(self, rhs: Position) -> Position {
+            Position(self.0 + rhs.0, self.1 + rhs.1)
+        }
+    }
+
     #[derive(Debug, Copy, Clone, PartialEq)]
     pub struct Ends {
         pub from: LocationId,
@@ -359,7 +416,72 @@ mod components {
 }
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--schema") {
+        schema().write_diagram(std::io::stdout()).unwrap();
+        return;
+    }
+
+    let mut galaxy = Galaxy::default();
+
+    let sol: SystemRow = ("Sol".to_string(), LightYears::default());
+    let sol = galaxy.state.create(sol, &mut galaxy.entities.systems).entity;
+
+    let earth = galaxy.construct(Planet {
+        system: sol,
+        orbit: OrbitRow {
+            radius: Radius::default(),
+            period: Period::default(),
+            angle: Angle::default(),
+            parent: None,
+        },
+        body: BodyRow {
+            radius: Radius::default(),
+            mass: Mass::default(),
+        },
+        surface: Some(SurfaceRow {
+            albedo: Albedo::default(),
+            area: Area::default(),
+        }),
+        atmosphere: None,
+    });
+
+    if std::env::args().any(|arg| arg == "--inspect") {
+        let body = galaxy.entities.bodies.verify(earth).unwrap();
+        print!("{}", registry().inspect(&galaxy.state, &galaxy.entities, &body, 2));
+    }
 
+    let orphan_moon = galaxy.try_construct(Moon {
+        system: sol,
+        orbit: OrbitRow {
+            radius: Radius::default(),
+            period: Period::default(),
+            angle: Angle::default(),
+            parent: None,
+        },
+        body: BodyRow {
+            radius: Radius::default(),
+            mass: Mass::default(),
+        },
+        surface: SurfaceRow {
+            albedo: Albedo::default(),
+            area: Area::default(),
+        },
+    });
+    assert!(orphan_moon.is_err());
+    assert_eq!(1, galaxy.entities.bodies.ids().count());
+
+    let schema = schema();
+
+    let report = schema.check(&galaxy.state, &galaxy.entities);
+    assert!(report.is_ok(), "{}", report);
+
+    galaxy.deconstruct(earth);
+
+    let stale = schema.sweep(&mut galaxy.state, &galaxy.entities);
+    assert!(stale.is_ok(), "{}", stale);
+
+    let report = schema.check(&galaxy.state, &galaxy.entities);
+    assert!(report.is_ok(), "{}", report);
 }
 
 //#[test]
@@ -0,0 +1,58 @@
+use std::io;
+use std::process;
+use relational_ecs::repl::{Layouts, Repl, SavedWorld};
+
+const USAGE: &str = "usage: relational_ecs-repl [--layout <tag>=<layout>]... <snapshot> [command...]";
+
+/// Explores a saved world. With a command it runs it and exits, otherwise it reads commands from stdin.
+///
+/// Each `--layout` decodes values tagged `tag` as if they were tagged `layout`, such as `--layout mass=(f64,f64)`.
+/// Only the snapshot is shown; mutations logged after it are not replayed.
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut layouts = Layouts::new();
+    while args.peek().map(String::as_str) == Some("--layout") {
+        args.next();
+        match args.next().as_deref().and_then(|layout| layout.split_once('=')) {
+            Some((tag, layout)) => layouts = layouts.layout(tag, layout),
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let path = match args.next() {
+        Some(path) if path != "--help" && path != "-h" => path,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let repl = match SavedWorld::open(&path, &layouts) {
+        Ok(world) => Repl::new(world),
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            process::exit(1);
+        }
+    };
+
+    let command: Vec<String> = args.collect();
+    if !command.is_empty() {
+        match repl.eval(&command.join(" ")) {
+            Ok(output) => print!("{}", output),
+            Err(error) => {
+                eprintln!("error: {}", error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    if let Err(error) = repl.run(stdin.lock(), io::stdout()) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,418 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use crate::traits::*;
+use crate::entities::{RawId, VerifiedEntity};
+use crate::storage::*;
+use crate::schema::{Field, RefColumn, Links, LinkVisitor};
+use crate::error::{Error, Result};
+
+/// What happens to owned entities when their owner is killed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OnDelete {
+    /// Kill the owned entities as well.
+    Cascade,
+    /// Clear the owned entities' reference back to the owner.
+    SetNull,
+    /// Refuse to kill the owner while it owns any living entities.
+    Restrict,
+}
+
+/// Ownership edge from `OWNER` to `OWNED`, usually declared through `link!` or `link_to_many!`.
+pub trait Owns<OWNER: IdType, OWNED: IdType> {
+    const ON_DELETE: OnDelete;
+
+    fn owned(&self, id: &VerifiedEntity<OWNER>) -> Vec<OWNED>;
+
+    /// Clears the reference from `id` back to its owner. Only called for `OnDelete::SetNull`.
+    fn release(&mut self, _id: &VerifiedEntity<OWNED>) {}
+}
+
+/// Storage that can hold no value for an entity.
+pub trait Nullable<ID: IdType> {
+    fn set_null(&mut self, id: &VerifiedEntity<ID>);
+}
+
+impl<ID: IdType, T> Nullable<ID> for EntityMap<ID, T> {
+    fn set_null(&mut self, id: &VerifiedEntity<ID>) {
+        self.remove(id);
+    }
+}
+
+impl<ID: IdType, T> Nullable<ID> for OrderedMap<ID, T> {
+    fn set_null(&mut self, id: &VerifiedEntity<ID>) {
+        self.remove(id);
+    }
+}
+
+impl<ID: IdType, T> Nullable<ID> for IndexedVec<ID, Option<T>> {
+    fn set_null(&mut self, id: &VerifiedEntity<ID>) {
+        if let Some(value) = self.get_mut(id) {
+            *value = None;
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Node {
+    table: TypeId,
+    id: RawId,
+}
+
+impl Node {
+    fn of<ID: IdType + 'static>(id: ID) -> Self {
+        Node {
+            table: TypeId::of::<ID>(),
+            id: RawId::of(id),
+        }
+    }
+
+    fn id<ID: IdType>(self) -> ID {
+        self.id.id()
+    }
+}
+
+trait Edge<S, E> {
+    fn owner(&self) -> TypeId;
+    fn on_delete(&self) -> OnDelete;
+    fn restricted(&self) -> Error;
+    fn owned(&self, state: &S, entities: &E, owner: Node) -> Vec<Node>;
+    fn release(&self, state: &mut S, owned: Node);
+    fn kill(&self, entities: &mut E, owned: Node);
+}
+
+/// An entity to be killed, with the edge that reached it. The root has no edge.
+type Doomed<'a, S, E> = (Node, Option<&'a dyn Edge<S, E>>);
+
+struct OwnsEdge<OWNER, OWNED>(PhantomData<(OWNER, OWNED)>);
+
+impl<S, E, OWNER, OWNED> Edge<S, E> for OwnsEdge<OWNER, OWNED>
+where
+    S: Owns<OWNER, OWNED>,
+    E: Entities<OWNER> + Entities<OWNED>,
+    OWNER: IdType + 'static,
+    OWNED: IdType + 'static,
+{
+    fn owner(&self) -> TypeId {
+        TypeId::of::<OWNER>()
+    }
+
+    fn on_delete(&self) -> OnDelete {
+        S::ON_DELETE
+    }
+
+    fn restricted(&self) -> Error {
+        Error::Restricted {
+            owner: type_name::<OWNER>(),
+            owned: type_name::<OWNED>(),
+        }
+    }
+
+    fn owned(&self, state: &S, entities: &E, owner: Node) -> Vec<Node> {
+        let owner = VerifiedEntity::assert_valid(owner.id::<OWNER>());
+
+        state.owned(&owner)
+            .into_iter()
+            .filter(|id| Entities::<OWNED>::is_alive(entities, *id))
+            .map(Node::of)
+            .collect()
+    }
+
+    fn release(&self, state: &mut S, owned: Node) {
+        state.release(&VerifiedEntity::assert_valid(owned.id::<OWNED>()));
+    }
+
+    fn kill(&self, entities: &mut E, owned: Node) {
+        Entities::<OWNED>::delete(entities, owned.id());
+    }
+}
+
+/// Clears the references to a killed entity held by the other side of a link.
+trait Unset<S> {
+    fn unset(&self, state: &mut S, killed: Node, survives: &dyn Fn(Node) -> bool);
+}
+
+struct LinkEdge<S, A, B, CA, CB> {
+    forward: Field<S, CA>,
+    back: Field<S, CB>,
+    marker: PhantomData<(A, B)>,
+}
+
+impl<S, A, B, CA, CB> Unset<S> for LinkEdge<S, A, B, CA, CB>
+where
+    A: IdType + 'static,
+    B: IdType + 'static,
+    CA: RefColumn<A, B>,
+    CB: RefColumn<B, A>,
+{
+    fn unset(&self, state: &mut S, killed: Node, survives: &dyn Fn(Node) -> bool) {
+        if killed.table == TypeId::of::<A>() {
+            let a = killed.id::<A>();
+            for b in (self.forward.get)(state).refs(a) {
+                if survives(Node::of(b)) {
+                    (self.back.get_mut)(state).unset(b, a);
+                }
+            }
+        } else if killed.table == TypeId::of::<B>() {
+            let b = killed.id::<B>();
+            for a in (self.back.get)(state).refs(b) {
+                if survives(Node::of(a)) {
+                    (self.forward.get_mut)(state).unset(a, b);
+                }
+            }
+        }
+    }
+}
+
+/// The ownership edges and links of a state `S` whose allocators are held by `E`.
+///
+/// Edges come from the `owns:` and `owned_by:` policies declared in `links!`, so each is declared once.
+pub struct Ownership<S, E> {
+    edges: Vec<Box<dyn Edge<S, E>>>,
+    links: Vec<Box<dyn Unset<S>>>,
+}
+
+impl<S: Links<E> + 'static, E: 'static> Default for Ownership<S, E> {
+    fn default() -> Self {
+        let mut ownership = Ownership { edges: vec![], links: vec![] };
+        S::visit_links(&mut ownership);
+        ownership
+    }
+}
+
+impl<S: 'static, E: 'static> LinkVisitor<S, E> for Ownership<S, E> {
+    fn link<A, B, CA, CB>(&mut self, forward: Field<S, CA>, back: Field<S, CB>)
+    where
+        A: IdType + 'static,
+        B: IdType + 'static,
+        CA: RefColumn<A, B> + 'static,
+        CB: RefColumn<B, A> + 'static,
+    {
+        self.links.push(Box::new(LinkEdge { forward, back, marker: PhantomData }));
+    }
+
+    fn owns<OWNER, OWNED>(&mut self)
+    where
+        S: Owns<OWNER, OWNED>,
+        E: HasAllocator<OWNER> + HasAllocator<OWNED>,
+        OWNER: IdType + 'static,
+        OWNED: IdType + 'static,
+    {
+        self.edges.push(Box::new(OwnsEdge::<OWNER, OWNED>(PhantomData)));
+    }
+}
+
+impl<S: 'static, E: 'static> Ownership<S, E> {
+    /// The ownership of every link declared with `links!`.
+    pub fn new() -> Self
+    where
+        S: Links<E>,
+    {
+        Self::default()
+    }
+
+    /// Kills `id` and everything it owns, following `Cascade` edges across allocators
+    /// and clearing references along `SetNull` edges. Surviving entities linked to a killed
+    /// entity drop their reference to it, where their column can hold no reference.
+    ///
+    /// Nothing is changed if a `Restrict` edge would be violated.
+    /// Returns the number of entities killed.
+    pub fn kill_cascade<ID>(&self, state: &mut S, entities: &mut E, id: ID) -> Result<usize>
+    where
+        ID: IdType + 'static,
+        E: Entities<ID>,
+    {
+        if !entities.is_alive(id) {
+            return Ok(0);
+        }
+
+        let root = Node::of(id);
+        let mut doomed: Vec<Doomed<S, E>> = vec![(root, None)];
+        let mut visited = HashSet::new();
+        visited.insert(root);
+
+        let mut i = 0;
+        while i < doomed.len() {
+            let owner = doomed[i].0;
+
+            for edge in self.edges_from(owner) {
+                let owned = edge.owned(state, entities, owner);
+
+                match edge.on_delete() {
+                    OnDelete::Restrict if !owned.is_empty() => return Err(edge.restricted()),
+                    OnDelete::Cascade => {
+                        for node in owned {
+                            if visited.insert(node) {
+                                doomed.push((node, Some(edge)));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            i += 1;
+        }
+
+        for (owner, _) in &doomed {
+            for edge in self.edges_from(*owner) {
+                if edge.on_delete() == OnDelete::SetNull {
+                    for node in edge.owned(state, entities, *owner) {
+                        if !visited.contains(&node) {
+                            edge.release(state, node);
+                        }
+                    }
+                }
+            }
+        }
+
+        let survives = |node| !visited.contains(&node);
+        for (node, _) in &doomed {
+            for link in &self.links {
+                link.unset(state, *node, &survives);
+            }
+        }
+
+        for (node, edge) in &doomed {
+            match edge {
+                Some(edge) => edge.kill(entities, *node),
+                None => entities.delete(id),
+            }
+        }
+
+        Ok(doomed.len())
+    }
+
+    fn edges_from(&self, owner: Node) -> impl Iterator<Item=&dyn Edge<S, E>> {
+        self.edges
+            .iter()
+            .filter(move |edge| edge.owner() == owner.table)
+            .map(|edge| edge.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Allocator;
+
+    id_type!(ParentId);
+    id_type!(ChildId);
+    id_type!(PetId);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        parents: Allocator<ParentId>,
+        children: Allocator<ChildId>,
+        pets: Allocator<PetId>,
+    }
+
+    entities!(Allocators, parents: ParentId, children: ChildId, pets: PetId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        parent_children: IndexedVec<ParentId, EntitySet<ChildId>>,
+        child_parent: IndexedVec<ChildId, ParentId>,
+        child_pet: EntityMap<ChildId, PetId>,
+        pet_child: EntityMap<PetId, ChildId>,
+    }
+
+    links! {
+        link_to_many(ParentId, parent_children, ChildId, child_parent, owns: Cascade);
+        link(ChildId, child_pet, PetId, pet_child, owns: SetNull);
+    }
+
+    fn setup() -> (State, Allocators, ParentId, PetId) {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+
+        let parent = entities.parents.create_entity();
+        state.parent_children.insert(&parent, EntitySet::new());
+
+        let child = entities.children.create_entity();
+        state.link(&parent, &child);
+
+        let pet = entities.pets.create_entity();
+        state.link(&child, &pet);
+
+        let (parent, pet) = (parent.entity, pet.entity);
+        (state, entities, parent, pet)
+    }
+
+    fn ownership() -> Ownership<State, Allocators> {
+        Ownership::new()
+    }
+
+    #[test]
+    fn cascade_kills_owned_entities() {
+        let (mut state, mut entities, parent, pet) = setup();
+
+        let killed = ownership().kill_cascade(&mut state, &mut entities, parent);
+
+        assert_eq!(2, killed.unwrap());
+        assert!(entities.children.ids().next().is_none());
+        assert!(entities.pets.is_alive(pet));
+    }
+
+    #[test]
+    fn set_null_clears_back_reference() {
+        let (mut state, mut entities, parent, pet) = setup();
+
+        ownership().kill_cascade(&mut state, &mut entities, parent).unwrap();
+
+        let pet = entities.pets.verify(pet).unwrap();
+        assert_eq!(None, state.pet_child.get(&pet));
+    }
+
+    #[test]
+    fn surviving_owner_drops_killed_entity() {
+        let (mut state, mut entities, parent, pet) = setup();
+        let child = entities.children.ids().next().unwrap().entity;
+
+        ownership().kill_cascade(&mut state, &mut entities, child).unwrap();
+
+        let parent = entities.parents.verify(parent).unwrap();
+        let pet = entities.pets.verify(pet).unwrap();
+        assert!(state.parent_children[&parent].is_empty());
+        assert_eq!(None, state.pet_child.get(&pet));
+    }
+
+    #[test]
+    fn dead_id_kills_nothing() {
+        let (mut state, mut entities, parent, _) = setup();
+        entities.parents.kill(parent);
+
+        assert_eq!(0, ownership().kill_cascade(&mut state, &mut entities, parent).unwrap());
+    }
+
+    mod restrict {
+        use super::*;
+
+        #[derive(Debug, Default)]
+        struct State {
+            parent_children: IndexedVec<ParentId, EntitySet<ChildId>>,
+            child_parent: IndexedVec<ChildId, ParentId>,
+        }
+
+        links! {
+            link_to_many(ParentId, parent_children, ChildId, child_parent, owns: Restrict);
+        }
+
+        #[test]
+        fn restrict_refuses_to_kill() {
+            let mut state = State::default();
+            let mut entities = Allocators::default();
+
+            let parent = entities.parents.create_entity();
+            state.parent_children.insert(&parent, EntitySet::new());
+            let child = entities.children.create_entity();
+            state.link(&parent, &child);
+            let parent = parent.entity;
+
+            let ownership = Ownership::<State, Allocators>::new();
+
+            assert!(ownership.kill_cascade(&mut state, &mut entities, parent).is_err());
+            assert!(entities.parents.is_alive(parent));
+        }
+    }
+}
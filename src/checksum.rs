@@ -0,0 +1,409 @@
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use crate::traits::IdType;
+use crate::entities::RawId;
+use crate::reflect::{Registry, AnyTable, AnyColumn};
+use crate::snapshot::{Tag, Encode, Decode};
+use crate::diff::encoded;
+use crate::error::{Error, Result};
+
+/// 64-bit FNV-1a, which unlike the standard library's hashers is the same on every platform and run.
+pub(crate) fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+fn hash_of<T: Encode>(value: &T) -> u64 {
+    fnv(&encoded(value))
+}
+
+/// Row hashes by index, and their wrapping sum, which does not depend on the order rows are visited in.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Hashes {
+    rows: BTreeMap<usize, (RawId, u64)>,
+    sum: u64,
+}
+
+impl Hashes {
+    fn set(&mut self, index: usize, row: Option<(RawId, u64)>) {
+        if let Some((_, old)) = self.rows.remove(&index) {
+            self.sum = self.sum.wrapping_sub(old);
+        }
+        if let Some((id, hash)) = row {
+            self.rows.insert(index, (id, hash));
+            self.sum = self.sum.wrapping_add(hash);
+        }
+    }
+}
+
+/// The hash of every registered table, in registration order: allocators first, then columns.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Checksum {
+    pub tables: Vec<(String, u64)>,
+}
+
+impl Checksum {
+    /// The single value peers compare every tick.
+    pub fn value(&self) -> u64 {
+        hash_of(self)
+    }
+}
+
+impl Tag for Checksum {
+    fn tag() -> String {
+        "checksum".to_string()
+    }
+}
+
+impl Encode for Checksum {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.tables.encode(out);
+    }
+}
+
+impl Decode for Checksum {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        Ok(Checksum { tables: Vec::decode(input)? })
+    }
+}
+
+/// Where two worlds first differ. `id` is `None` when only an allocator's dead list differs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Divergence {
+    pub table: &'static str,
+    pub id: Option<RawId>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "{} diverged at {}", self.table, id),
+            None => write!(f, "{} diverged in its dead list", self.table),
+        }
+    }
+}
+
+/// A checksum of the tables and encoded columns of a registry, for lockstep peers to detect desyncs.
+///
+/// Each row is hashed with its id from its encoded bytes, so the hash is the same on every
+/// platform and independent of the storage's iteration order. Rows are hashed by the index
+/// of the allocator of their id type, using the generation that index currently has.
+///
+/// `update` only rehashes the entities passed to `touch` since the last update, so every entity
+/// created, killed or changed must be touched. The first update hashes everything. `verify`
+/// compares against a full rehash, which catches a change that was not touched.
+pub struct WorldHash<S, E> {
+    registry: Registry<S, E>,
+    allocator_hashes: Vec<(Hashes, u64)>,
+    column_hashes: Vec<Hashes>,
+    touched: HashMap<TypeId, BTreeSet<usize>>,
+    stale: bool,
+}
+
+impl<S: 'static, E: 'static> WorldHash<S, E> {
+    /// Fails with `Error::Unregistered` if an encoded column's table is not in `registry`,
+    /// since its rows could not be hashed.
+    pub fn new(registry: Registry<S, E>) -> Result<Self> {
+        let hash = WorldHash {
+            registry,
+            allocator_hashes: vec![],
+            column_hashes: vec![],
+            touched: HashMap::new(),
+            stale: true,
+        };
+
+        if let Some(column) = hash.columns().find(|column| hash.registry.table_of(column.id_type()).is_none()) {
+            return Err(Error::Unregistered { column: column.info().name });
+        }
+        Ok(hash)
+    }
+
+    fn allocators(&self) -> impl Iterator<Item = &dyn AnyTable<E>> + '_ {
+        self.registry.all_tables()
+    }
+
+    fn columns(&self) -> impl Iterator<Item = &dyn AnyColumn<S>> + '_ {
+        self.registry.all_columns().filter(|column| column.info().encoded)
+    }
+
+    /// Marks `id` to be rehashed by the next `update`.
+    pub fn touch<ID: IdType + 'static>(&mut self, id: ID) {
+        self.touched.entry(TypeId::of::<ID>()).or_default().insert(id.index());
+    }
+
+    /// Rehashes the touched entities, or everything on the first call.
+    ///
+    /// Entities changed without being touched keep their old hashes; `verify` finds them.
+    pub fn update(&mut self, state: &S, entities: &E) {
+        if self.stale {
+            return self.rebuild(state, entities);
+        }
+
+        for (a, allocator) in self.registry.all_tables().enumerate() {
+            let indices = match self.touched.get(&allocator.id_type()) {
+                Some(indices) => indices,
+                None => continue,
+            };
+
+            let (hashes, dead) = &mut self.allocator_hashes[a];
+            for &index in indices {
+                hashes.set(index, Self::allocator_row(allocator, entities, index));
+            }
+            *dead = hash_of(&allocator.dead(entities));
+
+            let columns = self.registry.all_columns().filter(|column| column.info().encoded);
+            for (c, column) in columns.enumerate().filter(|(_, c)| c.id_type() == allocator.id_type()) {
+                for &index in indices {
+                    let row = Self::column_row(allocator, column, state, entities, index);
+                    self.column_hashes[c].set(index, row);
+                }
+            }
+        }
+
+        self.touched.clear();
+    }
+
+    /// Hashes every row from scratch.
+    pub fn rebuild(&mut self, state: &S, entities: &E) {
+        let (allocator_hashes, column_hashes) = self.hash_all(state, entities);
+        self.allocator_hashes = allocator_hashes;
+        self.column_hashes = column_hashes;
+
+        self.touched.clear();
+        self.stale = false;
+    }
+
+    /// Whether the hashes match a full rehash of the world, which fails if an entity was
+    /// changed without being touched since the last `update`.
+    pub fn verify(&self, state: &S, entities: &E) -> bool {
+        let (allocator_hashes, column_hashes) = self.hash_all(state, entities);
+        allocator_hashes == self.allocator_hashes && column_hashes == self.column_hashes
+    }
+
+    fn hash_all(&self, state: &S, entities: &E) -> (Vec<(Hashes, u64)>, Vec<Hashes>) {
+        let allocator_hashes = self.allocators()
+            .map(|allocator| {
+                let mut hashes = Hashes::default();
+                for index in 0..allocator.len(entities) {
+                    hashes.set(index, Self::allocator_row(allocator, entities, index));
+                }
+                (hashes, hash_of(&allocator.dead(entities)))
+            })
+            .collect();
+
+        let column_hashes = self.columns()
+            .map(|column| {
+                let mut hashes = Hashes::default();
+                if let Some(allocator) = self.registry.table_of(column.id_type()) {
+                    for index in 0..allocator.len(entities) {
+                        let row = Self::column_row(allocator, column, state, entities, index);
+                        hashes.set(index, row);
+                    }
+                }
+                hashes
+            })
+            .collect();
+
+        (allocator_hashes, column_hashes)
+    }
+
+    fn allocator_row(allocator: &dyn AnyTable<E>, entities: &E, index: usize) -> Option<(RawId, u64)> {
+        if index >= allocator.len(entities) {
+            return None;
+        }
+
+        let id = allocator.id_at(entities, index);
+        Some((id, hash_of(&(id, allocator.alive_at(entities, index)))))
+    }
+
+    fn column_row(
+        allocator: &dyn AnyTable<E>,
+        column: &dyn AnyColumn<S>,
+        state: &S,
+        entities: &E,
+        index: usize,
+    ) -> Option<(RawId, u64)> {
+        if index >= allocator.len(entities) {
+            return None;
+        }
+
+        let id = allocator.id_at(entities, index);
+        column.encode(state, id).map(|row| (id, hash_of(&(id, row))))
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        let allocators = self.allocators()
+            .zip(&self.allocator_hashes)
+            .map(|(allocator, (hashes, dead))| (allocator.info().name.to_string(), hashes.sum.wrapping_add(*dead)));
+        let columns = self.columns()
+            .zip(&self.column_hashes)
+            .map(|(column, hashes)| (column.info().name.to_string(), hashes.sum));
+
+        Checksum { tables: allocators.chain(columns).collect() }
+    }
+
+    /// The row hashes of `table` in index order, for a peer to compare against its own.
+    pub fn rows(&self, table: &str) -> Option<Vec<(RawId, u64)>> {
+        self.hashes(table).map(|hashes| hashes.rows.values().copied().collect())
+    }
+
+    fn hashes(&self, table: &str) -> Option<&Hashes> {
+        let allocators = self.allocators()
+            .zip(&self.allocator_hashes)
+            .map(|(allocator, (hashes, _))| (allocator.info().name, hashes));
+        let columns = self.columns()
+            .zip(&self.column_hashes)
+            .map(|(column, hashes)| (column.info().name, hashes));
+
+        allocators.chain(columns)
+            .find(|(name, _)| *name == table)
+            .map(|(_, hashes)| hashes)
+    }
+
+    /// The first table, in registration order, whose hash differs from `theirs`.
+    pub fn diverged_table(&self, theirs: &Checksum) -> Option<&'static str> {
+        let names = self.allocators().map(|a| a.info().name).chain(self.columns().map(|c| c.info().name));
+
+        names.zip(self.checksum().tables)
+            .find(|(name, (_, hash))| {
+                theirs.tables.iter().find(|(their_name, _)| their_name == name).map(|(_, h)| h) != Some(hash)
+            })
+            .map(|(name, _)| name)
+    }
+
+    /// The lowest index whose row in `table` differs from `theirs`, a peer's `rows` for the same table.
+    pub fn diverged_row(&self, table: &str, theirs: &[(RawId, u64)]) -> Option<RawId> {
+        let ours = self.hashes(table)?;
+        let theirs: BTreeMap<usize, (RawId, u64)> = theirs.iter().map(|row| (row.0.index, *row)).collect();
+
+        let indices: BTreeSet<usize> = ours.rows.keys().chain(theirs.keys()).copied().collect();
+        indices.into_iter()
+            .find(|index| ours.rows.get(index) != theirs.get(index))
+            .map(|index| ours.rows.get(&index).or_else(|| theirs.get(&index)).unwrap().0)
+    }
+
+    /// Where this world first differs from `other`, which must register the same tables.
+    pub fn compare(&self, other: &Self) -> Option<Divergence> {
+        let table = self.diverged_table(&other.checksum())?;
+        let id = self.diverged_row(table, &other.rows(table)?);
+        Some(Divergence { table, id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+
+    id_type!(UnitId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        units: Allocator<UnitId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        unit_health: IndexedVec<UnitId, u32>,
+        unit_target: EntityMap<UnitId, UnitId>,
+    }
+
+    fn world_hash() -> WorldHash<State, Allocators> {
+        WorldHash::new(registry!(State, Allocators;
+            tables: units;
+            encoded: unit_health;
+            references: unit_target)).unwrap()
+    }
+
+    fn world() -> (State, Allocators) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        for health in &[10, 20, 30] {
+            let unit = entities.units.create_entity();
+            state.unit_health.insert(&unit, *health);
+        }
+        let ids: Vec<_> = entities.units.ids().map(|id| id.entity).collect();
+        state.unit_target.values.insert(ids[0], ids[2]);
+        state.unit_target.values.insert(ids[2], ids[1]);
+        (state, entities)
+    }
+
+    fn hashed(state: &State, entities: &Allocators) -> WorldHash<State, Allocators> {
+        let mut hash = world_hash();
+        hash.update(state, entities);
+        hash
+    }
+
+    #[test]
+    fn equal_worlds_hash_equally() {
+        let (state, entities) = world();
+        let (mut reordered, _) = world();
+        let mut targets: Vec<_> = reordered.unit_target.values.drain().collect();
+        targets.sort_unstable_by_key(|(unit, _)| std::cmp::Reverse(*unit));
+        reordered.unit_target.values.extend(targets);
+
+        let a = hashed(&state, &entities).checksum();
+        let b = hashed(&reordered, &entities).checksum();
+        assert_eq!(a, b);
+        assert_eq!(a.value(), b.value());
+    }
+
+    #[test]
+    fn update_matches_rebuild() {
+        let (mut state, mut entities) = world();
+        let mut hash = hashed(&state, &entities);
+        let ids: Vec<_> = entities.units.ids().map(|id| id.entity).collect();
+
+        entities.units.kill(ids[1]);
+        state.unit_target.values.remove(&ids[2]);
+        let unit = entities.units.create_entity().entity;
+        state.unit_health.values[unit.index()] = 40;
+        for id in &[ids[1], ids[2], unit] {
+            hash.touch(*id);
+        }
+        hash.update(&state, &entities);
+        assert!(hash.verify(&state, &entities));
+
+        let rebuilt = hashed(&state, &entities);
+        assert_eq!(rebuilt.checksum(), hash.checksum());
+        assert_eq!(None, hash.compare(&rebuilt));
+        assert_ne!(hashed(&world().0, &world().1).checksum(), hash.checksum());
+    }
+
+    #[test]
+    fn verify_finds_untouched_changes() {
+        let (mut state, entities) = world();
+        let mut hash = hashed(&state, &entities);
+        assert!(hash.verify(&state, &entities));
+
+        state.unit_health.values[0] = 5;
+        hash.update(&state, &entities);
+        assert!(!hash.verify(&state, &entities));
+    }
+
+    #[test]
+    fn column_without_a_table_is_an_error() {
+        let result = WorldHash::new(registry!(State, Allocators; tables: ; encoded: unit_health));
+
+        assert!(matches!(result, Err(Error::Unregistered { column: "unit_health" })));
+    }
+
+    #[test]
+    fn reports_diverged_column_and_entity() {
+        let (state, entities) = world();
+        let (mut theirs, their_entities) = world();
+        let ids: Vec<_> = entities.units.ids().map(|id| id.entity).collect();
+        theirs.unit_target.values.insert(ids[1], ids[0]);
+
+        let divergence = hashed(&state, &entities).compare(&hashed(&theirs, &their_entities)).unwrap();
+        assert_eq!(Divergence { table: "unit_target", id: Some(RawId::of(ids[1])) }, divergence);
+
+        let mut their_entities = their_entities;
+        their_entities.units.kill(ids[0]);
+        let divergence = hashed(&state, &entities).compare(&hashed(&state, &their_entities)).unwrap();
+        assert_eq!(Divergence { table: "units", id: Some(RawId::of(ids[0])) }, divergence);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::traits::*;
+use crate::transaction::{Transaction, Restore, LinkRows, InsertRows};
+use crate::schema::Field;
+use crate::error::Error;
+
+/// Why a `TryConstruct` failed.
+pub type ConstructError = Error;
+
+/// Performs the steps of a construction and undoes all of them unless `commit` is called.
+///
+/// Changes are logged in a `Transaction`. Rolling back puts back every row a create, insert or link wrote,
+/// in reverse order, and returns created ids to their allocators.
+pub struct Builder<'a, S, E> {
+    tx: Transaction<'a, S, E>,
+}
+
+impl<'a, S: 'static, E: 'static> Builder<'a, S, E> {
+    pub fn new(state: &'a mut S, entities: &'a mut E) -> Self {
+        Builder { tx: Transaction::new(state, entities) }
+    }
+
+    pub fn state(&self) -> &S {
+        self.tx.state()
+    }
+
+    pub fn entities(&self) -> &E {
+        self.tx.entities()
+    }
+
+    /// Returns `id` if it is alive.
+    pub fn verify<ID: IdType>(&self, id: ID) -> Result<ID, ConstructError>
+    where
+        E: Entities<ID>,
+    {
+        if self.entities().is_alive(id) {
+            Ok(id)
+        } else {
+            Err(dead(id))
+        }
+    }
+
+    /// Creates an entity and inserts `row` for it.
+    pub fn create<ID: IdType + 'static, T>(&mut self, row: T) -> ID
+    where
+        S: Insert<ID, T> + InsertRows<ID, T>,
+        E: HasAllocator<ID>,
+    {
+        let id = self.tx.create_entity();
+        self.tx.insert_row(id, row).expect("created entities are alive");
+        id
+    }
+
+    /// Sets the row for an existing entity in `column`. Rollback restores the previous row,
+    /// or removes the row if there was none.
+    pub fn insert<ID, T, C>(&mut self, column: Field<S, C>, id: ID, value: T) -> Result<(), ConstructError>
+    where
+        ID: IdType + 'static,
+        T: Clone + 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        self.tx.insert(column, id, value).map(drop)
+    }
+
+    /// Links two living entities. Rollback restores both rows the link wrote,
+    /// including a link it replaced.
+    pub fn link<A: IdType + 'static, B: IdType + 'static>(&mut self, a: A, b: B) -> Result<(), ConstructError>
+    where
+        S: Link<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        self.tx.link(a, b)
+    }
+
+    /// Creates an entity from `row` and links it to `owner`.
+    pub fn create_and_link<A: IdType + 'static, B: IdType + 'static, T>(&mut self, owner: A, row: T) -> Result<B, ConstructError>
+    where
+        S: Insert<B, T> + InsertRows<B, T> + Link<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        self.verify(owner)?;
+        let id = self.create(row);
+        self.link(owner, id)?;
+        Ok(id)
+    }
+
+    /// Keeps every change made through the builder.
+    pub fn commit(self) {
+        self.tx.commit();
+    }
+
+    /// Undoes every change made through the builder, newest first.
+    pub fn rollback(self) {
+        self.tx.rollback();
+    }
+}
+
+fn dead<ID: IdType>(id: ID) -> ConstructError {
+    Error::dead_entity(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{Allocator, RawId, VerifiedEntity};
+    use crate::storage::*;
+
+    id_type!(FleetId);
+    id_type!(ShipId);
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct Allocators {
+        fleets: Allocator<FleetId>,
+        ships: Allocator<ShipId>,
+    }
+
+    entities!(Allocators, fleets: FleetId, ships: ShipId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        fleet_ships: IndexedVec<FleetId, EntitySet<ShipId>>,
+        ship_fleet: IndexedVec<ShipId, FleetId>,
+        ship_name: IndexedVec<ShipId, String>,
+        ship_captain: EntityMap<ShipId, Captain>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Captain(&'static str);
+
+    link_to_many!(FleetId, fleet_ships, ShipId, ship_fleet);
+
+    impl Insert<FleetId, ()> for State {
+        fn insert(&mut self, id: &VerifiedEntity<FleetId>, _: ()) {
+            self.fleet_ships.insert(id, EntitySet::new());
+        }
+    }
+
+    impl Insert<ShipId, String> for State {
+        fn insert(&mut self, id: &VerifiedEntity<ShipId>, value: String) {
+            self.ship_name.insert(id, value);
+        }
+    }
+
+    insert_rows!(State, FleetId, (): fleet_ships);
+    insert_rows!(State, ShipId, String: ship_name);
+
+    struct Fleet(Vec<&'static str>);
+
+    struct World {
+        state: State,
+        entities: Allocators,
+    }
+
+    impl TryConstruct<FleetId, Fleet> for World {
+        fn try_construct(&mut self, fleet: Fleet) -> Result<FleetId, ConstructError> {
+            let mut builder = Builder::new(&mut self.state, &mut self.entities);
+
+            let id = builder.create(());
+            for name in fleet.0 {
+                if name.is_empty() {
+                    return Err(ConstructError::Invalid("ships must be named"));
+                }
+                let _ship: ShipId = builder.create_and_link(id, name.to_string())?;
+            }
+
+            builder.commit();
+            Ok(id)
+        }
+    }
+
+    fn world() -> World {
+        World { state: State::default(), entities: Allocators::default() }
+    }
+
+    type Columns = (Vec<EntitySet<ShipId>>, Vec<FleetId>, Vec<String>, EntityMap<ShipId, Captain>);
+
+    fn columns(state: &State) -> Columns {
+        (state.fleet_ships.values.clone(), state.ship_fleet.values.clone(), state.ship_name.values.clone(), state.ship_captain.clone())
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut world = world();
+
+        let fleet = world.try_construct(Fleet(vec!["Cutty Sark", "Victory"])).unwrap();
+
+        let fleet = world.entities.fleets.verify(fleet).unwrap();
+        assert_eq!(2, world.state.fleet_ships[&fleet].len());
+        assert_eq!(2, world.entities.ships.ids().count());
+    }
+
+    #[test]
+    fn failure_kills_created_entities() {
+        let mut world = world();
+
+        let result = world.try_construct(Fleet(vec!["Cutty Sark", ""]));
+
+        assert!(matches!(result, Err(ConstructError::Invalid("ships must be named"))));
+        assert_eq!(0, world.entities.fleets.ids().count());
+        assert_eq!(0, world.entities.ships.ids().count());
+        assert_eq!(columns(&State::default()), columns(&world.state));
+        assert_eq!(Allocators::default(), world.entities);
+    }
+
+    #[test]
+    fn rollback_restores_values_and_links() {
+        let mut world = world();
+        let fleet = world.try_construct(Fleet(vec!["Cutty Sark"])).unwrap();
+        let ship = world.entities.ships.ids().next().unwrap().entity;
+        world.state.ship_captain.values.insert(ship, Captain("Moodie"));
+
+        let (before, entities) = (columns(&world.state), world.entities.clone());
+
+        let mut builder = Builder::new(&mut world.state, &mut world.entities);
+        builder.insert(field!(State, ship_captain), ship, Captain("Willis")).unwrap();
+        let other: ShipId = builder.create_and_link(fleet, "Endeavour".to_string()).unwrap();
+        builder.insert(field!(State, ship_captain), other, Captain("Cook")).unwrap();
+        builder.rollback();
+
+        assert_eq!(before, columns(&world.state));
+        assert_eq!(entities, world.entities);
+
+        let fleet = world.entities.fleets.verify(fleet).unwrap();
+        assert_eq!(Some(&Captain("Moodie")), world.state.ship_captain.values.get(&ship));
+        assert_eq!(1, world.state.ship_captain.len());
+        assert!(!world.state.fleet_ships[&fleet].contains(&other));
+        assert!(!world.entities.ships.is_alive(other));
+    }
+
+    #[test]
+    fn rollback_restores_replaced_link() {
+        let mut world = world();
+        let home = world.try_construct(Fleet(vec!["Cutty Sark"])).unwrap();
+        let away = world.try_construct(Fleet(vec![])).unwrap();
+        let ship = world.entities.ships.ids().next().unwrap().entity;
+
+        let mut builder = Builder::new(&mut world.state, &mut world.entities);
+        builder.link(away, ship).unwrap();
+        assert_eq!(vec![away], builder.state().ship_fleet.values);
+        builder.rollback();
+
+        assert_eq!(vec![home], world.state.ship_fleet.values);
+        assert!(world.state.fleet_ships.values[away.index()].is_empty());
+    }
+
+    #[test]
+    fn dead_owner_is_an_error() {
+        let mut world = world();
+        let fleet = world.try_construct(Fleet(vec![])).unwrap();
+        world.entities.fleets.kill(fleet);
+
+        let mut builder = Builder::new(&mut world.state, &mut world.entities);
+        let result: Result<ShipId, _> = builder.create_and_link(fleet, "Endeavour".to_string());
+
+        assert!(matches!(result, Err(Error::DeadEntity { id, .. }) if id == RawId::of(fleet)));
+    }
+}
@@ -0,0 +1,449 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use crate::traits::*;
+use crate::entities::{Allocator, VerifiedEntity};
+use crate::reflect::Registry;
+use crate::error::{Error, Result};
+
+/// Why a CSV table could not be read or imported, nested in `Error::Csv`. Lines count from 1, the header being line 1.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CsvError {
+    /// A quoted field is not closed, or text follows its closing quote.
+    Malformed { line: usize },
+    MissingColumn(String),
+    /// A field could not be parsed as the type asked for.
+    Invalid { line: usize, column: String, value: String },
+    /// A foreign key names no entity.
+    UnknownKey { line: usize, column: String, key: String },
+    /// Two entities share a natural key.
+    DuplicateKey(String),
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsvError::Malformed { line } => write!(f, "line {}: malformed csv", line),
+            CsvError::MissingColumn(column) => write!(f, "missing csv column: {}", column),
+            CsvError::Invalid { line, column, value } => write!(f, "line {}: {}: invalid value {:?}", line, column, value),
+            CsvError::UnknownKey { line, column, key } => write!(f, "line {}: {}: unknown key {:?}", line, column, key),
+            CsvError::DuplicateKey(key) => write!(f, "duplicate key {:?}", key),
+        }
+    }
+}
+
+impl std::error::Error for CsvError {}
+
+fn write_record<W: Write>(output: &mut W, fields: &[String]) -> io::Result<()> {
+    // a lone empty field is quoted so that it is not read back as a blank line
+    let lone = fields.len() == 1;
+    let fields: Vec<_> = fields.iter()
+        .map(|field| {
+            if field.contains(&[',', '"', '\n', '\r'][..]) || (lone && field.is_empty()) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.clone()
+            }
+        })
+        .collect();
+
+    writeln!(output, "{}", fields.join(","))
+}
+
+/// Splits RFC 4180 text into records, each with the line it starts on. Blank lines are skipped,
+/// but a line holding only `""` is a record with one empty field.
+fn read_records(text: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = vec![];
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut blank = true;
+
+        loop {
+            match chars.next() {
+                Some('"') if quoted => {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        field.push('"');
+                    } else {
+                        quoted = false;
+                        if !matches!(chars.peek(), None | Some(',') | Some('\n') | Some('\r')) {
+                            return Err(CsvError::Malformed { line }.into());
+                        }
+                    }
+                }
+                Some('"') if field.is_empty() => {
+                    quoted = true;
+                    blank = false;
+                }
+                Some(c) if quoted => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some(c) => field.push(c),
+                None if quoted => return Err(CsvError::Malformed { line }.into()),
+                None => break,
+            }
+        }
+
+        fields.push(field);
+        if !blank || fields != [""] {
+            records.push((start, fields));
+        }
+    }
+
+    Ok(records)
+}
+
+type Cell<S, ID> = Box<dyn Fn(&S, &VerifiedEntity<ID>) -> Option<String>>;
+
+/// The columns of a registry keyed by `ID` that were registered with `text`, exported as one
+/// table with a leading `id` column and any computed cells after them.
+pub struct CsvTable<'r, S, E, ID: IdType> {
+    registry: &'r Registry<S, E>,
+    cells: Vec<(&'static str, Cell<S, ID>)>,
+}
+
+impl<'r, S: 'static, E: 'static, ID: IdType + 'static> CsvTable<'r, S, E, ID> {
+    pub fn new(registry: &'r Registry<S, E>) -> Self {
+        CsvTable { registry, cells: vec![] }
+    }
+
+    /// Adds a computed column, such as the natural key of a referenced entity.
+    pub fn cell<F>(mut self, name: &'static str, cell: F) -> Self
+    where
+        F: Fn(&S, &VerifiedEntity<ID>) -> Option<String> + 'static,
+    {
+        self.cells.push((name, Box::new(cell)));
+        self
+    }
+
+    /// Writes a header and one row per living entity, in index order. Entities without a value get an empty cell.
+    ///
+    /// Returns `Error::Unregistered`, naming the id type, if no table is registered for `ID`.
+    pub fn export<W: Write>(&self, mut output: W, state: &S, entities: &E) -> Result<()> {
+        let table = self.registry.table_of(TypeId::of::<ID>())
+            .ok_or(Error::Unregistered { column: type_name::<ID>() })?;
+        let columns: Vec<_> = self.registry.texts_keyed(TypeId::of::<ID>()).collect();
+
+        let header: Vec<_> = std::iter::once("id")
+            .chain(columns.iter().map(|(name, _)| *name))
+            .chain(self.cells.iter().map(|(name, _)| *name))
+            .map(str::to_string)
+            .collect();
+        write_record(&mut output, &header)?;
+
+        for raw in table.living(entities) {
+            let id = VerifiedEntity::assert_valid(raw.id::<ID>());
+            let row: Vec<_> = std::iter::once(raw.to_string())
+                .chain(columns.iter().map(|(_, text)| text(state, raw).unwrap_or_default()))
+                .chain(self.cells.iter().map(|(_, cell)| cell(state, &id).unwrap_or_default()))
+                .collect();
+            write_record(&mut output, &row)?;
+        }
+
+        output.flush()?;
+        Ok(())
+    }
+}
+
+/// Natural keys, such as names, of the entities of one id type.
+#[derive(Debug, Clone)]
+pub struct Keys<ID> {
+    ids: HashMap<String, ID>,
+}
+
+impl<ID: IdType> Default for Keys<ID> {
+    fn default() -> Self {
+        Keys { ids: HashMap::new() }
+    }
+}
+
+impl<ID: IdType> Keys<ID> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keys every living entity by its value in `column`, failing if two entities share a value.
+    pub fn of<T: Display, C: Get<ID, T>>(allocator: &Allocator<ID>, column: &C) -> Result<Self> {
+        let mut keys = Keys::new();
+        for id in allocator.ids() {
+            if let Some(key) = column.get(&id) {
+                keys.insert(key.to_string(), id.entity)?;
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Adds `key` for `id`, failing if another entity already has it.
+    pub fn insert(&mut self, key: String, id: ID) -> Result<()> {
+        if self.ids.contains_key(&key) {
+            return Err(CsvError::DuplicateKey(key).into());
+        }
+        self.ids.insert(key, id);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<ID> {
+        self.ids.get(key).copied()
+    }
+}
+
+/// One row being imported, with its fields looked up by header.
+pub struct Record<'a> {
+    headers: &'a [String],
+    fields: &'a [String],
+    line: usize,
+}
+
+impl<'a> Record<'a> {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The raw text of the field under `column`. Missing trailing fields are empty.
+    pub fn get(&self, column: &str) -> Result<&'a str> {
+        let index = self.headers.iter()
+            .position(|header| header == column)
+            .ok_or_else(|| CsvError::MissingColumn(column.to_string()))?;
+
+        Ok(self.fields.get(index).map(String::as_str).unwrap_or(""))
+    }
+
+    pub fn parse<T: FromStr>(&self, column: &str) -> Result<T> {
+        let value = self.get(column)?;
+        value.parse().map_err(|_| CsvError::Invalid {
+            line: self.line,
+            column: column.to_string(),
+            value: value.to_string(),
+        }.into())
+    }
+
+    /// Resolves the natural key under `column` to an id.
+    pub fn lookup<ID: IdType>(&self, column: &str, keys: &Keys<ID>) -> Result<ID> {
+        let key = self.get(column)?;
+        keys.get(key).ok_or_else(|| CsvError::UnknownKey {
+            line: self.line,
+            column: column.to_string(),
+            key: key.to_string(),
+        }.into())
+    }
+}
+
+/// Creates an entity through `Create` for every row after the header, turning rows into `T` with `row`.
+///
+/// Any `id` column is ignored; rows get new ids, returned in row order. Every row is read
+/// before any entity is created, so a failing row leaves the state and allocator unchanged.
+pub fn import<R, ID, T, S, F>(mut input: R, state: &mut S, allocator: &mut Allocator<ID>, mut row: F) -> Result<Vec<ID>>
+where
+    R: Read,
+    ID: IdType,
+    S: for<'a> Create<'a, ID, T>,
+    F: FnMut(&Record) -> Result<T>,
+{
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+
+    let mut records = read_records(&text)?.into_iter();
+    let headers = match records.next() {
+        Some((_, headers)) => headers,
+        None => return Ok(vec![]),
+    };
+
+    let values = records
+        .map(|(line, fields)| row(&Record { headers: &headers, fields: &fields, line }))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(values.into_iter()
+        .map(|value| state.create(value, allocator).entity)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::*;
+
+    id_type!(SystemId, snapshot);
+    id_type!(PlanetId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        systems: Allocator<SystemId>,
+        planets: Allocator<PlanetId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        system_name: IndexedVec<SystemId, String>,
+        system_x: IndexedVec<SystemId, f32>,
+        planet_name: IndexedVec<PlanetId, String>,
+        planet_system: IndexedVec<PlanetId, SystemId>,
+    }
+
+    struct SystemRow {
+        name: String,
+        x: f32,
+    }
+
+    impl Insert<SystemId, SystemRow> for State {
+        fn insert(&mut self, id: &VerifiedEntity<SystemId>, row: SystemRow) {
+            self.system_name.insert(id, row.name);
+            self.system_x.insert(id, row.x);
+        }
+    }
+
+    impl Create<'_, SystemId, SystemRow> for State {}
+
+    struct PlanetRow {
+        name: String,
+        system: SystemId,
+    }
+
+    impl Insert<PlanetId, PlanetRow> for State {
+        fn insert(&mut self, id: &VerifiedEntity<PlanetId>, row: PlanetRow) {
+            self.planet_name.insert(id, row.name);
+            self.planet_system.insert(id, row.system);
+        }
+    }
+
+    impl Create<'_, PlanetId, PlanetRow> for State {}
+
+    fn registry() -> Registry<State, Allocators> {
+        registry!(State, Allocators;
+            tables: systems, planets;
+            text: system_name, system_x, planet_name)
+    }
+
+    fn systems(state: &mut State, systems: &mut Allocator<SystemId>) {
+        let catalogue = "id,system_name,system_x\n,Sol,0\n,\"Alpha, Centauri\",4.37\n";
+        import(catalogue.as_bytes(), state, systems, |record| {
+            Ok(SystemRow { name: record.get("system_name")?.to_string(), x: record.parse("system_x")? })
+        }).unwrap();
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        systems(&mut state, &mut entities.systems);
+
+        let registry = registry();
+        let mut exported = vec![];
+        CsvTable::<_, _, SystemId>::new(&registry).export(&mut exported, &state, &entities).unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!("id,system_name,system_x\n0v1,Sol,0\n1v1,\"Alpha, Centauri\",4.37\n", exported);
+
+        let (mut copy, mut copy_allocator) = (State::default(), Allocator::new());
+        import(exported.as_bytes(), &mut copy, &mut copy_allocator, |record| {
+            Ok(SystemRow { name: record.get("system_name")?.to_string(), x: record.parse("system_x")? })
+        }).unwrap();
+        assert_eq!(state.system_name.values, copy.system_name.values);
+        assert_eq!(state.system_x.values, copy.system_x.values);
+    }
+
+    #[test]
+    fn import_resolves_natural_keys() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        systems(&mut state, &mut entities.systems);
+        let keys = Keys::of(&entities.systems, &state.system_name).unwrap();
+
+        let planets = "name,system\nEarth,Sol\r\nProxima b,\"Alpha, Centauri\"\r\n";
+        let ids = import(planets.as_bytes(), &mut state, &mut entities.planets, |record| {
+            Ok(PlanetRow { name: record.get("name")?.to_string(), system: record.lookup("system", &keys)? })
+        }).unwrap();
+
+        assert_eq!(2, ids.len());
+        assert_eq!(keys.get("Alpha, Centauri"), Some(state.planet_system.values[1]));
+
+        let registry = registry();
+        let table = CsvTable::<_, _, PlanetId>::new(&registry)
+            .cell("system", |state, id| {
+                let system = VerifiedEntity::assert_valid(*state.planet_system.get(id)?);
+                state.system_name.get(&system).cloned()
+            });
+        let mut exported = vec![];
+        table.export(&mut exported, &state, &entities).unwrap();
+        assert_eq!("id,planet_name,system\n0v1,Earth,Sol\n1v1,Proxima b,\"Alpha, Centauri\"\n", String::from_utf8(exported).unwrap());
+    }
+
+    #[test]
+    fn bad_rows_are_errors() {
+        let mut state = State::default();
+        let mut allocator = Allocator::<PlanetId>::new();
+        let keys = Keys::<SystemId>::new();
+        let import_planets = |text: &str, state: &mut State, allocator: &mut Allocator<PlanetId>| {
+            import(text.as_bytes(), state, allocator, |record| {
+                Ok(PlanetRow { name: record.get("name")?.to_string(), system: record.lookup("system", &keys)? })
+            })
+        };
+
+        assert!(matches!(
+            import_planets("name,system\nEarth,Sol\n", &mut state, &mut allocator),
+            Err(Error::Csv(CsvError::UnknownKey { line: 2, .. }))
+        ));
+        assert!(matches!(import_planets("name\nEarth\n", &mut state, &mut allocator), Err(Error::Csv(CsvError::MissingColumn(_)))));
+        assert!(matches!(import_planets("name,system\n\"Earth,Sol\n", &mut state, &mut allocator), Err(Error::Csv(CsvError::Malformed { .. }))));
+    }
+
+    #[test]
+    fn failing_row_creates_nothing() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+
+        let catalogue = "system_name,system_x\nSol,0\nAlpha Centauri,far\n";
+        let result = import(catalogue.as_bytes(), &mut state, &mut entities.systems, |record| {
+            Ok(SystemRow { name: record.get("system_name")?.to_string(), x: record.parse("system_x")? })
+        });
+
+        assert!(matches!(result, Err(Error::Csv(CsvError::Invalid { line: 3, .. }))));
+        assert_eq!(0, entities.systems.ids().count());
+        assert!(state.system_name.values.is_empty());
+    }
+
+    #[test]
+    fn quoted_empty_field_is_a_record() {
+        let records = read_records("name\n\nEarth\n\"\"\n").unwrap();
+        assert_eq!(vec![(1, vec!["name"]), (3, vec!["Earth"]), (4, vec![""])], records.iter()
+            .map(|(line, fields)| (*line, fields.iter().map(String::as_str).collect::<Vec<_>>()))
+            .collect::<Vec<_>>());
+
+        let mut written = vec![];
+        write_record(&mut written, &["".to_string()]).unwrap();
+        assert_eq!(b"\"\"\n".to_vec(), written);
+    }
+
+    #[test]
+    fn unregistered_table_is_an_error() {
+        let registry: Registry<State, Allocators> = registry!(State, Allocators; tables: systems);
+        let result = CsvTable::<_, _, PlanetId>::new(&registry).export(vec![], &State::default(), &Allocators::default());
+
+        assert!(matches!(result, Err(Error::Unregistered { .. })));
+    }
+
+    #[test]
+    fn duplicate_natural_keys_are_errors() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        systems(&mut state, &mut entities.systems);
+        let sol = entities.systems.create_entity();
+        state.system_name.insert(&sol, "Sol".to_string());
+
+        match Keys::of(&entities.systems, &state.system_name) {
+            Err(Error::Csv(CsvError::DuplicateKey(key))) => assert_eq!("Sol", key),
+            other => panic!("expected a duplicate key: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,629 @@
+use crate::traits::IdType;
+use crate::entities::{Allocator, Generation};
+use crate::storage::*;
+use crate::reflect::{Registry, AnyTable, AnyColumn};
+use crate::snapshot::{Tag, Encode, Decode, SnapshotError};
+use crate::error::Result;
+
+/// One changed row. `value` is `None` when the row was removed.
+///
+/// Keys and values are encoded with `snapshot::Encode`, so a delta can be sent or saved as it is.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Row {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl Row {
+    fn set<K: Encode, T: Encode>(key: &K, value: &T) -> Self {
+        Row { key: encoded(key), value: Some(encoded(value)) }
+    }
+
+    fn remove<K: Encode>(key: &K) -> Self {
+        Row { key: encoded(key), value: None }
+    }
+
+    fn key<K: Decode>(&self) -> Result<K> {
+        decoded(&self.key)
+    }
+}
+
+/// The bytes of `value`, as rows and messages hold them.
+pub(crate) fn encoded<T: Encode>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![];
+    value.encode(&mut bytes);
+    bytes
+}
+
+/// Decodes a whole row, failing if bytes are left over.
+pub(crate) fn decoded<T: Decode>(mut bytes: &[u8]) -> Result<T> {
+    let value = T::decode(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(SnapshotError::Corrupt("trailing bytes in row").into());
+    }
+    Ok(value)
+}
+
+/// Storage that can list its changes from an older version of itself and apply such a list.
+pub trait Diffable: Tag {
+    /// The rows that turn `self` into `new`.
+    fn diff(&self, new: &Self) -> Vec<Row>;
+    /// Fails where `patch` would, without changing anything.
+    fn check(&self, rows: &[Row]) -> Result<()>;
+    /// Applies `rows`, changing nothing if any of them is bad.
+    fn patch(&mut self, rows: &[Row]) -> Result<()>;
+}
+
+/// Decodes the keys and values of map rows, as `patch` will.
+fn check_map<ID: Decode, T: Decode>(rows: &[Row]) -> Result<()> {
+    for row in rows {
+        row.key::<ID>()?;
+        if let Some(value) = &row.value {
+            decoded::<T>(value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the keys of set rows, as `patch` will.
+fn check_set<ID: Decode>(rows: &[Row]) -> Result<()> {
+    rows.iter().try_for_each(|row| row.key::<ID>().map(drop))
+}
+
+/// The index, generation and liveness an allocator row sets, or `None` for the row holding the free list.
+pub(crate) fn allocator_slot(row: &Row) -> Result<Option<(usize, Generation, bool)>> {
+    let value = row.value.as_ref().ok_or(SnapshotError::Corrupt("allocator rows are never removed"))?;
+    if row.key.is_empty() {
+        return Ok(None);
+    }
+
+    let index = row.key::<u32>()? as usize;
+    let (generation, alive): (u32, bool) = decoded(value)?;
+    let generation = Generation::from_value(generation).ok_or(SnapshotError::Corrupt("zero generation"))?;
+    Ok(Some((index, generation, alive)))
+}
+
+/// The allocator `rows` turn `allocator` into, if it is one `create_entity` and `kill` could have produced.
+fn patched<ID: IdType + Encode + Decode>(allocator: &Allocator<ID>, rows: &[Row]) -> Result<Allocator<ID>> {
+    let mut generations = allocator.generations.clone();
+    let mut dead = allocator.dead.clone();
+    let mut living = allocator.living.clone();
+
+    for row in rows {
+        let (index, generation, alive) = match allocator_slot(row)? {
+            Some(slot) => slot,
+            None => {
+                let indices: Vec<u32> = decoded(row.value.as_deref().unwrap_or_default())?;
+                dead = indices.into_iter().map(|index| index as usize).collect();
+                continue;
+            }
+        };
+
+        if index >= generations.len() {
+            generations.resize(index + 1, Generation::default());
+            living.resize(index + 1, None);
+        }
+        generations[index] = generation;
+        living[index] = if alive { Some(ID::create(index, generation)) } else { None };
+    }
+
+    Allocator::from_parts(generations, dead, living)
+}
+
+/// Rows are keyed by index, holding the generation and whether the index is alive.
+/// The free list is a row with an empty key, included whenever it changed.
+impl<ID: IdType + Tag + Encode + Decode> Diffable for Allocator<ID> {
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows = vec![];
+
+        for (index, generation) in new.generations.iter().enumerate() {
+            let alive = new.living[index].is_some();
+
+            if self.generations.get(index) != Some(generation) || self.living[index].is_some() != alive {
+                rows.push(Row::set(&(index as u32), &(generation.value(), alive)));
+            }
+        }
+
+        if self.dead != new.dead {
+            let dead: Vec<u32> = new.dead.iter().map(|index| *index as u32).collect();
+            rows.push(Row { key: vec![], value: Some(encoded(&dead)) });
+        }
+
+        rows
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        patched(self, rows).map(drop)
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        *self = patched(self, rows)?;
+        Ok(())
+    }
+}
+
+/// Rows are keyed by index. A removed row truncates the column there.
+impl<ID, T> Diffable for IndexedVec<ID, T>
+where
+    ID: IdType + Tag,
+    T: Encode + Decode + PartialEq,
+{
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows: Vec<_> = new.values.iter()
+            .enumerate()
+            .filter(|(index, value)| self.values.get(*index) != Some(value))
+            .map(|(index, value)| Row::set(&(index as u32), value))
+            .collect();
+
+        if new.values.len() < self.values.len() {
+            rows.push(Row::remove(&(new.values.len() as u32)));
+        }
+
+        rows
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        let mut len = self.values.len();
+
+        for row in rows {
+            let index = row.key::<u32>()? as usize;
+
+            match &row.value {
+                Some(value) if index <= len => {
+                    decoded::<T>(value)?;
+                    len = len.max(index + 1);
+                }
+                Some(_) => return Err(SnapshotError::Corrupt("row past the end of the column").into()),
+                None => len = len.min(index),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        self.check(rows)?;
+
+        for row in rows {
+            let index = row.key::<u32>()? as usize;
+
+            match &row.value {
+                Some(value) if index < self.values.len() => self.values[index] = decoded(value)?,
+                Some(value) if index == self.values.len() => self.values.push(decoded(value)?),
+                Some(_) => return Err(SnapshotError::Corrupt("row past the end of the column").into()),
+                None => self.values.truncate(index),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<ID, T> Diffable for EntityMap<ID, T>
+where
+    ID: IdType + Encode + Decode,
+    T: Encode + Decode + PartialEq,
+{
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows: Vec<_> = new.values.iter()
+            .filter(|(id, value)| self.values.get(id) != Some(value))
+            .map(|(id, value)| (*id, Row::set(id, value)))
+            .chain(self.values.keys()
+                .filter(|id| !new.values.contains_key(id))
+                .map(|id| (*id, Row::remove(id))))
+            .collect();
+
+        rows.sort_unstable_by_key(|(id, _)| *id);
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        check_map::<ID, T>(rows)
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        self.check(rows)?;
+
+        for row in rows {
+            let id = row.key::<ID>()?;
+
+            match &row.value {
+                Some(value) => self.values.insert(id, decoded(value)?),
+                None => self.values.remove(&id),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Rows are keyed by id, with an empty value for ids that were inserted.
+impl<ID: IdType + Encode + Decode> Diffable for EntitySet<ID> {
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows: Vec<_> = new.values.difference(&self.values)
+            .map(|id| (*id, Row { key: encoded(id), value: Some(vec![]) }))
+            .chain(self.values.difference(&new.values).map(|id| (*id, Row::remove(id))))
+            .collect();
+
+        rows.sort_unstable_by_key(|(id, _)| *id);
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        check_set::<ID>(rows)
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        self.check(rows)?;
+
+        for row in rows {
+            let id = row.key::<ID>()?;
+
+            match row.value {
+                Some(_) => self.values.insert(id),
+                None => self.values.remove(&id),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl<ID, T> Diffable for OrderedMap<ID, T>
+where
+    ID: IdType + Encode + Decode,
+    T: Encode + Decode + PartialEq,
+{
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows: Vec<_> = new.values.iter()
+            .filter(|(id, value)| self.values.get(id) != Some(value))
+            .map(|(id, value)| (*id, Row::set(id, value)))
+            .chain(self.values.keys()
+                .filter(|id| !new.values.contains_key(id))
+                .map(|id| (*id, Row::remove(id))))
+            .collect();
+
+        rows.sort_by_key(|(id, _)| *id);
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        check_map::<ID, T>(rows)
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        self.check(rows)?;
+
+        for row in rows {
+            let id = row.key::<ID>()?;
+
+            match &row.value {
+                Some(value) => self.values.insert(id, decoded(value)?),
+                None => self.values.remove(&id),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl<ID: IdType + Encode + Decode> Diffable for OrderedSet<ID> {
+    fn diff(&self, new: &Self) -> Vec<Row> {
+        let mut rows: Vec<_> = new.values.difference(&self.values)
+            .map(|id| (*id, Row { key: encoded(id), value: Some(vec![]) }))
+            .chain(self.values.difference(&new.values).map(|id| (*id, Row::remove(id))))
+            .collect();
+
+        rows.sort_by_key(|(id, _)| *id);
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn check(&self, rows: &[Row]) -> Result<()> {
+        check_set::<ID>(rows)
+    }
+
+    fn patch(&mut self, rows: &[Row]) -> Result<()> {
+        self.check(rows)?;
+
+        for row in rows {
+            let id = row.key::<ID>()?;
+
+            match row.value {
+                Some(_) => self.values.insert(id),
+                None => self.values.remove(&id),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// The changed rows of one registered allocator or column.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Change {
+    pub name: String,
+    pub tag: String,
+    pub rows: Vec<Row>,
+}
+
+/// Everything that changed between two versions of a world, allocators first.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Delta {
+    pub changes: Vec<Change>,
+}
+
+impl Delta {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn change(&self, name: &str) -> Option<&Change> {
+        self.changes.iter().find(|change| change.name == name)
+    }
+}
+
+impl Tag for Delta {
+    fn tag() -> String {
+        "delta".to_string()
+    }
+}
+
+impl Encode for Delta {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.changes.len() as u64).encode(out);
+
+        for change in &self.changes {
+            change.name.encode(out);
+            change.tag.encode(out);
+            (change.rows.len() as u64).encode(out);
+
+            for row in &change.rows {
+                row.key.encode(out);
+                row.value.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Delta {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = u64::decode(input)?;
+
+        let mut changes = vec![];
+        for _ in 0..len {
+            let name = String::decode(input)?;
+            let tag = String::decode(input)?;
+            let rows = (0..u64::decode(input)?)
+                .map(|_| Ok(Row { key: Vec::decode(input)?, value: Option::decode(input)? }))
+                .collect::<Result<_>>()?;
+
+            changes.push(Change { name, tag, rows });
+        }
+
+        Ok(Delta { changes })
+    }
+}
+
+/// The change that turns `old` into `new` under `name`, or `None` if they are the same.
+pub(crate) fn change<C: Diffable>(name: &str, old: &C, new: &C) -> Option<Change> {
+    let rows = old.diff(new);
+
+    if rows.is_empty() {
+        None
+    } else {
+        Some(Change { name: name.to_string(), tag: C::tag(), rows })
+    }
+}
+
+/// Fails where `patch` would, without changing `storage`.
+pub(crate) fn check<C: Diffable>(storage: &C, change: &Change) -> Result<()> {
+    check_tag::<C>(change)?;
+    storage.check(&change.rows)
+}
+
+/// Applies `change` after checking it was made from storage of the same type.
+pub(crate) fn patch<C: Diffable>(storage: &mut C, change: &Change) -> Result<()> {
+    check_tag::<C>(change)?;
+    storage.patch(&change.rows)
+}
+
+fn check_tag<C: Diffable>(change: &Change) -> Result<()> {
+    let expected = C::tag();
+    if change.tag != expected {
+        return Err(SnapshotError::TypeMismatch {
+            name: change.name.clone(),
+            expected,
+            found: change.tag.clone(),
+        }.into());
+    }
+    Ok(())
+}
+
+/// Deltas of the tables and encoded columns of a registry, allocators first.
+pub struct Diff<S, E> {
+    registry: Registry<S, E>,
+}
+
+impl<S: 'static, E: 'static> Diff<S, E> {
+    pub fn new(registry: Registry<S, E>) -> Self {
+        Diff { registry }
+    }
+
+    pub fn registry(&self) -> &Registry<S, E> {
+        &self.registry
+    }
+
+    /// Lists what changed from `old` to `new`.
+    pub fn between(&self, old: (&S, &E), new: (&S, &E)) -> Delta {
+        let tables = self.registry.all_tables().filter_map(|table| table.diff(old.1, new.1));
+        let columns = self.registry.all_columns().filter_map(|column| column.diff(old.0, new.0));
+
+        Delta { changes: tables.chain(columns).collect() }
+    }
+
+    /// Applies `delta` to a world that matches the `old` world it was made from.
+    ///
+    /// Every change is checked before any is applied, so a bad delta leaves the world as it was.
+    pub fn apply(&self, delta: &Delta, state: &mut S, entities: &mut E) -> Result<()> {
+        for (index, change) in delta.changes.iter().enumerate() {
+            if delta.changes[..index].iter().any(|earlier| earlier.name == change.name) {
+                return Err(SnapshotError::DuplicateSection(change.name.clone()).into());
+            }
+
+            if let Some(table) = self.table(&change.name) {
+                table.check(entities, change)?;
+            } else if let Some(column) = self.column(&change.name) {
+                column.check(state, change)?;
+            } else {
+                return Err(SnapshotError::MissingSection(change.name.clone()).into());
+            }
+        }
+
+        for change in &delta.changes {
+            if let Some(table) = self.table(&change.name) {
+                table.patch(entities, change)?;
+            } else if let Some(column) = self.column(&change.name) {
+                column.patch(state, change)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn table(&self, name: &str) -> Option<&dyn AnyTable<E>> {
+        self.registry.all_tables().find(|table| table.info().name == name)
+    }
+
+    fn column(&self, name: &str) -> Option<&dyn AnyColumn<S>> {
+        self.registry.all_columns().find(|column| column.info().name == name && column.info().encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+
+    id_type!(ShipId, snapshot);
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Allocators {
+        ships: Allocator<ShipId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        ship_name: IndexedVec<ShipId, String>,
+        ship_escort: EntityMap<ShipId, ShipId>,
+        docked: EntitySet<ShipId>,
+    }
+
+    fn diff() -> Diff<State, Allocators> {
+        Diff::new(registry!(State, Allocators;
+            tables: ships;
+            encoded: ship_name, docked;
+            references: ship_escort))
+    }
+
+    fn base() -> (State, Allocators) {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+
+        for name in &["Victory", "Beagle", "Endeavour"] {
+            let ship = entities.ships.create_entity();
+            state.ship_name.insert(&ship, name.to_string());
+            state.docked.insert(ship.entity);
+        }
+        let ids: Vec<_> = entities.ships.ids().map(|id| id.entity).collect();
+        state.ship_escort.values.insert(ids[0], ids[1]);
+
+        (state, entities)
+    }
+
+    fn changed() -> (State, Allocators) {
+        let (mut state, mut entities) = base();
+        let ids: Vec<_> = entities.ships.ids().map(|id| id.entity).collect();
+
+        entities.ships.kill(ids[1]);
+        state.ship_escort.values.remove(&ids[0]);
+        state.docked.remove(&ids[1]);
+
+        let reused = entities.ships.create_entity();
+        state.ship_name.values[reused.entity.index()] = "Bounty".to_string();
+        state.ship_escort.values.insert(ids[2], reused.entity);
+
+        let fresh = entities.ships.create_entity();
+        state.ship_name.insert(&fresh, "Resolution".to_string());
+
+        (state, entities)
+    }
+
+    fn assert_same(a: &(State, Allocators), b: &(State, Allocators)) {
+        assert_eq!(a.1, b.1);
+        assert_eq!(a.0.ship_name.values, b.0.ship_name.values);
+        assert_eq!(a.0.ship_escort, b.0.ship_escort);
+        assert_eq!(a.0.docked, b.0.docked);
+    }
+
+    #[test]
+    fn same_worlds_have_empty_delta() {
+        let (state, entities) = base();
+        assert!(diff().between((&state, &entities), (&state, &entities)).is_empty());
+    }
+
+    #[test]
+    fn apply_turns_base_into_changed() {
+        let (old, new) = (base(), changed());
+        let delta = diff().between((&old.0, &old.1), (&new.0, &new.1));
+
+        assert_eq!(2, delta.change("ships").unwrap().rows.len());
+        assert_eq!(1, delta.change("docked").unwrap().rows.len());
+
+        let mut world = base();
+        diff().apply(&delta, &mut world.0, &mut world.1).unwrap();
+        assert_same(&new, &world);
+    }
+
+    #[test]
+    fn delta_round_trips_as_bytes() {
+        let (old, new) = (base(), changed());
+        let delta = diff().between((&old.0, &old.1), (&new.0, &new.1));
+
+        let mut bytes = vec![];
+        delta.encode(&mut bytes);
+        assert_eq!(delta, Delta::decode(&mut &bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn truncated_column_is_patched() {
+        let (old, mut new) = (base(), base());
+        new.0.ship_name.values.truncate(1);
+
+        let delta = diff().between((&old.0, &old.1), (&new.0, &new.1));
+        let mut world = base();
+        diff().apply(&delta, &mut world.0, &mut world.1).unwrap();
+
+        assert_eq!(vec!["Victory"], world.0.ship_name.values);
+    }
+
+    #[test]
+    fn bad_delta_changes_nothing() {
+        let (old, new) = (base(), changed());
+        let mut delta = diff().between((&old.0, &old.1), (&new.0, &new.1));
+        delta.changes.last_mut().unwrap().rows.push(Row { key: vec![1], value: None });
+
+        let mut world = base();
+        assert!(diff().apply(&delta, &mut world.0, &mut world.1).is_err());
+        assert_same(&old, &world);
+    }
+
+    #[test]
+    fn inconsistent_allocator_rows_change_nothing() {
+        let (_, entities) = base();
+        let mut allocator = entities.ships.clone();
+        let rows = vec![Row { key: vec![], value: Some(encoded(&vec![0u32])) }];
+
+        assert!(allocator.patch(&rows).is_err());
+        assert_eq!(entities.ships, allocator);
+    }
+}
@@ -0,0 +1,243 @@
+use std::any::TypeId;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+use crate::traits::*;
+use crate::entities::RawId;
+use crate::reflect::{Registry, AnyTable};
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Writes living entities as a Graphviz DOT digraph, with edges from reference columns.
+///
+/// Nodes come from the tables of a registry and are labelled with their table and id, followed by the
+/// `Debug` rows of the columns chosen with `label`. Edges to entities that are dead or whose table is
+/// not registered are left out.
+pub struct Graph<S, E> {
+    registry: Registry<S, E>,
+    labels: Vec<&'static str>,
+}
+
+impl<S: 'static, E: 'static> Graph<S, E> {
+    pub fn new(registry: Registry<S, E>) -> Self {
+        Graph { registry, labels: vec![] }
+    }
+
+    /// Adds the registered column `name` to the labels of the nodes it has a row for, after the columns added before it.
+    pub fn label(mut self, name: &'static str) -> Self {
+        self.labels.push(name);
+        self
+    }
+
+    fn table(&self, node: usize) -> &dyn AnyTable<E> {
+        self.registry.all_tables().nth(node).expect("nodes are positions of registered tables")
+    }
+
+    fn node(&self, table: TypeId) -> Option<usize> {
+        self.registry.all_tables().position(|registered| registered.id_type() == table)
+    }
+
+    /// The targets of the edges from `node` that lead to living, registered entities, sorted so output is stable.
+    fn targets(&self, state: &S, entities: &E, (node, id): (usize, RawId)) -> Vec<(&'static str, (usize, RawId))> {
+        let table = self.table(node).id_type();
+        let mut targets = vec![];
+
+        for column in self.registry.columns_keyed(table) {
+            if let Some(to) = column.target().and_then(|target| self.node(target)) {
+                for target in column.refs(state, id) {
+                    if self.table(to).is_alive(entities, target) {
+                        targets.push((column.info().name, (to, target)));
+                    }
+                }
+            }
+        }
+
+        targets.sort_unstable();
+        targets
+    }
+
+    /// Writes every living entity of the registered tables.
+    pub fn write<W: Write>(&self, output: W, state: &S, entities: &E) -> io::Result<()> {
+        let nodes = self.registry.all_tables()
+            .enumerate()
+            .flat_map(|(node, table)| table.living(entities).into_iter().map(move |id| (node, id)))
+            .collect();
+
+        self.write_nodes(output, state, entities, &nodes)
+    }
+
+    /// Writes `root` and the entities reachable from it by following at most `depth` edges.
+    pub fn write_from<ID, W>(&self, output: W, state: &S, entities: &E, root: ID, depth: usize) -> io::Result<()>
+    where
+        ID: IdType + 'static,
+        W: Write,
+    {
+        let mut nodes = BTreeSet::new();
+        let mut queue = VecDeque::new();
+
+        if let Some(node) = self.node(TypeId::of::<ID>()) {
+            if self.table(node).is_alive(entities, RawId::of(root)) {
+                nodes.insert((node, RawId::of(root)));
+                queue.push_back(((node, RawId::of(root)), 0));
+            }
+        }
+
+        while let Some((from, distance)) = queue.pop_front() {
+            if distance == depth {
+                continue;
+            }
+
+            for (_, to) in self.targets(state, entities, from) {
+                if nodes.insert(to) {
+                    queue.push_back((to, distance + 1));
+                }
+            }
+        }
+
+        self.write_nodes(output, state, entities, &nodes)
+    }
+
+    fn write_nodes<W: Write>(&self, mut output: W, state: &S, entities: &E, nodes: &BTreeSet<(usize, RawId)>) -> io::Result<()> {
+        let name = |(node, id): (usize, RawId)| format!("\"{}_{}\"", self.table(node).info().name, id);
+
+        writeln!(output, "digraph world {{")?;
+
+        for &(node, id) in nodes {
+            let table = self.table(node);
+            let mut label = format!("{} {}", table.info().name, id);
+            for &name in &self.labels {
+                let column = self.registry.columns_keyed(table.id_type()).find(|column| column.info().name == name);
+                if let Some(value) = column.and_then(|column| column.value(state, id)) {
+                    label += &format!("\n{}: {:?}", name, value);
+                }
+            }
+
+            writeln!(output, "    {} [label=\"{}\"];", name((node, id)), escape(&label))?;
+        }
+
+        for &from in nodes {
+            for (edge, to) in self.targets(state, entities, from) {
+                if nodes.contains(&to) {
+                    writeln!(output, "    {} -> {} [label=\"{}\"];", name(from), name(to), edge)?;
+                }
+            }
+        }
+
+        writeln!(output, "}}")?;
+        output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+
+    id_type!(SystemId, snapshot);
+    id_type!(BodyId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        systems: Allocator<SystemId>,
+        bodies: Allocator<BodyId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        system_name: IndexedVec<SystemId, String>,
+        system_bodies: IndexedVec<SystemId, EntitySet<BodyId>>,
+        body_name: IndexedVec<BodyId, String>,
+        body_orbit: EntityMap<BodyId, BodyId>,
+    }
+
+    fn world() -> (State, Allocators) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+
+        let sol = entities.systems.create_entity();
+        state.system_name.insert(&sol, "Sol".to_string());
+        state.system_bodies.insert(&sol, EntitySet::new());
+
+        let mut bodies = vec![];
+        for name in &["Sun", "Earth", "Moon"] {
+            let body = entities.bodies.create_entity();
+            state.body_name.insert(&body, name.to_string());
+            state.system_bodies.values[0].insert(body.entity);
+            bodies.push(body.entity);
+        }
+        state.body_orbit.values.insert(bodies[1], bodies[0]);
+        state.body_orbit.values.insert(bodies[2], bodies[1]);
+
+        (state, entities)
+    }
+
+    fn graph() -> Graph<State, Allocators> {
+        Graph::new(registry!(State, Allocators;
+            tables: systems, bodies;
+            columns: system_name, body_name;
+            references: body_orbit, system_bodies))
+            .label("system_name")
+            .label("body_name")
+    }
+
+    fn written(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut bytes = vec![];
+        write(&mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn writes_labelled_nodes_and_edges() {
+        let (state, entities) = world();
+        let dot = written(|out| graph().write(out, &state, &entities));
+
+        assert_eq!(concat!(
+            "digraph world {\n",
+            "    \"systems_0v1\" [label=\"systems 0v1\\nsystem_name: \\\"Sol\\\"\"];\n",
+            "    \"bodies_0v1\" [label=\"bodies 0v1\\nbody_name: \\\"Sun\\\"\"];\n",
+            "    \"bodies_1v1\" [label=\"bodies 1v1\\nbody_name: \\\"Earth\\\"\"];\n",
+            "    \"bodies_2v1\" [label=\"bodies 2v1\\nbody_name: \\\"Moon\\\"\"];\n",
+            "    \"systems_0v1\" -> \"bodies_0v1\" [label=\"system_bodies\"];\n",
+            "    \"systems_0v1\" -> \"bodies_1v1\" [label=\"system_bodies\"];\n",
+            "    \"systems_0v1\" -> \"bodies_2v1\" [label=\"system_bodies\"];\n",
+            "    \"bodies_1v1\" -> \"bodies_0v1\" [label=\"body_orbit\"];\n",
+            "    \"bodies_2v1\" -> \"bodies_1v1\" [label=\"body_orbit\"];\n",
+            "}\n",
+        ), dot);
+    }
+
+    #[test]
+    fn only_chosen_columns_label_nodes() {
+        let (state, entities) = world();
+        let graph = Graph::new(registry!(State, Allocators;
+            tables: systems, bodies;
+            columns: system_name, body_name;
+            references: body_orbit, system_bodies))
+            .label("body_name");
+
+        let dot = written(|out| graph.write(out, &state, &entities));
+        assert!(dot.contains("    \"systems_0v1\" [label=\"systems 0v1\"];\n"));
+        assert!(dot.contains("    \"bodies_1v1\" [label=\"bodies 1v1\\nbody_name: \\\"Earth\\\"\"];\n"));
+    }
+
+    #[test]
+    fn root_and_depth_limit_the_graph() {
+        let (state, mut entities) = world();
+        let graph = graph();
+        let moon = BodyId::new(2);
+
+        let dot = written(|out| graph.write_from(out, &state, &entities, moon, 1));
+        assert!(dot.contains("bodies_1v1\" ["));
+        assert!(!dot.contains("bodies_0v1\" ["));
+        assert!(!dot.contains("systems_0v1"));
+
+        let dot = written(|out| graph.write_from(out, &state, &entities, SystemId::new(0), 1));
+        assert_eq!(3, dot.matches("[label=\"bodies").count());
+        assert_eq!(3, dot.matches("[label=\"system_bodies\"]").count());
+
+        entities.bodies.kill(moon);
+        let dot = written(|out| graph.write(out, &state, &entities));
+        assert!(!dot.contains("bodies_2v1"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::marker::PhantomData;
 use std::num::NonZeroU32;
-use crate::traits::IdType;
+use crate::traits::{IdType, HasAllocator};
+use crate::error::{Error, Result};
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Generation(NonZeroU32);
@@ -14,6 +15,11 @@ impl Generation {
     pub fn value(self) -> u32 {
         self.0.get()
     }
+
+    /// Returns `None` for zero, which is never a valid generation.
+    pub fn from_value(value: u32) -> Option<Self> {
+        NonZeroU32::new(value).map(Generation)
+    }
 }
 
 impl Default for Generation {
@@ -22,11 +28,39 @@ impl Default for Generation {
     }
 }
 
-#[derive(Debug)]
+/// An id with its type erased, for code that handles several id types at once.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct RawId {
+    pub index: usize,
+    pub generation: Generation,
+}
+
+impl RawId {
+    pub fn of<ID: IdType>(id: ID) -> Self {
+        RawId {
+            index: id.index(),
+            generation: id.generation(),
+        }
+    }
+
+    pub fn id<ID: IdType>(self) -> ID {
+        ID::create(self.index, self.generation)
+    }
+}
+
+impl std::fmt::Display for RawId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation.value())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::serialize::AllocatorParts<ID>"))]
 pub struct Allocator<ID: IdType> {
-    generations: Vec<Generation>,
-    dead: Vec<usize>,
-    living: Vec<Option<ID>>,
+    pub(crate) generations: Vec<Generation>,
+    pub(crate) dead: Vec<usize>,
+    pub(crate) living: Vec<Option<ID>>,
 }
 
 impl<ID: IdType> Default for Allocator<ID> {
@@ -44,7 +78,36 @@ impl<ID: IdType> Allocator<ID> {
         Default::default()
     }
 
-    pub fn create_entity(&mut self) -> VerifiedEntity<ID> {
+    /// Rebuilds an allocator from saved parts, failing unless they describe one that
+    /// `create_entity` and `kill` could have produced.
+    pub(crate) fn from_parts(generations: Vec<Generation>, dead: Vec<usize>, living: Vec<Option<ID>>) -> Result<Self> {
+        if living.len() != generations.len() {
+            return Err(Error::Invalid("allocator lengths disagree"));
+        }
+
+        for (index, id) in living.iter().enumerate() {
+            if let Some(id) = id {
+                if id.index() != index || id.generation() != generations[index] {
+                    return Err(Error::Invalid("living id does not match its slot"));
+                }
+            }
+        }
+
+        let mut free = vec![false; living.len()];
+        for &index in &dead {
+            match living.get(index) {
+                Some(None) if !free[index] => free[index] = true,
+                _ => return Err(Error::Invalid("dead index is out of range, alive or repeated")),
+            }
+        }
+        if living.iter().filter(|id| id.is_none()).count() != dead.len() {
+            return Err(Error::Invalid("dead slot missing from the free list"));
+        }
+
+        Ok(Allocator { generations, dead, living })
+    }
+
+    pub fn create_entity(&mut self) -> VerifiedEntity<'_, ID> {
         if let Some(index) = self.dead.pop() {
             if let Some(gen) = self.generations.get(index) {
                 let entity = ID::create(index, *gen);
@@ -65,7 +128,15 @@ impl<ID: IdType> Allocator<ID> {
         self.generations.len()
     }
 
-    pub fn ids(&self) -> impl Iterator<Item = VerifiedEntity<ID>> {
+    /// The id the next `create_entity` will return.
+    pub(crate) fn next_id(&self) -> ID {
+        match self.dead.last() {
+            Some(&index) => ID::create(index, self.generations[index]),
+            None => ID::new(self.generations.len() as u32),
+        }
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = VerifiedEntity<'_, ID>> {
         self.living.iter()
             .filter_map(|id| {
                 id.map(|i| VerifiedEntity::assert_valid(i))
@@ -84,6 +155,61 @@ impl<ID: IdType> Allocator<ID> {
         None
     }
 
+    pub fn try_kill(&mut self, id: ID) -> Result<()> {
+        self.kill(id).ok_or_else(|| Error::dead_entity(id))
+    }
+
+    pub fn try_verify(&self, id: ID) -> Result<VerifiedEntity<'_, ID>> {
+        self.verify(id).ok_or_else(|| Error::dead_entity(id))
+    }
+
+    /// Makes `id` alive, or its slot dead, without allocating, for mirrors that only hold some entities.
+    ///
+    /// Slots added to reach `id`'s index start dead, and every dead slot is kept on the free list.
+    pub(crate) fn set_alive(&mut self, id: ID, alive: bool) {
+        let index = id.index();
+        while self.generations.len() <= index {
+            self.dead.push(self.generations.len());
+            self.generations.push(Generation::default());
+            self.living.push(None);
+        }
+
+        match (self.living[index].is_some(), alive) {
+            (false, true) => self.dead.retain(|dead| *dead != index),
+            (true, false) => self.dead.push(index),
+            _ => {}
+        }
+
+        self.generations[index] = if alive { id.generation() } else { id.generation().next() };
+        self.living[index] = if alive { Some(id) } else { None };
+    }
+
+    /// Reverses the most recent `create_entity`, which returned `id`.
+    pub(crate) fn undo_create(&mut self, id: ID) {
+        let index = id.index();
+
+        if id.generation() == Generation::default() {
+            // a fresh index was pushed
+            debug_assert_eq!(index + 1, self.generations.len());
+            self.generations.pop();
+            self.living.pop();
+        } else {
+            // a dead index was reused
+            self.living[index] = None;
+            self.dead.push(index);
+        }
+    }
+
+    /// Reverses the most recent `kill`, which killed `id`.
+    pub(crate) fn undo_kill(&mut self, id: ID) {
+        let index = id.index();
+
+        debug_assert_eq!(Some(index), self.dead.last().copied());
+        self.dead.pop();
+        self.generations[index] = id.generation();
+        self.living[index] = Some(id);
+    }
+
     pub fn is_alive(&self, entity: ID) -> bool {
         if let Some(gen) = self.generations.get(entity.index()) {
             entity.generation() == *gen
@@ -92,7 +218,7 @@ impl<ID: IdType> Allocator<ID> {
         }
     }
 
-    pub fn verify(&self, entity: ID) -> Option<VerifiedEntity<ID>> {
+    pub fn verify(&self, entity: ID) -> Option<VerifiedEntity<'_, ID>> {
         if self.is_alive(entity) {
             Some(VerifiedEntity::assert_valid(entity))
         } else {
@@ -101,6 +227,16 @@ impl<ID: IdType> Allocator<ID> {
     }
 }
 
+impl<ID: IdType> HasAllocator<ID> for Allocator<ID> {
+    fn allocator(&self) -> &Allocator<ID> {
+        self
+    }
+
+    fn allocator_mut(&mut self) -> &mut Allocator<ID> {
+        self
+    }
+}
+
 #[derive(Debug)]
 pub struct VerifiedEntity<'a,ID: IdType> {
     pub entity: ID,
@@ -124,6 +260,49 @@ mod tests {
         assert_eq!(generation, (id.1).0.get());
     }
 
+    #[test]
+    fn set_alive_keeps_the_free_list() {
+        let mut allocator = Allocator::<TestId>::new();
+        let far = TestId::create(3, Generation::default().next());
+
+        allocator.set_alive(far, true);
+        allocator.set_alive(TestId::new(1), true);
+        allocator.set_alive(TestId::new(1), false);
+
+        let (generations, dead, living) = (allocator.generations.clone(), allocator.dead.clone(), allocator.living.clone());
+        assert!(Allocator::<TestId>::from_parts(generations, dead, living).is_ok());
+        assert!(allocator.is_alive(far));
+
+        let created: Vec<_> = (0..3).map(|_| allocator.create_entity().entity.index()).collect();
+        assert!(!created.contains(&3));
+    }
+
+    #[test]
+    fn from_parts_rejects_inconsistent_allocators() {
+        let mut allocator = Allocator::<TestId>::new();
+        let id = allocator.create_entity().entity;
+        allocator.create_entity();
+        allocator.kill(id);
+
+        let parts = |allocator: &Allocator<TestId>| (allocator.generations.clone(), allocator.dead.clone(), allocator.living.clone());
+        let (generations, dead, living) = parts(&allocator);
+        assert_eq!(allocator, Allocator::from_parts(generations, dead, living).unwrap());
+
+        let (generations, _, living) = parts(&allocator);
+        assert!(Allocator::from_parts(generations, vec![], living).is_err());
+
+        let (generations, _, living) = parts(&allocator);
+        assert!(Allocator::from_parts(generations, vec![0, 0], living).is_err());
+
+        let (generations, dead, mut living) = parts(&allocator);
+        living.swap(0, 1);
+        assert!(Allocator::from_parts(generations, dead, living).is_err());
+
+        let (mut generations, dead, living) = parts(&allocator);
+        generations.pop();
+        assert!(Allocator::from_parts(generations, dead, living).is_err());
+    }
+
     #[test]
     fn create_entity() {
         let mut allocator = Allocator::<TestId>::new();
@@ -186,6 +365,15 @@ mod tests {
         assert!(allocator.is_alive(live));
     }
 
+    #[test]
+    fn try_kill_dead_entity() {
+        let mut allocator = Allocator::<TestId>::new();
+        let id = allocator.create_entity().entity;
+
+        allocator.try_kill(id).unwrap();
+        assert!(matches!(allocator.try_kill(id), Err(Error::DeadEntity { id: dead, .. }) if dead == RawId::of(id)));
+    }
+
     #[test]
     fn verify_returns_some_if_alive() {
         let mut allocator = Allocator::<TestId>::new();
@@ -0,0 +1,117 @@
+use std::any::type_name;
+use std::fmt;
+use std::io;
+use crate::traits::IdType;
+use crate::entities::RawId;
+use crate::snapshot::SnapshotError;
+use crate::csv::CsvError;
+use crate::prefab::PrefabError;
+use crate::repl::CommandError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Every failure the crate reports.
+///
+/// `column` names the storage involved. The `try_` methods on storage types only know the
+/// storage's type and fill that in; calls made through a `Field`, including every change a
+/// `Transaction` makes, report the field's name instead. Failures particular to one module
+/// are nested in it, in that module's own enum.
+#[derive(Debug)]
+pub enum Error {
+    /// An id is not alive in its allocator.
+    DeadEntity { id_type: &'static str, id: RawId },
+    /// A dense column has no row at the id's index.
+    IndexOutOfBounds { column: &'static str, id: RawId, len: usize },
+    /// A sparse column has no value for the id.
+    MissingComponent { column: &'static str, id: RawId },
+    /// A column already holds a value for the id, such as one side of a link that is already made.
+    LinkConflict { column: &'static str, id: RawId },
+    /// A kill refused because of an `OnDelete::Restrict` edge.
+    Restricted { owner: &'static str, owned: &'static str },
+    /// A value breaks one of its own requirements.
+    Invalid(&'static str),
+    /// A registry holds a column whose id type has no registered table.
+    Unregistered { column: &'static str },
+    Io(io::Error),
+    Snapshot(SnapshotError),
+    Csv(CsvError),
+    Prefab(PrefabError),
+    Command(CommandError),
+}
+
+impl Error {
+    pub fn dead_entity<ID: IdType>(id: ID) -> Self {
+        Error::DeadEntity {
+            id_type: type_name::<ID>(),
+            id: RawId::of(id),
+        }
+    }
+
+    /// Replaces the column name, for callers that know which field they were using.
+    pub fn in_column(self, name: &'static str) -> Self {
+        match self {
+            Error::IndexOutOfBounds { id, len, .. } => Error::IndexOutOfBounds { column: name, id, len },
+            Error::MissingComponent { id, .. } => Error::MissingComponent { column: name, id },
+            Error::LinkConflict { id, .. } => Error::LinkConflict { column: name, id },
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DeadEntity { id_type, id } =>
+                write!(f, "dead entity: {} {}", id_type, id),
+            Error::IndexOutOfBounds { column, id, len } =>
+                write!(f, "{}: entity index out of bounds: {} len: {}", column, id, len),
+            Error::MissingComponent { column, id } =>
+                write!(f, "{}: no value for {}", column, id),
+            Error::LinkConflict { column, id } =>
+                write!(f, "{}: {} already holds a value", column, id),
+            Error::Restricted { owner, owned } =>
+                write!(f, "cannot kill {} while it owns a living {}", owner, owned),
+            Error::Invalid(reason) =>
+                write!(f, "invalid value: {}", reason),
+            Error::Unregistered { column } =>
+                write!(f, "{}: no table is registered for its ids", column),
+            Error::Io(error) => write!(f, "io: {}", error),
+            Error::Snapshot(error) => write!(f, "{}", error),
+            Error::Csv(error) => write!(f, "{}", error),
+            Error::Prefab(error) => write!(f, "{}", error),
+            Error::Command(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<SnapshotError> for Error {
+    fn from(error: SnapshotError) -> Self {
+        Error::Snapshot(error)
+    }
+}
+
+impl From<CsvError> for Error {
+    fn from(error: CsvError) -> Self {
+        Error::Csv(error)
+    }
+}
+
+impl From<PrefabError> for Error {
+    fn from(error: PrefabError) -> Self {
+        Error::Prefab(error)
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(error: CommandError) -> Self {
+        Error::Command(error)
+    }
+}
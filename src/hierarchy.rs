@@ -0,0 +1,289 @@
+use crate::traits::*;
+use crate::entities::{Allocator, RawId, VerifiedEntity};
+use crate::storage::*;
+use crate::error::{Error, Result};
+
+/// A relation from an entity to its parent, such as `IndexedVec<OrbitId, Option<OrbitId>>`.
+pub trait Parent<ID: IdType> {
+    fn parent(&self, id: &VerifiedEntity<ID>) -> Option<ID>;
+}
+
+impl<ID: IdType> Parent<ID> for IndexedVec<ID, Option<ID>> {
+    fn parent(&self, id: &VerifiedEntity<ID>) -> Option<ID> {
+        self.get(id).copied().flatten()
+    }
+}
+
+impl<ID: IdType> Parent<ID> for EntityMap<ID, ID> {
+    fn parent(&self, id: &VerifiedEntity<ID>) -> Option<ID> {
+        self.get(id).copied()
+    }
+}
+
+impl<ID: IdType> Parent<ID> for OrderedMap<ID, ID> {
+    fn parent(&self, id: &VerifiedEntity<ID>) -> Option<ID> {
+        self.get(id).copied()
+    }
+}
+
+/// Returns the living entities ordered so that every parent comes before its children.
+///
+/// Parents that are dead are ignored, making their children roots.
+/// Fails with `Error::Invalid` if the relation contains a cycle.
+pub fn parents_first<'a, ID: IdType, P: Parent<ID>>(
+    allocator: &'a Allocator<ID>,
+    parent: &P,
+) -> Result<Vec<VerifiedEntity<'a, ID>>> {
+    let mut depth: Vec<Option<usize>> = vec![];
+    let mut ids = vec![];
+
+    for id in allocator.ids() {
+        let d = depth_of(&id, allocator, parent, &mut depth)?;
+        ids.push((d, id));
+    }
+
+    // stable sort keeps siblings in allocator order
+    ids.sort_by_key(|(d, _)| *d);
+    Ok(ids.into_iter().map(|(_, id)| id).collect())
+}
+
+fn depth_of<ID: IdType, P: Parent<ID>>(
+    id: &VerifiedEntity<ID>,
+    allocator: &Allocator<ID>,
+    parent: &P,
+    depth: &mut Vec<Option<usize>>,
+) -> Result<usize> {
+    let mut chain = vec![id.entity];
+    let mut current = allocator.verify(id.entity);
+    let mut base = 0;
+
+    while let Some(child) = current {
+        if let Some(Some(d)) = depth.get(child.entity.index()) {
+            base = *d + 1;
+            chain.pop();
+            break;
+        }
+
+        match parent.parent(&child).and_then(|p| allocator.verify(p)) {
+            Some(p) => {
+                if chain.contains(&p.entity) {
+                    return Err(Error::Invalid("cycle in hierarchy"));
+                }
+                chain.push(p.entity);
+                current = Some(p);
+            }
+            None => current = None,
+        }
+    }
+
+    // chain runs from `id` up to the highest ancestor without a known depth
+    for (i, entity) in chain.iter().rev().enumerate() {
+        let index = entity.index();
+        if depth.len() <= index {
+            depth.resize(index + 1, None);
+        }
+        depth[index] = Some(base + i);
+    }
+
+    Ok(depth[id.entity.index()].unwrap())
+}
+
+/// Computes `world = combine(parent world, local)` for every living entity, parents first.
+///
+/// Roots take their local value unchanged. Fails, changing nothing, if the relation contains a cycle
+/// or a living entity has no `local` row.
+pub fn propagate<ID, P, T, F>(
+    allocator: &Allocator<ID>,
+    parent: &P,
+    local: &IndexedVec<ID, T>,
+    world: &mut IndexedVec<ID, T>,
+    combine: F,
+) -> Result<()>
+where
+    ID: IdType,
+    P: Parent<ID>,
+    T: Clone,
+    F: Fn(&T, &T) -> T,
+{
+    propagate_where(allocator, parent, local, world, combine, |_| true)
+}
+
+/// Like `propagate`, but only recomputes entities that are in `dirty` or have a dirty ancestor.
+pub fn propagate_dirty<ID, P, T, F>(
+    allocator: &Allocator<ID>,
+    parent: &P,
+    local: &IndexedVec<ID, T>,
+    world: &mut IndexedVec<ID, T>,
+    dirty: &EntitySet<ID>,
+    combine: F,
+) -> Result<()>
+where
+    ID: IdType,
+    P: Parent<ID>,
+    T: Clone,
+    F: Fn(&T, &T) -> T,
+{
+    propagate_where(allocator, parent, local, world, combine, |id| dirty.contains(id))
+}
+
+fn propagate_where<ID, P, T, F, D>(
+    allocator: &Allocator<ID>,
+    parent: &P,
+    local: &IndexedVec<ID, T>,
+    world: &mut IndexedVec<ID, T>,
+    combine: F,
+    is_dirty: D,
+) -> Result<()>
+where
+    ID: IdType,
+    P: Parent<ID>,
+    T: Clone,
+    F: Fn(&T, &T) -> T,
+    D: Fn(&ID) -> bool,
+{
+    let order = parents_first(allocator, parent)?;
+    if let Some(id) = order.iter().find(|id| local.get(id).is_none()) {
+        return Err(Error::MissingComponent { column: "local", id: RawId::of(id.entity) });
+    }
+
+    // rows that do not exist yet start as their local value
+    let len = world.values.len();
+    if len < local.values.len() {
+        world.values.extend_from_slice(&local.values[len..]);
+    }
+
+    let mut changed = vec![false; local.values.len()];
+
+    for id in order {
+        let parent = parent.parent(&id).and_then(|p| allocator.verify(p));
+
+        let parent_changed = parent.as_ref()
+            .map(|p| changed[p.entity.index()])
+            .unwrap_or(false);
+
+        if !parent_changed && !is_dirty(&id.entity) {
+            continue;
+        }
+
+        changed[id.entity.index()] = true;
+
+        let value = match parent {
+            Some(p) => combine(&world[&p], &local[&id]),
+            None => local[&id].clone(),
+        };
+
+        world.insert(&id, value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    id_type!(TestId);
+
+    type Parents = IndexedVec<TestId, Option<TestId>>;
+
+    fn setup() -> (Allocator<TestId>, Parents, IndexedVec<TestId, i32>) {
+        let mut allocator = Allocator::<TestId>::new();
+        let mut parent = IndexedVec::new();
+        let mut local = IndexedVec::new();
+
+        // 0 <- 2 <- 1, created out of order so that index order is not parents-first
+        let root = allocator.create_entity().entity;
+        let leaf = allocator.create_entity().entity;
+        let middle = allocator.create_entity().entity;
+
+        parent.insert(&VerifiedEntity::assert_valid(root), None);
+        parent.insert(&VerifiedEntity::assert_valid(leaf), Some(middle));
+        parent.insert(&VerifiedEntity::assert_valid(middle), Some(root));
+
+        local.insert(&VerifiedEntity::assert_valid(root), 1);
+        local.insert(&VerifiedEntity::assert_valid(leaf), 100);
+        local.insert(&VerifiedEntity::assert_valid(middle), 10);
+
+        (allocator, parent, local)
+    }
+
+    #[test]
+    fn parents_first_orders_by_depth() {
+        let (allocator, parent, _) = setup();
+
+        let order = parents_first(&allocator, &parent)
+            .unwrap()
+            .into_iter()
+            .map(|id| id.entity.index())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![0, 2, 1], order);
+    }
+
+    #[test]
+    fn propagate_combines_with_parent() {
+        let (allocator, parent, local) = setup();
+        let mut world = IndexedVec::new();
+
+        propagate(&allocator, &parent, &local, &mut world, |p, l| p + l).unwrap();
+
+        assert_eq!(vec![1, 111, 11], world.values);
+    }
+
+    #[test]
+    fn dead_parent_makes_root() {
+        let (mut allocator, parent, local) = setup();
+        let mut world = IndexedVec::new();
+
+        let middle = parent.values[1].unwrap();
+        allocator.kill(middle);
+
+        propagate(&allocator, &parent, &local, &mut world, |p, l| p + l).unwrap();
+
+        assert_eq!(1, world.values[0]);
+        assert_eq!(100, world.values[1]);
+    }
+
+    #[test]
+    fn propagate_dirty_only_updates_dirty_subtrees() {
+        let (allocator, parent, mut local) = setup();
+        let mut world = IndexedVec::new();
+        propagate(&allocator, &parent, &local, &mut world, |p, l| p + l).unwrap();
+
+        let root = allocator.verify(parent.values[2].unwrap()).unwrap();
+        let middle = allocator.verify(parent.values[1].unwrap()).unwrap();
+        local.insert(&root, 2);
+        local.insert(&middle, 20);
+
+        let mut dirty = EntitySet::new();
+        dirty.insert(middle.entity);
+
+        propagate_dirty(&allocator, &parent, &local, &mut world, &dirty, |p, l| p + l).unwrap();
+
+        // root is not dirty, so its stale world value is kept
+        assert_eq!(vec![1, 121, 21], world.values);
+    }
+
+    #[test]
+    fn cycle_is_an_error() {
+        let (allocator, mut parent, local) = setup();
+        let root = allocator.verify(parent.values[2].unwrap()).unwrap();
+        let leaf = allocator.ids().nth(1).unwrap();
+        parent.insert(&root, Some(leaf.entity));
+
+        assert!(matches!(parents_first(&allocator, &parent), Err(Error::Invalid(_))));
+        assert!(propagate(&allocator, &parent, &local, &mut IndexedVec::new(), |p, l| p + l).is_err());
+    }
+
+    #[test]
+    fn missing_local_row_is_an_error() {
+        let (mut allocator, parent, local) = setup();
+        let extra = allocator.create_entity().entity;
+        let mut world = IndexedVec::new();
+
+        let result = propagate(&allocator, &parent, &local, &mut world, |p, l| p + l);
+
+        assert!(matches!(result, Err(Error::MissingComponent { id, .. }) if id == RawId::of(extra)));
+        assert!(world.values.is_empty());
+    }
+}
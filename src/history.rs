@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+use crate::traits::*;
+use crate::schema::Field;
+use crate::transaction::{Transaction, Restore, LinkRows, Log};
+use crate::error::Result;
+
+struct Action<S, E> {
+    name: &'static str,
+    steps: Log<S, E>,
+}
+
+impl<S, E> Action<S, E> {
+    fn undo(&mut self, state: &mut S, entities: &mut E) {
+        for step in self.steps.iter_mut().rev() {
+            step.undo(state, entities);
+        }
+    }
+
+    fn redo(&mut self, state: &mut S, entities: &mut E) {
+        for step in self.steps.iter_mut() {
+            step.redo(state, entities);
+        }
+    }
+}
+
+/// Named user-level actions that can be undone and redone, newest first.
+///
+/// Each action is the log of a committed `Transaction`. Undo and redo restore rows, links and
+/// allocators exactly, so redone creates get back the same ids.
+/// This only holds while every change to the world goes through the history.
+/// At most `limit` actions are kept; the oldest are forgotten first.
+pub struct History<S, E> {
+    done: VecDeque<Action<S, E>>,
+    undone: Vec<Action<S, E>>,
+    limit: usize,
+}
+
+impl<S: 'static, E: 'static> History<S, E> {
+    pub fn new(limit: usize) -> Self {
+        History {
+            done: VecDeque::new(),
+            undone: vec![],
+            limit,
+        }
+    }
+
+    /// Starts recording an action. Nothing is added to the history unless the edit is committed.
+    pub fn edit<'a>(&'a mut self, name: &'static str, state: &'a mut S, entities: &'a mut E) -> Edit<'a, S, E> {
+        Edit {
+            history: self,
+            name,
+            tx: Transaction::new(state, entities),
+        }
+    }
+
+    /// Undoes the newest action, returning its name.
+    pub fn undo(&mut self, state: &mut S, entities: &mut E) -> Option<&'static str> {
+        let mut action = self.done.pop_back()?;
+        action.undo(state, entities);
+
+        let name = action.name;
+        self.undone.push(action);
+        Some(name)
+    }
+
+    /// Redoes the newest undone action, returning its name.
+    pub fn redo(&mut self, state: &mut S, entities: &mut E) -> Option<&'static str> {
+        let mut action = self.undone.pop()?;
+        action.redo(state, entities);
+
+        let name = action.name;
+        self.done.push_back(action);
+        Some(name)
+    }
+
+    pub fn undo_names(&self) -> impl Iterator<Item=&'static str> + '_ {
+        self.done.iter().rev().map(|action| action.name)
+    }
+
+    pub fn redo_names(&self) -> impl Iterator<Item=&'static str> + '_ {
+        self.undone.iter().rev().map(|action| action.name)
+    }
+
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+    }
+
+    fn push(&mut self, action: Action<S, E>) {
+        self.undone.clear();
+        self.done.push_back(action);
+
+        while self.done.len() > self.limit {
+            self.done.pop_front();
+        }
+    }
+}
+
+/// Changes the world in a transaction whose log becomes one action.
+///
+/// Dropping an uncommitted edit rolls it back and records nothing.
+pub struct Edit<'a, S: 'static, E: 'static> {
+    history: &'a mut History<S, E>,
+    name: &'static str,
+    tx: Transaction<'a, S, E>,
+}
+
+impl<'a, S: 'static, E: 'static> Edit<'a, S, E> {
+    pub fn state(&self) -> &S {
+        self.tx.state()
+    }
+
+    pub fn entities(&self) -> &E {
+        self.tx.entities()
+    }
+
+    pub fn create_entity<ID: IdType + 'static>(&mut self) -> ID
+    where
+        E: HasAllocator<ID>,
+    {
+        self.tx.create_entity()
+    }
+
+    pub fn kill<ID: IdType + 'static>(&mut self, id: ID) -> Result<()>
+    where
+        E: HasAllocator<ID>,
+    {
+        self.tx.kill(id)
+    }
+
+    /// Sets the row for a living `id` in `column`.
+    pub fn insert<ID, T, C>(&mut self, column: Field<S, C>, id: ID, value: T) -> Result<()>
+    where
+        ID: IdType + 'static,
+        T: 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        self.tx.swap(column, id, Some(value))
+    }
+
+    /// Removes the row for a living `id` from `column`.
+    pub fn remove<ID, T, C>(&mut self, column: Field<S, C>, id: ID) -> Result<()>
+    where
+        ID: IdType + 'static,
+        T: 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        self.tx.swap::<ID, T, C>(column, id, None)
+    }
+
+    /// Links two living entities. Undoing puts back both rows the link wrote, including a link it replaced.
+    pub fn link<A: IdType + 'static, B: IdType + 'static>(&mut self, a: A, b: B) -> Result<()>
+    where
+        S: Link<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        self.tx.link(a, b)
+    }
+
+    /// Unlinks two living entities. Undoing puts back both rows.
+    pub fn unlink<A: IdType + 'static, B: IdType + 'static>(&mut self, a: A, b: B) -> Result<()>
+    where
+        S: Unlink<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        self.tx.unlink(a, b)
+    }
+
+    /// Adds the action to the history, clearing everything that could be redone.
+    pub fn commit(self) {
+        let steps = self.tx.commit_log();
+
+        if !steps.is_empty() {
+            self.history.push(Action { name: self.name, steps });
+        }
+    }
+
+    /// Undoes every step and records nothing.
+    pub fn cancel(self) {
+        self.tx.rollback();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{Allocator, VerifiedEntity};
+    use crate::storage::*;
+
+    id_type!(FleetId);
+    id_type!(ShipId);
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct Allocators {
+        fleets: Allocator<FleetId>,
+        ships: Allocator<ShipId>,
+    }
+
+    entities!(Allocators, fleets: FleetId, ships: ShipId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        fleet_ships: IndexedVec<FleetId, EntitySet<ShipId>>,
+        ship_fleet: IndexedVec<ShipId, FleetId>,
+        ship_name: EntityMap<ShipId, &'static str>,
+    }
+
+    link_to_many!(FleetId, fleet_ships, ShipId, ship_fleet);
+
+    fn spawn(history: &mut History<State, Allocators>, state: &mut State, entities: &mut Allocators, name: &'static str) -> ShipId {
+        let mut edit = history.edit("spawn", state, entities);
+        let ship = edit.create_entity();
+        edit.insert(field!(State, ship_name), ship, name).unwrap();
+        edit.commit();
+        ship
+    }
+
+    #[test]
+    fn undo_and_redo_restore_ids_exactly() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(10);
+
+        let victory = spawn(&mut history, &mut state, &mut entities, "Victory");
+        let before = entities.clone();
+
+        let mut edit = history.edit("scuttle and replace", &mut state, &mut entities);
+        edit.kill(victory).unwrap();
+        let beagle: ShipId = edit.create_entity();
+        edit.insert(field!(State, ship_name), beagle, "Beagle").unwrap();
+        edit.commit();
+
+        assert_eq!(Some("scuttle and replace"), history.undo(&mut state, &mut entities));
+        assert_eq!(before, entities);
+        assert_eq!(Some(&"Victory"), state.ship_name.values.get(&victory));
+
+        assert_eq!(Some("scuttle and replace"), history.redo(&mut state, &mut entities));
+        assert!(entities.ships.is_alive(beagle));
+        assert!(!entities.ships.is_alive(victory));
+        assert_eq!(Some(&"Beagle"), state.ship_name.values.get(&beagle));
+    }
+
+    #[test]
+    fn undo_and_redo_links() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(10);
+
+        let mut edit = history.edit("found fleet", &mut state, &mut entities);
+        let fleet: FleetId = edit.create_entity();
+        edit.insert(field!(State, fleet_ships), fleet, EntitySet::new()).unwrap();
+        let ship: ShipId = edit.create_entity();
+        edit.link(fleet, ship).unwrap();
+        edit.commit();
+
+        let mut edit = history.edit("leave fleet", &mut state, &mut entities);
+        edit.unlink(fleet, ship).unwrap();
+        edit.commit();
+        assert!(state.fleet_ships.values[0].is_empty());
+
+        history.undo(&mut state, &mut entities);
+        assert!(state.fleet_ships.values[0].contains(&ship));
+
+        history.redo(&mut state, &mut entities);
+        assert!(state.fleet_ships.values[0].is_empty());
+    }
+
+    #[test]
+    fn undo_restores_replaced_link() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(10);
+
+        let mut edit = history.edit("found fleets", &mut state, &mut entities);
+        let (home, away): (FleetId, FleetId) = (edit.create_entity(), edit.create_entity());
+        edit.insert(field!(State, fleet_ships), home, EntitySet::new()).unwrap();
+        edit.insert(field!(State, fleet_ships), away, EntitySet::new()).unwrap();
+        let ship: ShipId = edit.create_entity();
+        edit.link(home, ship).unwrap();
+        edit.commit();
+
+        let mut edit = history.edit("transfer", &mut state, &mut entities);
+        edit.link(away, ship).unwrap();
+        edit.commit();
+        assert_eq!(vec![away], state.ship_fleet.values);
+
+        history.undo(&mut state, &mut entities);
+        assert_eq!(vec![home], state.ship_fleet.values);
+        assert!(state.fleet_ships.values[1].is_empty());
+
+        history.redo(&mut state, &mut entities);
+        assert_eq!(vec![away], state.ship_fleet.values);
+        assert!(state.fleet_ships.values[1].contains(&ship));
+    }
+
+    #[test]
+    fn new_action_clears_redo() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(10);
+
+        spawn(&mut history, &mut state, &mut entities, "Victory");
+        history.undo(&mut state, &mut entities);
+        assert_eq!(vec!["spawn"], history.redo_names().collect::<Vec<_>>());
+
+        spawn(&mut history, &mut state, &mut entities, "Beagle");
+        assert_eq!(None, history.redo(&mut state, &mut entities));
+    }
+
+    #[test]
+    fn limit_forgets_oldest_actions() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(2);
+
+        for name in &["Victory", "Beagle", "Endeavour"] {
+            spawn(&mut history, &mut state, &mut entities, name);
+        }
+
+        assert_eq!(2, history.undo_names().count());
+        history.undo(&mut state, &mut entities);
+        history.undo(&mut state, &mut entities);
+        assert_eq!(None, history.undo(&mut state, &mut entities));
+        assert_eq!(1, entities.ships.ids().count());
+    }
+
+    #[test]
+    fn cancelled_edit_changes_nothing() {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+        let mut history = History::new(10);
+
+        let mut edit = history.edit("spawn", &mut state, &mut entities);
+        let ship: ShipId = edit.create_entity();
+        edit.insert(field!(State, ship_name), ship, "Victory").unwrap();
+        edit.cancel();
+
+        assert_eq!(Allocators::default(), entities);
+        assert!(state.ship_name.is_empty());
+        assert_eq!(None, history.undo(&mut state, &mut entities));
+    }
+}
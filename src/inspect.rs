@@ -0,0 +1,186 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashSet;
+use std::fmt;
+use crate::traits::IdType;
+use crate::entities::{RawId, VerifiedEntity};
+use crate::reflect::Registry;
+
+/// One entity with every registered row, as built by `Registry::inspect`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Node {
+    pub table: &'static str,
+    pub id: RawId,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Entry {
+    /// A row, formatted with `Debug`.
+    Value { column: &'static str, value: String },
+    /// A reference to another entity, with its node if the reference was followed.
+    Link { column: &'static str, table: &'static str, id: RawId, alive: bool, node: Option<Node> },
+}
+
+impl Node {
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+
+        for entry in &self.entries {
+            match entry {
+                Entry::Value { column, value } => writeln!(f, "{}{}: {}", pad, column, value)?,
+                Entry::Link { column, table, id, alive, node } => {
+                    let dead = if *alive { "" } else { " (dead)" };
+                    writeln!(f, "{}{} -> {} {}{}", pad, column, table, id, dead)?;
+
+                    if let Some(node) = node {
+                        node.write(f, indent + 1)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the tree with one row or reference per line, indenting the rows of followed entities.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.table, self.id)?;
+        self.write(f, 1)
+    }
+}
+
+impl<S: 'static, E: 'static> Registry<S, E> {
+    /// Reads every registered row of `id` and follows reference columns `depth` levels deep.
+    ///
+    /// Each entity is expanded once, so links back to an entity already in the tree are not followed again.
+    pub fn inspect<ID: IdType + 'static>(&self, state: &S, entities: &E, id: &VerifiedEntity<ID>, depth: usize) -> Node {
+        let table = self.table_of(TypeId::of::<ID>())
+            .map(|table| table.info().name)
+            .unwrap_or_else(type_name::<ID>);
+
+        let root = (TypeId::of::<ID>(), RawId::of(id.entity));
+        let mut seen = HashSet::new();
+        seen.insert(root);
+
+        self.node(state, entities, table, root, depth, &mut seen)
+    }
+
+    fn node(
+        &self,
+        state: &S,
+        entities: &E,
+        table: &'static str,
+        (id_type, id): (TypeId, RawId),
+        depth: usize,
+        seen: &mut HashSet<(TypeId, RawId)>,
+    ) -> Node {
+        let mut entries = vec![];
+
+        for column in self.columns_keyed(id_type) {
+            let name = column.info().name;
+            let target = column.target().and_then(|target| Some((target, self.table_of(target)?)));
+
+            match target {
+                Some((target, target_table)) => {
+                    for target_id in column.refs(state, id) {
+                        let alive = target_table.is_alive(entities, target_id);
+                        let table = target_table.info().name;
+
+                        let node = if alive && depth > 0 && seen.insert((target, target_id)) {
+                            Some(self.node(state, entities, table, (target, target_id), depth - 1, seen))
+                        } else {
+                            None
+                        };
+
+                        entries.push(Entry::Link { column: name, table, id: target_id, alive, node });
+                    }
+                }
+                None => {
+                    if let Some(value) = column.value(state, id) {
+                        entries.push(Entry::Value { column: name, value: format!("{:?}", value) });
+                    }
+                }
+            }
+        }
+
+        Node { table, id, entries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+
+    id_type!(BodyId, snapshot);
+    id_type!(SurfaceId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        bodies: Allocator<BodyId>,
+        surfaces: Allocator<SurfaceId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        body_radius: IndexedVec<BodyId, f64>,
+        body_surface: EntityMap<BodyId, SurfaceId>,
+        surface_body: IndexedVec<SurfaceId, BodyId>,
+        surface_albedo: IndexedVec<SurfaceId, f64>,
+    }
+
+    fn registry() -> Registry<State, Allocators> {
+        registry!(State, Allocators;
+            tables: bodies, surfaces;
+            columns: body_radius, surface_albedo;
+            references: body_surface, surface_body)
+    }
+
+    fn world() -> (State, Allocators, BodyId) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+
+        let body = entities.bodies.create_entity();
+        state.body_radius.insert(&body, 6371.0);
+        let body = body.entity;
+
+        let surface = entities.surfaces.create_entity();
+        state.surface_body.insert(&surface, body);
+        state.surface_albedo.insert(&surface, 0.3);
+        state.body_surface.values.insert(body, surface.entity);
+
+        (state, entities, body)
+    }
+
+    #[test]
+    fn follows_links_once() {
+        let (state, entities, body) = world();
+        let body = entities.bodies.verify(body).unwrap();
+
+        let node = registry().inspect(&state, &entities, &body, 3);
+        let text = concat!(
+            "bodies 0v1\n",
+            "  body_radius: 6371.0\n",
+            "  body_surface -> surfaces 0v1\n",
+            "    surface_albedo: 0.3\n",
+            "    surface_body -> bodies 0v1\n",
+        );
+        assert_eq!(text, node.to_string());
+
+        let shallow = registry().inspect(&state, &entities, &body, 0);
+        assert_eq!(2, shallow.entries.len());
+        assert!(matches!(shallow.entries[1], Entry::Link { node: None, alive: true, .. }));
+    }
+
+    #[test]
+    fn marks_dead_references() {
+        let (state, mut entities, body) = world();
+        entities.surfaces.kill(SurfaceId::new(0));
+        let body = entities.bodies.verify(body).unwrap();
+
+        let node = registry().inspect(&state, &entities, &body, 1);
+        assert!(node.to_string().contains("body_surface -> surfaces 0v1 (dead)\n"));
+    }
+}
@@ -0,0 +1,638 @@
+use std::any::TypeId;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use crate::traits::IdType;
+use crate::entities::RawId;
+use crate::storage::*;
+use crate::schema::{Field, RefColumn};
+use crate::reflect::{Registry, AnyTable};
+use crate::snapshot::{Tag, Encode, Decode, SnapshotError};
+use crate::diff::{encoded, decoded};
+use crate::error::Result;
+use crate::replication::{write_frame, read_frame};
+
+/// The entities one subscriber may see.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Interest {
+    ids: HashSet<(TypeId, RawId)>,
+}
+
+impl Interest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<ID: IdType + 'static>(&mut self, id: ID) {
+        self.ids.insert((TypeId::of::<ID>(), RawId::of(id)));
+    }
+
+    pub fn contains<ID: IdType + 'static>(&self, id: ID) -> bool {
+        self.contains_raw(TypeId::of::<ID>(), RawId::of(id))
+    }
+
+    fn contains_raw(&self, table: TypeId, id: RawId) -> bool {
+        self.ids.contains(&(table, id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+trait Follow<S> {
+    fn from(&self) -> TypeId;
+    fn to(&self) -> TypeId;
+    fn refs(&self, state: &S, id: RawId) -> Vec<RawId>;
+}
+
+struct RefField<S, ID, T, C> {
+    field: Field<S, C>,
+    marker: std::marker::PhantomData<(ID, T)>,
+}
+
+impl<S, ID, T, C> Follow<S> for RefField<S, ID, T, C>
+where
+    ID: IdType + 'static,
+    T: IdType + 'static,
+    C: RefColumn<ID, T>,
+{
+    fn from(&self) -> TypeId {
+        TypeId::of::<ID>()
+    }
+
+    fn to(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn refs(&self, state: &S, id: RawId) -> Vec<RawId> {
+        (self.field.get)(state).refs(id.id()).into_iter().map(RawId::of).collect()
+    }
+}
+
+/// Finds the entities reachable from a root by following registered reference columns.
+pub struct Reach<S> {
+    follow: Vec<Box<dyn Follow<S>>>,
+}
+
+impl<S> Default for Reach<S> {
+    fn default() -> Self {
+        Reach { follow: vec![] }
+    }
+}
+
+impl<S: 'static> Reach<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn follow<ID, T, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        T: IdType + 'static,
+        C: RefColumn<ID, T> + 'static,
+    {
+        self.follow.push(Box::new(RefField { field, marker: std::marker::PhantomData }));
+        self
+    }
+
+    /// The root and everything reachable from it. Dead references are included; subscribers only see living ids.
+    pub fn from<ID: IdType + 'static>(&self, state: &S, root: ID) -> Interest {
+        let mut interest = Interest::new();
+        let mut queue = VecDeque::new();
+
+        interest.insert(root);
+        queue.push_back((TypeId::of::<ID>(), RawId::of(root)));
+
+        while let Some((table, id)) = queue.pop_front() {
+            for follow in self.follow.iter().filter(|follow| follow.from() == table) {
+                for target in follow.refs(state, id) {
+                    if interest.ids.insert((follow.to(), target)) {
+                        queue.push_back((follow.to(), target));
+                    }
+                }
+            }
+        }
+
+        interest
+    }
+}
+
+/// Storage that can hand out and take back the encoded row of a single entity.
+pub trait EntityRows<ID: IdType> {
+    fn row(&self, id: ID) -> Option<Vec<u8>>;
+
+    /// Sets the row for `id`, or clears it if `row` is `None`.
+    fn set_row(&mut self, id: ID, row: Option<&[u8]>) -> Result<()>;
+}
+
+/// Rows of entities the client cannot see are left at `T::default()`.
+impl<ID: IdType, T: Encode + Decode + Default> EntityRows<ID> for IndexedVec<ID, T> {
+    fn row(&self, id: ID) -> Option<Vec<u8>> {
+        self.values.get(id.index()).map(encoded)
+    }
+
+    fn set_row(&mut self, id: ID, row: Option<&[u8]>) -> Result<()> {
+        let index = id.index();
+        if index >= self.values.len() {
+            self.values.resize_with(index + 1, T::default);
+        }
+
+        self.values[index] = match row {
+            Some(row) => decoded(row)?,
+            None => T::default(),
+        };
+        Ok(())
+    }
+}
+
+impl<ID: IdType, T: Encode + Decode> EntityRows<ID> for EntityMap<ID, T> {
+    fn row(&self, id: ID) -> Option<Vec<u8>> {
+        self.values.get(&id).map(encoded)
+    }
+
+    fn set_row(&mut self, id: ID, row: Option<&[u8]>) -> Result<()> {
+        match row {
+            Some(row) => self.values.insert(id, decoded(row)?),
+            None => self.values.remove(&id),
+        };
+        Ok(())
+    }
+}
+
+impl<ID: IdType, T: Encode + Decode> EntityRows<ID> for OrderedMap<ID, T> {
+    fn row(&self, id: ID) -> Option<Vec<u8>> {
+        self.values.get(&id).map(encoded)
+    }
+
+    fn set_row(&mut self, id: ID, row: Option<&[u8]>) -> Result<()> {
+        match row {
+            Some(row) => self.values.insert(id, decoded(row)?),
+            None => self.values.remove(&id),
+        };
+        Ok(())
+    }
+}
+
+/// What a subscriber is told. Enter carries every registered row of the entity.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    Enter { allocator: String, id: RawId, rows: Vec<(String, Vec<u8>)> },
+    Leave { allocator: String, id: RawId },
+    Change { column: String, id: RawId, row: Option<Vec<u8>> },
+}
+
+impl Tag for Message {
+    fn tag() -> String {
+        "message".to_string()
+    }
+}
+
+impl Encode for Message {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Enter { allocator, id, rows } => {
+                0u8.encode(out);
+                allocator.encode(out);
+                id.encode(out);
+                (rows.len() as u64).encode(out);
+                for (column, row) in rows {
+                    column.encode(out);
+                    row.encode(out);
+                }
+            }
+            Message::Leave { allocator, id } => {
+                1u8.encode(out);
+                allocator.encode(out);
+                id.encode(out);
+            }
+            Message::Change { column, id, row } => {
+                2u8.encode(out);
+                column.encode(out);
+                id.encode(out);
+                row.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        match u8::decode(input)? {
+            0 => {
+                let allocator = String::decode(input)?;
+                let id = RawId::decode(input)?;
+                let rows = (0..u64::decode(input)?)
+                    .map(|_| Ok((String::decode(input)?, Vec::decode(input)?)))
+                    .collect::<Result<_>>()?;
+                Ok(Message::Enter { allocator, id, rows })
+            }
+            1 => Ok(Message::Leave { allocator: String::decode(input)?, id: RawId::decode(input)? }),
+            2 => Ok(Message::Change { column: String::decode(input)?, id: RawId::decode(input)?, row: Option::decode(input)? }),
+            _ => Err(SnapshotError::Corrupt("unknown message").into()),
+        }
+    }
+}
+
+trait AnyRows<S> {
+    fn name(&self) -> &'static str;
+    fn table(&self) -> TypeId;
+    fn row(&self, state: &S, id: RawId) -> Option<Vec<u8>>;
+    fn set_row(&self, state: &mut S, id: RawId, row: Option<&[u8]>) -> Result<()>;
+}
+
+struct RowsField<S, ID, C> {
+    field: Field<S, C>,
+    marker: std::marker::PhantomData<ID>,
+}
+
+impl<S, ID: IdType + 'static, C: EntityRows<ID>> AnyRows<S> for RowsField<S, ID, C> {
+    fn name(&self) -> &'static str {
+        self.field.name
+    }
+
+    fn table(&self) -> TypeId {
+        TypeId::of::<ID>()
+    }
+
+    fn row(&self, state: &S, id: RawId) -> Option<Vec<u8>> {
+        (self.field.get)(state).row(id.id())
+    }
+
+    fn set_row(&self, state: &mut S, id: RawId, row: Option<&[u8]>) -> Result<()> {
+        (self.field.get_mut)(state).set_row(id.id(), row)
+    }
+}
+
+/// The tables of a registry and the columns replicated per entity. Servers and clients must register the same ones.
+///
+/// Client allocators only mirror the entities they can see, and never allocate.
+pub struct Interests<S, E> {
+    registry: Registry<S, E>,
+    columns: Vec<Box<dyn AnyRows<S>>>,
+}
+
+impl<S: 'static, E: 'static> Interests<S, E> {
+    pub fn new(registry: Registry<S, E>) -> Self {
+        Interests { registry, columns: vec![] }
+    }
+
+    pub fn column<ID, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        C: EntityRows<ID> + 'static,
+    {
+        self.columns.push(Box::new(RowsField { field, marker: std::marker::PhantomData }));
+        self
+    }
+
+    fn columns_of(&self, table: TypeId) -> impl Iterator<Item=&dyn AnyRows<S>> {
+        self.columns.iter()
+            .filter(move |column| column.table() == table)
+            .map(|column| column.as_ref())
+    }
+
+    fn allocator(&self, index: usize) -> &dyn AnyTable<E> {
+        self.registry.all_tables().nth(index).expect("visible entities are keyed by registered tables")
+    }
+
+    fn allocator_named(&self, name: &str) -> Result<&dyn AnyTable<E>> {
+        self.registry.all_tables()
+            .find(|allocator| allocator.info().name == name)
+            .ok_or_else(|| SnapshotError::MissingSection(name.to_string()).into())
+    }
+
+    fn column_named(&self, name: &str) -> Result<&dyn AnyRows<S>> {
+        self.columns.iter()
+            .find(|column| column.name() == name)
+            .map(|column| column.as_ref())
+            .ok_or_else(|| SnapshotError::MissingSection(name.to_string()).into())
+    }
+}
+
+type Filter<S, E> = Box<dyn Fn(&S, &E) -> Interest>;
+
+/// Sends one client the living entities in its interest set: enter and leave messages as
+/// entities come into and go out of view, and changed rows for those that stay.
+pub struct Subscriber<S, E> {
+    interests: Interests<S, E>,
+    filter: Filter<S, E>,
+    visible: BTreeSet<(usize, RawId)>,
+    sent: HashMap<(&'static str, RawId), Option<Vec<u8>>>,
+    tick: u64,
+}
+
+impl<S: 'static, E: 'static> Subscriber<S, E> {
+    pub fn new<F: Fn(&S, &E) -> Interest + 'static>(interests: Interests<S, E>, filter: F) -> Self {
+        Subscriber {
+            interests,
+            filter: Box::new(filter),
+            visible: BTreeSet::new(),
+            sent: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Computes the messages for the current state without sending them, as if they were sent.
+    pub fn update(&mut self, state: &S, entities: &E) -> Vec<Message> {
+        let update = self.changes(state, entities);
+        self.visible = update.visible;
+        self.sent = update.sent;
+        update.messages
+    }
+
+    /// Writes one tick of messages, even if there are none, and returns the tick sent.
+    ///
+    /// What the client has seen only changes once the frame is written, so a failed write is resent next tick.
+    pub fn send<W: Write>(&mut self, output: &mut W, state: &S, entities: &E) -> Result<u64> {
+        let update = self.changes(state, entities);
+        write_frame(output, self.tick + 1, &update.messages)?;
+
+        self.visible = update.visible;
+        self.sent = update.sent;
+        self.tick += 1;
+        Ok(self.tick)
+    }
+
+    fn changes(&self, state: &S, entities: &E) -> Update {
+        let interest = (self.filter)(state, entities);
+
+        let mut visible = BTreeSet::new();
+        for (i, allocator) in self.interests.registry.all_tables().enumerate() {
+            for id in allocator.living(entities) {
+                if interest.contains_raw(allocator.id_type(), id) {
+                    visible.insert((i, id));
+                }
+            }
+        }
+
+        let mut messages = vec![];
+        let mut sent = HashMap::new();
+        for (i, id) in self.visible.difference(&visible) {
+            let allocator = self.interests.allocator(*i);
+            messages.push(Message::Leave { allocator: allocator.info().name.to_string(), id: *id });
+        }
+
+        for (i, id) in &visible {
+            let allocator = self.interests.allocator(*i);
+            let entered = !self.visible.contains(&(*i, *id));
+            let mut rows = vec![];
+
+            for column in self.interests.columns_of(allocator.id_type()) {
+                let row = column.row(state, *id);
+
+                if entered {
+                    if let Some(row) = &row {
+                        rows.push((column.name().to_string(), row.clone()));
+                    }
+                } else if self.sent.get(&(column.name(), *id)) != Some(&row) {
+                    messages.push(Message::Change { column: column.name().to_string(), id: *id, row: row.clone() });
+                }
+                sent.insert((column.name(), *id), row);
+            }
+
+            if entered {
+                messages.push(Message::Enter { allocator: allocator.info().name.to_string(), id: *id, rows });
+            }
+        }
+
+        Update { messages, visible, sent }
+    }
+}
+
+/// The messages for one tick, with what the client has seen once it receives them.
+struct Update {
+    messages: Vec<Message>,
+    visible: BTreeSet<(usize, RawId)>,
+    sent: HashMap<(&'static str, RawId), Option<Vec<u8>>>,
+}
+
+/// Holds the entities a subscriber can see. Rows and allocator entries of other entities are empty.
+pub struct Subscription<S, E> {
+    interests: Interests<S, E>,
+    state: S,
+    entities: E,
+    tick: u64,
+}
+
+impl<S: Default + 'static, E: Default + 'static> Subscription<S, E> {
+    pub fn new(interests: Interests<S, E>) -> Self {
+        Subscription {
+            interests,
+            state: S::default(),
+            entities: E::default(),
+            tick: 0,
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn entities(&self) -> &E {
+        &self.entities
+    }
+
+    /// Applies one message.
+    pub fn apply(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Enter { allocator, id, rows } => {
+                let allocator = self.interests.allocator_named(allocator)?;
+                allocator.set_alive(&mut self.entities, *id, true);
+
+                for (column, row) in rows {
+                    self.interests.column_named(column)?.set_row(&mut self.state, *id, Some(row))?;
+                }
+            }
+            Message::Leave { allocator, id } => {
+                let allocator = self.interests.allocator_named(allocator)?;
+                allocator.set_alive(&mut self.entities, *id, false);
+
+                for column in self.interests.columns_of(allocator.id_type()) {
+                    column.set_row(&mut self.state, *id, None)?;
+                }
+            }
+            Message::Change { column, id, row } => {
+                self.interests.column_named(column)?.set_row(&mut self.state, *id, row.as_deref())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads and applies one tick, blocking until it arrives, and returns its messages.
+    pub fn receive<R: Read>(&mut self, input: &mut R) -> Result<Vec<Message>> {
+        let (tick, messages): (u64, Vec<Message>) = read_frame(input)?;
+        if tick != self.tick + 1 {
+            return Err(SnapshotError::Corrupt("tick out of order").into());
+        }
+
+        for message in &messages {
+            self.apply(message)?;
+        }
+        self.tick = tick;
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::entities::{Allocator, VerifiedEntity};
+
+    id_type!(SystemId, snapshot);
+    id_type!(LocationId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        systems: Allocator<SystemId>,
+        locations: Allocator<LocationId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        system_name: IndexedVec<SystemId, String>,
+        system_locations: IndexedVec<SystemId, EntitySet<LocationId>>,
+        location_system: IndexedVec<LocationId, SystemId>,
+        location_x: EntityMap<LocationId, f32>,
+    }
+
+    link_to_many!(SystemId, system_locations, LocationId, location_system);
+
+    fn interests() -> Interests<State, Allocators> {
+        Interests::new(registry!(State, Allocators; tables: systems, locations))
+            .column(field!(State, system_name))
+            .column(field!(State, location_x))
+    }
+
+    fn reach() -> Reach<State> {
+        Reach::new().follow(field!(State, system_locations))
+    }
+
+    fn galaxy() -> (State, Allocators, SystemId, SystemId) {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+
+        let mut systems = vec![];
+        for name in &["Sol", "Sirius"] {
+            let system = entities.systems.create_entity();
+            state.system_name.insert(&system, name.to_string());
+            state.system_locations.insert(&system, EntitySet::new());
+            let system = system.entity;
+
+            for x in 0..2 {
+                let location = entities.locations.create_entity();
+                state.location_x.insert(&location, x as f32);
+                let system = entities.systems.verify(system).unwrap();
+                state.link(&system, &location);
+            }
+            systems.push(system);
+        }
+
+        (state, entities, systems[0], systems[1])
+    }
+
+    fn sol_only(sol: SystemId) -> impl Fn(&State, &Allocators) -> Interest {
+        move |state, _| reach().from(state, sol)
+    }
+
+    #[test]
+    fn reach_follows_links() {
+        let (state, _, sol, sirius) = galaxy();
+        let interest = reach().from(&state, sol);
+
+        assert_eq!(3, interest.len());
+        assert!(!interest.contains(sirius));
+    }
+
+    #[test]
+    fn only_interesting_entities_enter() {
+        let (state, entities, sol, sirius) = galaxy();
+        let mut subscriber = Subscriber::new(interests(), sol_only(sol));
+
+        let messages = subscriber.update(&state, &entities);
+
+        assert_eq!(3, messages.len());
+        assert!(messages.iter().all(|message| match message {
+            Message::Enter { allocator, id, .. } => !(allocator == "systems" && *id == RawId::of(sirius)),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn changes_and_leaves_follow_enters() {
+        let (mut state, mut entities, sol, _) = galaxy();
+        let mut subscriber = Subscriber::new(interests(), sol_only(sol));
+        subscriber.update(&state, &entities);
+
+        let locations: Vec<_> = entities.locations.ids().map(|id| id.entity).collect();
+        state.location_x.values.insert(locations[0], 5.0);
+        state.location_x.values.insert(locations[2], 5.0);
+        entities.locations.kill(locations[1]);
+
+        let messages = subscriber.update(&state, &entities);
+        assert_eq!(vec![
+            Message::Leave { allocator: "locations".to_string(), id: RawId::of(locations[1]) },
+            Message::Change { column: "location_x".to_string(), id: RawId::of(locations[0]), row: Some(encoded(&5.0f32)) },
+        ], messages);
+
+        assert!(subscriber.update(&state, &entities).is_empty());
+    }
+
+    #[test]
+    fn subscription_mirrors_visible_entities() {
+        let (mut state, mut entities, sol, sirius) = galaxy();
+        let mut subscriber = Subscriber::new(interests(), sol_only(sol));
+        let mut subscription = Subscription::new(interests());
+        let mut pipe = vec![];
+
+        subscriber.send(&mut pipe, &state, &entities).unwrap();
+        let location = entities.locations.ids().next().unwrap().entity;
+        entities.locations.kill(location);
+        state.location_x.values.remove(&location);
+        subscriber.send(&mut pipe, &state, &entities).unwrap();
+
+        let mut input = &pipe[..];
+        subscription.receive(&mut input).unwrap();
+        assert!(subscription.entities().locations.is_alive(location));
+        subscription.receive(&mut input).unwrap();
+
+        assert!(subscription.entities().systems.is_alive(sol));
+        assert!(!subscription.entities().systems.is_alive(sirius));
+        assert!(!subscription.entities().locations.is_alive(location));
+        assert_eq!(vec!["Sol"], subscription.state().system_name.values);
+        assert_eq!(1, subscription.state().location_x.len());
+    }
+
+    #[test]
+    fn failed_send_is_resent() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (state, entities, sol, _) = galaxy();
+        let mut subscriber = Subscriber::new(interests(), sol_only(sol));
+        let mut subscription = Subscription::new(interests());
+
+        assert!(subscriber.send(&mut Broken, &state, &entities).is_err());
+
+        let mut pipe = vec![];
+        assert_eq!(1, subscriber.send(&mut pipe, &state, &entities).unwrap());
+        subscription.receive(&mut &pipe[..]).unwrap();
+
+        assert!(subscription.entities().systems.is_alive(sol));
+        assert_eq!(vec!["Sol"], subscription.state().system_name.values);
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use crate::traits::*;
+use crate::entities::{RawId, VerifiedEntity};
+use crate::snapshot::{Tag, Encode, Decode, SnapshotError};
+use crate::checksum::fnv;
+use crate::diff::encoded;
+use crate::error::{Error, Result};
+
+const MAGIC: &[u8; 4] = b"RECJ";
+/// Version 2 added the length and checksum to each record. Version 1 journals cannot be read.
+const FORMAT_VERSION: u16 = 2;
+
+/// One recorded mutation. Tables, columns and links are named by the tags of their types.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Entry {
+    Create { table: String, id: RawId },
+    Kill { table: String, id: RawId },
+    Insert { column: String, id: RawId, value: Vec<u8> },
+    Link { link: String, a: RawId, b: RawId },
+    Unlink { link: String, a: RawId, b: RawId },
+}
+
+fn insert_key<ID: Tag, T: Tag>() -> String {
+    format!("insert<{},{}>", ID::tag(), T::tag())
+}
+
+fn link_key<A: Tag, B: Tag>() -> String {
+    format!("link<{},{}>", A::tag(), B::tag())
+}
+
+/// Records are a `u64` length, the FNV-1a hash of the body, then the body: the tick and the entry.
+/// Writes one record per entry in a single write.
+fn write_records<W: Write>(output: &mut W, tick: u64, entries: &[Entry]) -> Result<()> {
+    let mut records = vec![];
+    for entry in entries {
+        let mut body = vec![];
+        tick.encode(&mut body);
+        entry.encode(&mut body);
+
+        (body.len() as u64).encode(&mut records);
+        fnv(&body).encode(&mut records);
+        records.extend_from_slice(&body);
+    }
+
+    output.write_all(&records)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Whether `rest`, the bytes after the last intact record, is a single record cut short by a crash.
+/// A damaged record followed by more bytes is corruption instead.
+pub(crate) fn is_torn_tail(rest: &[u8]) -> bool {
+    if rest.len() < 16 {
+        return true;
+    }
+
+    let len = u64::decode(&mut &rest[..8]).unwrap_or(u64::MAX);
+    len >= (rest.len() - 16) as u64
+}
+
+/// Reads the header and every intact record, returning the entries and how many bytes they span.
+///
+/// The first record that is cut short or fails its checksum ends the journal.
+pub(crate) fn read_records(bytes: &[u8]) -> Result<(Vec<(u64, Entry)>, usize)> {
+    if bytes.len() < 6 {
+        return Ok((vec![], 0));
+    }
+    if &bytes[..4] != MAGIC {
+        return Err(SnapshotError::Corrupt("not a journal").into());
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedFormat(version).into());
+    }
+
+    let mut entries = vec![];
+    let mut valid = 6;
+    loop {
+        let mut input = &bytes[valid..];
+        if input.len() < 16 {
+            break;
+        }
+
+        let len = u64::decode(&mut input)? as usize;
+        let hash = u64::decode(&mut input)?;
+        if input.len() < len || fnv(&input[..len]) != hash {
+            break;
+        }
+
+        let mut body = &input[..len];
+        let tick = u64::decode(&mut body)?;
+        entries.push((tick, Entry::decode(&mut body)?));
+        valid += 16 + len;
+    }
+
+    Ok((entries, valid))
+}
+
+impl Entry {
+    fn key(&self) -> &str {
+        match self {
+            Entry::Create { table, .. } | Entry::Kill { table, .. } => table,
+            Entry::Insert { column, .. } => column,
+            Entry::Link { link, .. } | Entry::Unlink { link, .. } => link,
+        }
+    }
+}
+
+impl Tag for Entry {
+    fn tag() -> String {
+        "entry".to_string()
+    }
+}
+
+impl Encode for Entry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Entry::Create { table, id } => {
+                0u8.encode(out);
+                table.encode(out);
+                id.encode(out);
+            }
+            Entry::Kill { table, id } => {
+                1u8.encode(out);
+                table.encode(out);
+                id.encode(out);
+            }
+            Entry::Insert { column, id, value } => {
+                2u8.encode(out);
+                column.encode(out);
+                id.encode(out);
+                value.encode(out);
+            }
+            Entry::Link { link, a, b } => {
+                3u8.encode(out);
+                link.encode(out);
+                a.encode(out);
+                b.encode(out);
+            }
+            Entry::Unlink { link, a, b } => {
+                4u8.encode(out);
+                link.encode(out);
+                a.encode(out);
+                b.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Entry {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        match u8::decode(input)? {
+            0 => Ok(Entry::Create { table: String::decode(input)?, id: RawId::decode(input)? }),
+            1 => Ok(Entry::Kill { table: String::decode(input)?, id: RawId::decode(input)? }),
+            2 => Ok(Entry::Insert { column: String::decode(input)?, id: RawId::decode(input)?, value: Vec::decode(input)? }),
+            3 => Ok(Entry::Link { link: String::decode(input)?, a: RawId::decode(input)?, b: RawId::decode(input)? }),
+            4 => Ok(Entry::Unlink { link: String::decode(input)?, a: RawId::decode(input)?, b: RawId::decode(input)? }),
+            _ => Err(SnapshotError::Corrupt("unknown journal entry").into()),
+        }
+    }
+}
+
+/// Makes mutations and appends each one to a journal, tagged with the current tick.
+///
+/// Entries are written as checksummed records as they happen, so a crash loses at most the entry being written.
+/// Each entry is written before its mutation is made, so a failed write leaves the world unchanged.
+/// Replay only reproduces the world if every mutation goes through the recorder.
+pub struct Recorder<W: Write> {
+    output: W,
+    tick: u64,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Writes the journal header and starts at tick 0.
+    pub fn new(output: W) -> Result<Self> {
+        Self::starting_at(output, 0)
+    }
+
+    /// Writes the journal header and starts at `tick`, to continue an earlier journal.
+    pub fn starting_at(mut output: W, tick: u64) -> Result<Self> {
+        output.write_all(MAGIC)?;
+        output.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        output.flush()?;
+        Ok(Recorder { output, tick })
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Ends the current tick. Later entries belong to the next one.
+    pub fn end_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn append(&mut self, entry: Entry) -> Result<()> {
+        write_records(&mut self.output, self.tick, &[entry])
+    }
+
+    pub fn create_entity<ID, E>(&mut self, entities: &mut E) -> Result<ID>
+    where
+        ID: IdType + Tag,
+        E: HasAllocator<ID>,
+    {
+        let id = entities.allocator().next_id();
+        self.append(Entry::Create { table: ID::tag(), id: RawId::of(id) })?;
+
+        let created = entities.create().entity;
+        debug_assert_eq!(id, created);
+        Ok(id)
+    }
+
+    pub fn kill<ID, E>(&mut self, entities: &mut E, id: ID) -> Result<()>
+    where
+        ID: IdType + Tag,
+        E: HasAllocator<ID>,
+    {
+        entities.allocator().try_verify(id)?;
+        self.append(Entry::Kill { table: ID::tag(), id: RawId::of(id) })?;
+
+        entities.allocator_mut().try_kill(id)
+    }
+
+    pub fn insert<ID, T, S, E>(&mut self, state: &mut S, entities: &E, id: ID, value: T) -> Result<()>
+    where
+        ID: IdType + Tag,
+        T: Encode,
+        S: Insert<ID, T>,
+        E: HasAllocator<ID>,
+    {
+        let verified = entities.verify(id).ok_or_else(|| Error::dead_entity(id))?;
+        self.append(Entry::Insert { column: insert_key::<ID, T>(), id: RawId::of(id), value: encoded(&value) })?;
+
+        state.insert(&verified, value);
+        Ok(())
+    }
+
+    /// Creates an entity and inserts `row` for it, as `Create::create` does.
+    ///
+    /// Both entries go out in one write, so a failed write leaves the world unchanged.
+    pub fn create<ID, T, S, E>(&mut self, state: &mut S, entities: &mut E, row: T) -> Result<ID>
+    where
+        ID: IdType + Tag,
+        T: Encode,
+        S: Insert<ID, T>,
+        E: HasAllocator<ID>,
+    {
+        let id = entities.allocator().next_id();
+        write_records(&mut self.output, self.tick, &[
+            Entry::Create { table: ID::tag(), id: RawId::of(id) },
+            Entry::Insert { column: insert_key::<ID, T>(), id: RawId::of(id), value: encoded(&row) },
+        ])?;
+
+        let created = entities.create();
+        debug_assert_eq!(id, created.entity);
+        state.insert(&created, row);
+        Ok(id)
+    }
+
+    pub fn link<A, B, S, E>(&mut self, state: &mut S, entities: &E, a: A, b: B) -> Result<()>
+    where
+        A: IdType + Tag,
+        B: IdType + Tag,
+        S: Link<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        let va = HasAllocator::<A>::allocator(entities).try_verify(a)?;
+        let vb = HasAllocator::<B>::allocator(entities).try_verify(b)?;
+        self.append(Entry::Link { link: link_key::<A, B>(), a: RawId::of(a), b: RawId::of(b) })?;
+
+        state.link(&va, &vb);
+        Ok(())
+    }
+
+    pub fn unlink<A, B, S, E>(&mut self, state: &mut S, entities: &E, a: A, b: B) -> Result<()>
+    where
+        A: IdType + Tag,
+        B: IdType + Tag,
+        S: Unlink<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        let va = HasAllocator::<A>::allocator(entities).try_verify(a)?;
+        let vb = HasAllocator::<B>::allocator(entities).try_verify(b)?;
+        self.append(Entry::Unlink { link: link_key::<A, B>(), a: RawId::of(a), b: RawId::of(b) })?;
+
+        state.unlink(&va, &vb);
+        Ok(())
+    }
+}
+
+/// The entries of a journal, each with the tick it was recorded in.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Journal {
+    pub entries: Vec<(u64, Entry)>,
+}
+
+impl Journal {
+    /// Reads every entry up to the first torn or corrupt record, which a crash can leave at the end.
+    pub fn read<R: Read>(mut input: R) -> Result<Self> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+
+        let (entries, _) = read_records(&bytes)?;
+        Ok(Journal { entries })
+    }
+
+    /// The tick of the last entry.
+    pub fn last_tick(&self) -> Option<u64> {
+        self.entries.last().map(|(tick, _)| *tick)
+    }
+}
+
+type Apply<S, E> = Box<dyn Fn(&mut S, &mut E, &Entry) -> Result<()>>;
+
+/// Replays journal entries into a world. Every table, insert and link in the journal must be registered.
+pub struct Replay<S, E> {
+    apply: HashMap<String, Apply<S, E>>,
+}
+
+impl<S, E> Default for Replay<S, E> {
+    fn default() -> Self {
+        Replay { apply: HashMap::new() }
+    }
+}
+
+impl<S: 'static, E: 'static> Replay<S, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replays creates and kills of `ID`. Created ids must match the journal's, which they do
+    /// when replaying from the world the journal started from.
+    pub fn entity<ID>(mut self) -> Self
+    where
+        ID: IdType + Tag + 'static,
+        E: HasAllocator<ID>,
+    {
+        self.apply.insert(ID::tag(), Box::new(|_, entities: &mut E, entry| {
+            match entry {
+                Entry::Create { id, .. } => {
+                    if RawId::of(entities.create().entity) != *id {
+                        return Err(SnapshotError::Corrupt("replayed id differs from the journal").into());
+                    }
+                }
+                Entry::Kill { id, .. } => entities.allocator_mut().try_kill(id.id::<ID>())?,
+                _ => return Err(SnapshotError::Corrupt("entry does not match its table").into()),
+            }
+            Ok(())
+        }));
+        self
+    }
+
+    pub fn insert<ID, T>(mut self) -> Self
+    where
+        ID: IdType + Tag + 'static,
+        T: Decode + 'static,
+        S: Insert<ID, T>,
+        E: HasAllocator<ID>,
+    {
+        self.apply.insert(insert_key::<ID, T>(), Box::new(|state: &mut S, entities: &mut E, entry| {
+            match entry {
+                Entry::Insert { id, value, .. } => {
+                    let id = entities.allocator().try_verify(id.id::<ID>())?;
+                    let mut value = &value[..];
+                    state.insert(&id, T::decode(&mut value)?);
+                    Ok(())
+                }
+                _ => Err(SnapshotError::Corrupt("entry does not match its column").into()),
+            }
+        }));
+        self
+    }
+
+    pub fn link<A, B>(mut self) -> Self
+    where
+        A: IdType + Tag + 'static,
+        B: IdType + Tag + 'static,
+        S: Link<A, B> + Unlink<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        self.apply.insert(link_key::<A, B>(), Box::new(|state: &mut S, entities: &mut E, entry| {
+            let (a, b, linked) = match entry {
+                Entry::Link { a, b, .. } => (a, b, true),
+                Entry::Unlink { a, b, .. } => (a, b, false),
+                _ => return Err(SnapshotError::Corrupt("entry does not match its link").into()),
+            };
+
+            HasAllocator::<A>::allocator(entities).try_verify(a.id::<A>())?;
+            HasAllocator::<B>::allocator(entities).try_verify(b.id::<B>())?;
+            let (a, b) = (VerifiedEntity::assert_valid(a.id::<A>()), VerifiedEntity::assert_valid(b.id::<B>()));
+
+            if linked {
+                state.link(&a, &b);
+            } else {
+                state.unlink(&a, &b);
+            }
+            Ok(())
+        }));
+        self
+    }
+
+    pub fn apply(&self, entry: &Entry, state: &mut S, entities: &mut E) -> Result<()> {
+        let apply = self.apply.get(entry.key())
+            .ok_or_else(|| SnapshotError::MissingSection(entry.key().to_string()))?;
+
+        apply(state, entities, entry)
+    }
+
+    /// Replays every entry recorded up to the end of `tick` into a fresh world.
+    pub fn to_tick(&self, journal: &Journal, tick: u64) -> Result<(S, E)>
+    where
+        S: Default,
+        E: Default,
+    {
+        let (mut state, mut entities) = (S::default(), E::default());
+
+        for (_, entry) in journal.entries.iter().take_while(|(t, _)| *t <= tick) {
+            self.apply(entry, &mut state, &mut entities)?;
+        }
+        Ok((state, entities))
+    }
+
+    /// The first tick at whose end `invariant` fails, or `None` if it holds at the last tick.
+    ///
+    /// Bisects by replaying from a fresh world, so assumes that once broken the invariant stays broken.
+    pub fn bisect<F>(&self, journal: &Journal, invariant: F) -> Result<Option<u64>>
+    where
+        S: Default,
+        E: Default,
+        F: Fn(&S, &E) -> bool,
+    {
+        let last = match journal.last_tick() {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+
+        let holds = |tick| -> Result<bool> {
+            let (state, entities) = self.to_tick(journal, tick)?;
+            Ok(invariant(&state, &entities))
+        };
+
+        if holds(last)? {
+            return Ok(None);
+        }
+
+        let (mut first, mut broken) = (0, last);
+        while first < broken {
+            let middle = first + (broken - first) / 2;
+            if holds(middle)? {
+                first = middle + 1;
+            } else {
+                broken = middle;
+            }
+        }
+        Ok(Some(broken))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+
+    id_type!(FleetId, snapshot);
+    id_type!(ShipId, snapshot);
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Allocators {
+        fleets: Allocator<FleetId>,
+        ships: Allocator<ShipId>,
+    }
+
+    entities!(Allocators, fleets: FleetId, ships: ShipId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        fleet_ships: IndexedVec<FleetId, EntitySet<ShipId>>,
+        ship_fleet: IndexedVec<ShipId, FleetId>,
+        ship_name: EntityMap<ShipId, String>,
+    }
+
+    link_to_many!(FleetId, fleet_ships, ShipId, ship_fleet);
+
+    impl Insert<FleetId, ()> for State {
+        fn insert(&mut self, id: &VerifiedEntity<FleetId>, _: ()) {
+            self.fleet_ships.insert(id, EntitySet::new());
+        }
+    }
+
+    impl Insert<ShipId, String> for State {
+        fn insert(&mut self, id: &VerifiedEntity<ShipId>, value: String) {
+            self.ship_name.insert(id, value);
+        }
+    }
+
+    fn replay() -> Replay<State, Allocators> {
+        Replay::new()
+            .entity::<FleetId>()
+            .entity::<ShipId>()
+            .insert::<FleetId, ()>()
+            .insert::<ShipId, String>()
+            .link::<FleetId, ShipId>()
+    }
+
+    /// Tick 0 founds a fleet, each later tick adds a ship to it, and tick 3 sinks the first ship.
+    fn record() -> (Vec<u8>, State, Allocators) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let mut recorder = Recorder::new(vec![]).unwrap();
+
+        let fleet: FleetId = recorder.create(&mut state, &mut entities, ()).unwrap();
+        let mut ships = vec![];
+        for name in &["Victory", "Beagle", "Endeavour"] {
+            recorder.end_tick();
+            let ship: ShipId = recorder.create(&mut state, &mut entities, name.to_string()).unwrap();
+            recorder.link(&mut state, &entities, fleet, ship).unwrap();
+            ships.push(ship);
+        }
+        recorder.unlink(&mut state, &entities, fleet, ships[0]).unwrap();
+        recorder.kill(&mut entities, ships[0]).unwrap();
+
+        (recorder.into_inner(), state, entities)
+    }
+
+    #[test]
+    fn replay_reproduces_every_tick() {
+        let (bytes, state, entities) = record();
+        let journal = Journal::read(&bytes[..]).unwrap();
+        assert_eq!(Some(3), journal.last_tick());
+
+        let (replayed, replayed_entities) = replay().to_tick(&journal, 3).unwrap();
+        assert_eq!(entities, replayed_entities);
+        assert_eq!(state.ship_name, replayed.ship_name);
+        assert_eq!(state.fleet_ships.values, replayed.fleet_ships.values);
+
+        let (early, early_entities) = replay().to_tick(&journal, 1).unwrap();
+        assert_eq!(1, early_entities.ships.ids().count());
+        assert_eq!(1, early.fleet_ships.values[0].len());
+    }
+
+    #[test]
+    fn bisect_finds_first_broken_tick() {
+        let (bytes, _, _) = record();
+        let journal = Journal::read(&bytes[..]).unwrap();
+
+        let fleet_has_fewer_than_two = |state: &State, _: &Allocators| {
+            state.fleet_ships.values.iter().all(|ships| ships.len() < 2)
+        };
+        assert_eq!(Some(2), replay().bisect(&journal, fleet_has_fewer_than_two).unwrap());
+        assert_eq!(None, replay().bisect(&journal, |_, _| true).unwrap());
+    }
+
+    #[test]
+    fn torn_or_corrupt_last_entry_is_dropped() {
+        let (mut bytes, _, _) = record();
+        let whole = Journal::read(&bytes[..]).unwrap();
+        let cut = Journal::read(&bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(whole.entries.len() - 1, cut.entries.len());
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(cut, Journal::read(&bytes[..]).unwrap());
+        assert!(matches!(Replay::<State, Allocators>::new().to_tick(&whole, 0), Err(Error::Snapshot(SnapshotError::MissingSection(_)))));
+    }
+
+    /// Accepts writes until `full` is set.
+    struct Disk {
+        full: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl Write for Disk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.full.get() {
+                Err(std::io::Error::other("disk full"))
+            } else {
+                Ok(buf.len())
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_write_changes_nothing() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let full = std::rc::Rc::new(std::cell::Cell::new(false));
+        let mut recorder = Recorder::new(Disk { full: full.clone() }).unwrap();
+        let fleet: FleetId = recorder.create(&mut state, &mut entities, ()).unwrap();
+        let ship: ShipId = recorder.create_entity(&mut entities).unwrap();
+
+        full.set(true);
+        assert!(recorder.create_entity::<ShipId, _>(&mut entities).is_err());
+        assert!(recorder.insert(&mut state, &entities, ship, "Beagle".to_string()).is_err());
+        assert!(recorder.link(&mut state, &entities, fleet, ship).is_err());
+        assert!(recorder.kill(&mut entities, ship).is_err());
+        assert!(recorder.create::<ShipId, _, _, _>(&mut state, &mut entities, "Resolution".to_string()).is_err());
+
+        assert_eq!(1, entities.ships.ids().count());
+        assert!(entities.ships.is_alive(ship));
+        assert!(state.ship_name.is_empty());
+        assert!(state.fleet_ships.values[0].is_empty());
+    }
+}
@@ -2,4 +2,33 @@
 pub mod entities;
 pub mod traits;
 pub mod storage;
-pub mod prelude;
\ No newline at end of file
+pub mod hierarchy;
+pub mod cascade;
+pub mod schema;
+pub mod construct;
+pub mod transaction;
+pub mod history;
+pub mod error;
+pub mod snapshot;
+pub mod migration;
+pub mod diff;
+pub mod replication;
+pub mod interest;
+pub mod checksum;
+pub mod journal;
+pub mod persistence;
+pub mod csv;
+pub mod prefab;
+pub mod dot;
+pub mod reflect;
+pub mod inspect;
+pub mod repl;
+pub mod prelude;
+#[cfg(feature = "serde")]
+pub mod serialize;
+
+pub use crate::error::Error;
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub use serde;
@@ -1,5 +1,28 @@
+/// Declares an id type. Written `id_type!(ShipId, snapshot)` it can also be encoded in snapshots,
+/// which tables and columns holding it need before they can be diffed or registered.
 #[macro_export]
 macro_rules! id_type {
+    ($type_name:ident, snapshot) => {
+        $crate::id_type!($type_name);
+
+        impl $crate::snapshot::Tag for $type_name {
+            fn tag() -> String {
+                stringify!($type_name).to_string()
+            }
+        }
+
+        impl $crate::snapshot::Encode for $type_name {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $crate::snapshot::encode_id(self, out)
+            }
+        }
+
+        impl $crate::snapshot::Decode for $type_name {
+            fn decode(input: &mut &[u8]) -> $crate::error::Result<Self> {
+                $crate::snapshot::decode_id(input)
+            }
+        }
+    };
     ($type_name:ident) => {
         #[derive(Debug, Copy, Clone, Eq, PartialEq, std::hash::Hash, Ord, PartialOrd)]
         pub struct $type_name(u32, $crate::entities::Generation);
@@ -21,12 +44,41 @@ macro_rules! id_type {
                 self.1
             }
         }
+
+        $crate::id_type_serde!($type_name);
+    };
+}
+
+/// Serializes ids as `(index, generation)` when the `serde` feature is enabled.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! id_type_serde {
+    ($type_name:ident) => {
+        impl $crate::serde::Serialize for $type_name {
+            fn serialize<S: $crate::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                $crate::serialize::serialize_id(self, serializer)
+            }
+        }
+
+        impl<'de> $crate::serde::Deserialize<'de> for $type_name {
+            fn deserialize<D: $crate::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+                $crate::serialize::deserialize_id(deserializer)
+            }
+        }
     };
 }
 
+#[cfg(not(feature = "serde"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! id_type_serde {
+    ($type_name:ident) => {};
+}
+
 #[macro_export]
 macro_rules! link {
-    ($id_a:ty, $field_a:ident, $id_b:ty, $field_b:ident) => {
+    ($id_a:ty, $field_a:ident, $id_b:ty, $field_b:ident $(, owns: $owns:ident)? $(, owned_by: $owned_by:ident)?) => {
         impl Get<$id_a, $id_b> for State {
             fn get(&self, id: &VerifiedEntity<$id_a>) -> Option<&$id_b> {
                 self.$field_a.get(id)
@@ -66,12 +118,50 @@ macro_rules! link {
                 self.insert(b, a.entity);
             }
         }
+
+        impl Unlink<$id_a, $id_b> for State {
+            fn unlink(&mut self, a: &VerifiedEntity<$id_a>, b: &VerifiedEntity<$id_b>) {
+                $crate::schema::RefColumn::unset(&mut self.$field_a, a.entity, b.entity);
+                $crate::schema::RefColumn::unset(&mut self.$field_b, b.entity, a.entity);
+            }
+        }
+
+        impl $crate::transaction::LinkRows<$id_a, $id_b> for State {
+            fn save_link_rows<E: 'static>(tx: &mut $crate::transaction::Transaction<'_, Self, E>, a: $id_a, b: $id_b) {
+                tx.save_row($crate::field!(State, $field_a), a);
+                tx.save_row($crate::field!(State, $field_b), b);
+            }
+        }
+
+        $(
+            impl Owns<$id_a, $id_b> for State {
+                const ON_DELETE: OnDelete = OnDelete::$owns;
+
+                fn owned(&self, id: &VerifiedEntity<$id_a>) -> Vec<$id_b> {
+                    self.$field_a.get(id).into_iter().copied().collect()
+                }
+
+                $crate::owns_release!($owns, $id_b, $field_b);
+            }
+        )?
+
+        $(
+            impl Owns<$id_b, $id_a> for State {
+                const ON_DELETE: OnDelete = OnDelete::$owned_by;
+
+                fn owned(&self, id: &VerifiedEntity<$id_b>) -> Vec<$id_a> {
+                    self.$field_b.get(id).into_iter().copied().collect()
+                }
+
+                $crate::owns_release!($owned_by, $id_a, $field_a);
+            }
+        )?
     }
 }
 
 #[macro_export]
 macro_rules! link_to_many {
-    ($id_a:ty, $field_a:ident, $id_b:ty, $field_b:ident) => {
+    ($id_a:ty, $field_a:ident, $id_b:ty, $field_b:ident $(, owns: $owns:ident)?) => {
         impl Insert<$id_a, $id_b> for State {
             fn insert(&mut self, id: &VerifiedEntity<$id_a>, value: $id_b) {
                 let locations = &mut self.$field_a[id];
@@ -98,5 +188,136 @@ macro_rules! link_to_many {
                 self.insert(b, a.entity);
             }
         }
+
+        impl Unlink<$id_a, $id_b> for State {
+            fn unlink(&mut self, a: &VerifiedEntity<$id_a>, b: &VerifiedEntity<$id_b>) {
+                $crate::schema::RefColumn::unset(&mut self.$field_a, a.entity, b.entity);
+                $crate::schema::RefColumn::unset(&mut self.$field_b, b.entity, a.entity);
+            }
+        }
+
+        impl $crate::transaction::LinkRows<$id_a, $id_b> for State {
+            fn save_link_rows<E: 'static>(tx: &mut $crate::transaction::Transaction<'_, Self, E>, a: $id_a, b: $id_b) {
+                tx.save_row($crate::field!(State, $field_a), a);
+                tx.save_row($crate::field!(State, $field_b), b);
+            }
+        }
+
+        $(
+            impl Owns<$id_a, $id_b> for State {
+                const ON_DELETE: OnDelete = OnDelete::$owns;
+
+                fn owned(&self, id: &VerifiedEntity<$id_a>) -> Vec<$id_b> {
+                    self.$field_a.get(id)
+                        .map(|values| values.iter().copied().collect())
+                        .unwrap_or_default()
+                }
+
+                $crate::owns_release!($owns, $id_b, $field_b);
+            }
+        )?
     }
-}
\ No newline at end of file
+}
+
+/// Declares every link of `State`, each written as `link(...);` or `link_to_many(...);` with the
+/// arguments of that macro, and implements `schema::Links` so ownership is built from the same declarations.
+#[macro_export]
+macro_rules! links {
+    (@owns $policy:ident, $visitor:ident, $owner:ty, $owned:ty) => {
+        $visitor.owns::<$owner, $owned>();
+    };
+    ($($kind:ident($id_a:ty, $field_a:ident, $id_b:ty, $field_b:ident $(, owns: $owns:ident)? $(, owned_by: $owned_by:ident)?);)*) => {
+        $(
+            $crate::$kind!($id_a, $field_a, $id_b, $field_b $(, owns: $owns)? $(, owned_by: $owned_by)?);
+        )*
+
+        impl<E> $crate::schema::Links<E> for State
+        where
+            $(E: $crate::traits::HasAllocator<$id_a> + $crate::traits::HasAllocator<$id_b>,)*
+        {
+            fn visit_links<V: $crate::schema::LinkVisitor<Self, E>>(visitor: &mut V) {
+                $(
+                    visitor.link::<$id_a, $id_b, _, _>($crate::field!(State, $field_a), $crate::field!(State, $field_b));
+                    $($crate::links!(@owns $owns, visitor, $id_a, $id_b);)?
+                    $($crate::links!(@owns $owned_by, visitor, $id_b, $id_a);)?
+                )*
+            }
+        }
+    }
+}
+
+/// Implements `Owns::release` for `SetNull` edges by nulling the owned entity's back reference.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! owns_release {
+    (SetNull, $id:ty, $field:ident) => {
+        fn release(&mut self, id: &VerifiedEntity<$id>) {
+            Nullable::set_null(&mut self.$field, id);
+        }
+    };
+    ($policy:ident, $id:ty, $field:ident) => {};
+}
+
+/// Implements `HasAllocator`, and so `Entities`, for a struct holding one `Allocator` per id type.
+#[macro_export]
+macro_rules! entities {
+    ($type_name:ty, $($field:ident: $id:ty),*) => {
+        $(
+            impl $crate::traits::HasAllocator<$id> for $type_name {
+                fn allocator(&self) -> &Allocator<$id> {
+                    &self.$field
+                }
+
+                fn allocator_mut(&mut self) -> &mut Allocator<$id> {
+                    &mut self.$field
+                }
+            }
+        )*
+    }
+}
+
+/// Creates a `schema::Field` named after a field of the given struct.
+#[macro_export]
+macro_rules! field {
+    ($type_name:ty, $field:ident) => {
+        $crate::schema::Field::<$type_name, _>::new(
+            stringify!($field),
+            |s: &$type_name| &s.$field,
+            |s: &mut $type_name| &mut s.$field,
+        )
+    }
+}
+
+/// Implements `transaction::InsertRows` for `$state`, naming the columns its `Insert<$id, $row>` writes.
+#[macro_export]
+macro_rules! insert_rows {
+    ($state:ty, $id:ty, $row:ty: $($field:ident),+ $(,)?) => {
+        impl $crate::transaction::InsertRows<$id, $row> for $state {
+            fn save_insert_rows<E: 'static>(tx: &mut $crate::transaction::Transaction<'_, Self, E>, id: $id) {
+                $(tx.save_row($crate::field!($state, $field), id);)+
+            }
+        }
+    };
+}
+
+/// Creates a `reflect::Registry` from the names of the allocator fields of `$entities` and the
+/// column fields of `$state`. Columns listed after `encoded:` can also be encoded, those
+/// after `references:` followed to the entities they refer to, and those after `text:` written as text.
+#[macro_export]
+macro_rules! registry {
+    (
+        $state:ty, $entities:ty;
+        tables: $($table:ident),*
+        $(; columns: $($column:ident),*)?
+        $(; encoded: $($encoded:ident),*)?
+        $(; references: $($reference:ident),*)?
+        $(; text: $($text:ident),*)?
+    ) => {
+        $crate::reflect::Registry::<$state, $entities>::new()
+            $(.table($crate::field!($entities, $table)))*
+            $($(.column($crate::field!($state, $column)))*)?
+            $($(.encoded($crate::field!($state, $encoded)))*)?
+            $($(.reference($crate::field!($state, $reference)))*)?
+            $($(.text($crate::field!($state, $text)))*)?
+    }
+}
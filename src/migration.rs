@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use crate::traits::IdType;
+use crate::entities::Allocator;
+use crate::storage::IndexedVec;
+use crate::snapshot::{SnapshotReader, SnapshotError, Encode, Decode};
+use crate::error::Result;
+
+type Step = Box<dyn Fn(&mut SnapshotReader) -> Result<()>>;
+
+/// Ordered steps that bring a snapshot from any older schema version up to the current one.
+///
+/// The step registered for version `n` turns a version `n` snapshot into a version `n + 1` one.
+pub struct Migrations {
+    current: u32,
+    steps: BTreeMap<u32, Step>,
+}
+
+impl Migrations {
+    pub fn new(current: u32) -> Self {
+        Migrations { current, steps: BTreeMap::new() }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    pub fn step<F>(mut self, from: u32, step: F) -> Self
+    where
+        F: Fn(&mut SnapshotReader) -> Result<()> + 'static,
+    {
+        self.steps.insert(from, Box::new(step));
+        self
+    }
+
+    /// Reads a snapshot and applies every step from its schema version to the current one.
+    pub fn load<R: Read>(&self, input: R) -> Result<SnapshotReader> {
+        let mut snapshot = SnapshotReader::new(input)?;
+        self.migrate(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    pub fn migrate(&self, snapshot: &mut SnapshotReader) -> Result<()> {
+        let version = snapshot.schema_version();
+        if version > self.current {
+            return Err(SnapshotError::NewerSchema(version).into());
+        }
+
+        for version in version..self.current {
+            let step = self.steps.get(&version).ok_or(SnapshotError::MissingMigration(version))?;
+            step(snapshot)?;
+            snapshot.set_schema_version(version + 1);
+        }
+        Ok(())
+    }
+}
+
+/// Adds a dense column with `default` for every index of the allocator section `allocator`.
+pub fn add_column<ID, T>(snapshot: &mut SnapshotReader, name: &str, allocator: &str, default: T) -> Result<()>
+where
+    ID: IdType + Encode + Decode,
+    T: Encode + Clone,
+{
+    let allocator = snapshot.read::<Allocator<ID>>(allocator)?;
+
+    let mut column = IndexedVec::<ID, T>::new();
+    column.values = vec![default; allocator.generations.len()];
+    snapshot.insert(name, &column);
+    Ok(())
+}
+
+/// Replaces the section `name` with `f` applied to it, possibly changing its type.
+pub fn transform<A, B, F>(snapshot: &mut SnapshotReader, name: &str, f: F) -> Result<()>
+where
+    A: Decode,
+    B: Encode,
+    F: FnOnce(A) -> B,
+{
+    let value = snapshot.read::<A>(name)?;
+    snapshot.insert(name, &f(value));
+    Ok(())
+}
+
+/// Replaces the dense column `name` with two columns made by `f` from each of its values.
+pub fn split<ID, T, A, B, F>(snapshot: &mut SnapshotReader, name: &str, a: &str, b: &str, f: F) -> Result<()>
+where
+    ID: IdType + Encode + Decode,
+    T: Decode,
+    A: Encode,
+    B: Encode,
+    F: Fn(T) -> (A, B),
+{
+    let column = snapshot.read::<IndexedVec<ID, T>>(name)?;
+
+    let mut column_a = IndexedVec::<ID, A>::new();
+    let mut column_b = IndexedVec::<ID, B>::new();
+    for value in column.values {
+        let (value_a, value_b) = f(value);
+        column_a.values.push(value_a);
+        column_b.values.push(value_b);
+    }
+
+    snapshot.remove(name)?;
+    snapshot.insert(a, &column_a);
+    snapshot.insert(b, &column_b);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::snapshot::SnapshotWriter;
+
+    id_type!(BodyId, snapshot);
+
+    fn version_1() -> Vec<u8> {
+        let mut bodies = Allocator::<BodyId>::new();
+        bodies.create_entity();
+        bodies.create_entity();
+
+        let mut body_mass = IndexedVec::<BodyId, f32>::new();
+        body_mass.values = vec![5.97, 0.07];
+        let mut body_info = IndexedVec::<BodyId, String>::new();
+        body_info.values = vec!["Earth:blue".to_string(), "Moon:grey".to_string()];
+
+        let mut writer = SnapshotWriter::new(vec![], 1).unwrap();
+        writer.write("bodies", &bodies).unwrap();
+        writer.write("body_mass", &body_mass).unwrap();
+        writer.write("body_info", &body_info).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn migrations() -> Migrations {
+        Migrations::new(4)
+            .step(1, |s| s.rename("body_mass", "body_mass_kg"))
+            .step(2, |s| {
+                transform(s, "body_mass_kg", |mass: IndexedVec<BodyId, f32>| {
+                    let mut kg = IndexedVec::<BodyId, f64>::new();
+                    kg.values = mass.values.into_iter().map(|m| m as f64 * 1e24).collect();
+                    kg
+                })?;
+                add_column::<BodyId, f32>(s, "orbit_angle_offset", "bodies", 0.0)
+            })
+            .step(3, |s| split::<BodyId, String, _, _, _>(s, "body_info", "body_name", "body_colour", |info| {
+                let mut parts = info.splitn(2, ':').map(str::to_string);
+                (parts.next().unwrap_or_default(), parts.next().unwrap_or_default())
+            }))
+    }
+
+    #[test]
+    fn load_applies_every_step() {
+        let snapshot = migrations().load(&version_1()[..]).unwrap();
+
+        assert_eq!(4, snapshot.schema_version());
+        assert_eq!(
+            vec!["bodies", "body_mass_kg", "orbit_angle_offset", "body_name", "body_colour"],
+            snapshot.names().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![0.0, 0.0], snapshot.read::<IndexedVec<BodyId, f32>>("orbit_angle_offset").unwrap().values);
+        assert_eq!(vec!["Earth", "Moon"], snapshot.read::<IndexedVec<BodyId, String>>("body_name").unwrap().values);
+        assert!(snapshot.read::<IndexedVec<BodyId, f64>>("body_mass_kg").is_ok());
+    }
+
+    #[test]
+    fn current_snapshot_is_unchanged() {
+        let mut snapshot = SnapshotReader::new(&version_1()[..]).unwrap();
+        Migrations::new(1).migrate(&mut snapshot).unwrap();
+
+        assert!(snapshot.contains("body_mass"));
+    }
+
+    #[test]
+    fn missing_or_newer_versions_are_errors() {
+        let gap = Migrations::new(3).step(2, |_| Ok(()));
+        assert!(matches!(gap.load(&version_1()[..]), Err(Error::Snapshot(SnapshotError::MissingMigration(1)))));
+
+        let older = Migrations::new(0);
+        assert!(matches!(older.load(&version_1()[..]), Err(Error::Snapshot(SnapshotError::NewerSchema(1)))));
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::checksum::fnv;
+use crate::diff::{Diff, Delta};
+use crate::journal::{Recorder, Replay, read_records, is_torn_tail};
+use crate::snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+use crate::error::{Error, Result};
+
+const SNAPSHOT_SCHEMA: u32 = 1;
+
+fn snapshot_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("snapshot-{:016}.recs", sequence))
+}
+
+fn log_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("log-{:016}.recj", sequence))
+}
+
+/// The sequence numbers of the files in `dir` named `{prefix}-{sequence}.{extension}`, in order.
+fn sequences(dir: &Path, prefix: &str, extension: &str) -> Result<Vec<u64>> {
+    let mut sequences = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let sequence = name.to_str()
+            .and_then(|name| name.strip_prefix(prefix)?.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(extension)?.strip_suffix('.'))
+            .and_then(|sequence| sequence.parse::<u64>().ok());
+
+        sequences.extend(sequence);
+    }
+
+    sequences.sort_unstable();
+    Ok(sequences)
+}
+
+/// Writes `bytes` with its checksum to a temporary file, syncs it and renames it into place.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temporary)?;
+        file.write_all(bytes)?;
+        file.write_all(&fnv(bytes).to_le_bytes())?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&temporary, path)?;
+    sync_dir(path)
+}
+
+/// Makes renames and new files in the directory durable. Not every platform can sync a directory.
+fn sync_dir(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file written by `write_atomically`, failing with `SnapshotError::Corrupt` unless its checksum matches.
+fn read_checked(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = fs::read(path)?;
+    if bytes.len() < 8 {
+        return Err(SnapshotError::Corrupt("snapshot file too short").into());
+    }
+
+    let hash = bytes.split_off(bytes.len() - 8);
+    if fnv(&bytes).to_le_bytes()[..] != hash[..] {
+        return Err(SnapshotError::Corrupt("snapshot checksum mismatch").into());
+    }
+    Ok(bytes)
+}
+
+/// Reads a snapshot file kept by `Persistence`, such as `snapshot-N.recs`, after checking its checksum.
+pub fn read_snapshot<P: AsRef<Path>>(path: P) -> Result<SnapshotReader> {
+    SnapshotReader::new(&read_checked(path.as_ref())?[..])
+}
+
+/// Keeps a world in a directory as full snapshots plus an append-only log of the mutations since.
+///
+/// Each checkpoint starts `log-N` and then writes `snapshot-N`. Recovery loads the newest snapshot that
+/// passes its checksum and replays every log from there on, dropping a torn record at the end of the
+/// newest log. A damaged record anywhere else is reported as corruption and left in place.
+/// The previous snapshot and log are kept, so a damaged newest snapshot costs nothing.
+///
+/// The log is written as each mutation is recorded and synced at the end of each tick, so a process
+/// crash loses at most the entry being written and a power loss at most the current tick.
+pub struct Persistence<S, E> {
+    dir: PathBuf,
+    diff: Diff<S, E>,
+    sequence: u64,
+    recorder: Recorder<File>,
+}
+
+impl<S: Default + 'static, E: Default + 'static> Persistence<S, E> {
+    /// Recovers the world kept in `dir`, or starts an empty one, then checkpoints it.
+    ///
+    /// Recording resumes in the tick after the last one the log has entries for, as the log
+    /// cannot tell whether that tick ended before the crash.
+    ///
+    /// `diff` lists what snapshots hold and `replay` the mutations the log can hold; they must
+    /// cover the same allocators and columns.
+    pub fn open<P: AsRef<Path>>(dir: P, diff: Diff<S, E>, replay: &Replay<S, E>) -> Result<(Self, S, E)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let logs = sequences(&dir, "log", "recj")?;
+        let (sequence, mut tick, mut state, mut entities) = match Self::load_snapshot(&dir, &diff)? {
+            Some(loaded) => loaded,
+            None if logs.is_empty() => (0, 0, S::default(), E::default()),
+            None => return Err(SnapshotError::Corrupt("no readable snapshot for the logs").into()),
+        };
+
+        for log in logs.iter().copied().filter(|log| *log >= sequence) {
+            let path = log_path(&dir, log);
+            let bytes = fs::read(&path)?;
+            let (entries, valid) = read_records(&bytes)?;
+
+            let newest = Some(&log) == logs.last();
+            if valid < bytes.len() && !(newest && is_torn_tail(&bytes[valid..])) {
+                return Err(SnapshotError::Corrupt("damaged record before the end of the log").into());
+            }
+
+            for (entry_tick, entry) in &entries {
+                replay.apply(entry, &mut state, &mut entities)?;
+                tick = tick.max(*entry_tick + 1);
+            }
+
+            if valid < bytes.len() {
+                OpenOptions::new().write(true).open(&path)?.set_len(valid as u64)?;
+            }
+        }
+
+        let sequence = logs.last().copied().unwrap_or(0).max(sequence) + 1;
+        let recorder = Self::start(&dir, &diff, sequence, tick, &state, &entities)?;
+        let persistence = Persistence { dir, diff, sequence, recorder };
+        persistence.remove_old()?;
+
+        Ok((persistence, state, entities))
+    }
+
+    /// The newest snapshot that can be read, with its sequence number and tick.
+    ///
+    /// Only a corrupt snapshot, such as one torn by a crash, falls back to the one before it.
+    /// Any other failure, like an unreadable file or a schema this build cannot load, is returned.
+    fn load_snapshot(dir: &Path, diff: &Diff<S, E>) -> Result<Option<(u64, u64, S, E)>> {
+        for sequence in sequences(dir, "snapshot", "recs")?.into_iter().rev() {
+            let load = || -> Result<(u64, S, E)> {
+                let bytes = read_checked(&snapshot_path(dir, sequence))?;
+                let reader = SnapshotReader::new(&bytes[..])?;
+
+                let (mut state, mut entities) = (S::default(), E::default());
+                diff.apply(&reader.read::<Delta>("world")?, &mut state, &mut entities)?;
+                Ok((reader.read("tick")?, state, entities))
+            };
+
+            match load() {
+                Ok((tick, state, entities)) => return Ok(Some((sequence, tick, state, entities))),
+                Err(Error::Snapshot(SnapshotError::Corrupt(_))) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Records mutations into the current log.
+    pub fn recorder(&mut self) -> &mut Recorder<File> {
+        &mut self.recorder
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.recorder.tick()
+    }
+
+    /// Syncs the log to disk and ends the tick.
+    pub fn end_tick(&mut self) -> Result<u64> {
+        self.recorder.output().sync_data()?;
+        Ok(self.recorder.end_tick())
+    }
+
+    /// Starts a new log, writes a full snapshot of the world and removes files older than the previous checkpoint.
+    ///
+    /// On failure the current log is kept, and recovery still starts from the previous snapshot.
+    pub fn checkpoint(&mut self, state: &S, entities: &E) -> Result<()> {
+        let sequence = self.sequence + 1;
+        self.recorder = Self::start(&self.dir, &self.diff, sequence, self.recorder.tick(), state, entities)?;
+        self.sequence = sequence;
+
+        self.remove_old()
+    }
+
+    /// Creates `log-{sequence}`, then writes `snapshot-{sequence}` and returns the log's recorder.
+    ///
+    /// The snapshot is renamed into place last, so recovery never loads a snapshot whose log is missing.
+    fn start(dir: &Path, diff: &Diff<S, E>, sequence: u64, tick: u64, state: &S, entities: &E) -> Result<Recorder<File>> {
+        let path = log_path(dir, sequence);
+        let recorder = Recorder::starting_at(File::create(&path)?, tick)?;
+        recorder.output().sync_all()?;
+        sync_dir(&path)?;
+
+        let world = diff.between((&S::default(), &E::default()), (state, entities));
+        let mut writer = SnapshotWriter::new(vec![], SNAPSHOT_SCHEMA)?;
+        writer.write("tick", &tick)?;
+        writer.write("world", &world)?;
+        write_atomically(&snapshot_path(dir, sequence), &writer.finish()?)?;
+
+        Ok(recorder)
+    }
+
+    fn remove_old(&self) -> Result<()> {
+        for old in sequences(&self.dir, "snapshot", "recs")?.into_iter().filter(|old| *old + 1 < self.sequence) {
+            fs::remove_file(snapshot_path(&self.dir, old))?;
+        }
+        for old in sequences(&self.dir, "log", "recj")?.into_iter().filter(|old| *old + 1 < self.sequence) {
+            fs::remove_file(log_path(&self.dir, old))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::entities::{Allocator, VerifiedEntity};
+    use crate::storage::*;
+
+    id_type!(ShipId, snapshot);
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Allocators {
+        ships: Allocator<ShipId>,
+    }
+
+    entities!(Allocators, ships: ShipId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        ship_name: EntityMap<ShipId, String>,
+    }
+
+    impl Insert<ShipId, String> for State {
+        fn insert(&mut self, id: &VerifiedEntity<ShipId>, value: String) {
+            self.ship_name.insert(id, value);
+        }
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("relational_ecs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path) -> (Persistence<State, Allocators>, State, Allocators) {
+        let diff = Diff::new(registry!(State, Allocators; tables: ships; encoded: ship_name));
+        let replay = Replay::new()
+            .entity::<ShipId>()
+            .insert::<ShipId, String>();
+
+        Persistence::open(dir, diff, &replay).unwrap()
+    }
+
+    fn spawn(persistence: &mut Persistence<State, Allocators>, state: &mut State, entities: &mut Allocators, name: &str) -> ShipId {
+        persistence.recorder().create(state, entities, name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn recovers_snapshot_and_log_tail() {
+        let dir = dir("recover");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        spawn(&mut persistence, &mut state, &mut entities, "Victory");
+        persistence.end_tick().unwrap();
+        persistence.checkpoint(&state, &entities).unwrap();
+        let beagle = spawn(&mut persistence, &mut state, &mut entities, "Beagle");
+        persistence.recorder().kill(&mut entities, beagle).unwrap();
+        spawn(&mut persistence, &mut state, &mut entities, "Endeavour");
+        persistence.end_tick().unwrap();
+        drop(persistence);
+
+        let (recovered, recovered_state, recovered_entities) = open(&dir);
+        assert_eq!(entities, recovered_entities);
+        assert_eq!(state.ship_name, recovered_state.ship_name);
+        assert_eq!(2, recovered.tick());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_write_is_truncated() {
+        let dir = dir("torn");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        spawn(&mut persistence, &mut state, &mut entities, "Victory");
+        let sequence = persistence.sequence;
+        drop(persistence);
+
+        let log = log_path(&dir, sequence);
+        let whole = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(&[7, 0, 0]).unwrap();
+
+        let (persistence, recovered_state, _) = open(&dir);
+        assert_eq!(state.ship_name, recovered_state.ship_name);
+        assert_eq!(whole, fs::metadata(&log).unwrap().len());
+        drop(persistence);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_record_inside_a_log_is_an_error() {
+        let dir = dir("damaged-log");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        for name in &["Victory", "Beagle", "Endeavour"] {
+            spawn(&mut persistence, &mut state, &mut entities, name);
+        }
+        let log = log_path(&dir, persistence.sequence);
+        drop(persistence);
+
+        let mut bytes = fs::read(&log).unwrap();
+        bytes[6 + 16] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+
+        let diff = Diff::new(registry!(State, Allocators; tables: ships; encoded: ship_name));
+        let replay = Replay::new().entity::<ShipId>().insert::<ShipId, String>();
+        let result = Persistence::<State, Allocators>::open(&dir, diff, &replay);
+        assert!(matches!(result, Err(Error::Snapshot(SnapshotError::Corrupt(_)))));
+        assert_eq!(bytes, fs::read(&log).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_snapshot_falls_back_to_the_previous_one() {
+        let dir = dir("damaged");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        spawn(&mut persistence, &mut state, &mut entities, "Victory");
+        persistence.checkpoint(&state, &entities).unwrap();
+        spawn(&mut persistence, &mut state, &mut entities, "Beagle");
+        let newest = snapshot_path(&dir, persistence.sequence);
+        drop(persistence);
+
+        let mut bytes = fs::read(&newest).unwrap();
+        bytes[10] ^= 1;
+        fs::write(&newest, bytes).unwrap();
+
+        let (_, recovered_state, recovered_entities) = open(&dir);
+        assert_eq!(entities, recovered_entities);
+        assert_eq!(state.ship_name, recovered_state.ship_name);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_snapshot_checks_the_checksum() {
+        let dir = dir("read");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        spawn(&mut persistence, &mut state, &mut entities, "Victory");
+        persistence.end_tick().unwrap();
+        persistence.checkpoint(&state, &entities).unwrap();
+        let newest = snapshot_path(&dir, persistence.sequence);
+        drop(persistence);
+
+        let reader = read_snapshot(&newest).unwrap();
+        assert_eq!(1, reader.read::<u64>("tick").unwrap());
+        assert!(reader.names().any(|name| name == "world"));
+
+        let mut bytes = fs::read(&newest).unwrap();
+        bytes[10] ^= 1;
+        fs::write(&newest, bytes).unwrap();
+        assert!(matches!(read_snapshot(&newest), Err(Error::Snapshot(SnapshotError::Corrupt(_)))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_snapshot_that_is_not_corrupt_is_an_error() {
+        let dir = dir("newer");
+        let (mut persistence, mut state, mut entities) = open(&dir);
+
+        spawn(&mut persistence, &mut state, &mut entities, "Victory");
+        persistence.checkpoint(&state, &entities).unwrap();
+        let newest = snapshot_path(&dir, persistence.sequence);
+        drop(persistence);
+
+        let mut bytes = read_checked(&newest).unwrap();
+        bytes[4] = 99;
+        write_atomically(&newest, &bytes).unwrap();
+
+        let diff = Diff::new(registry!(State, Allocators; tables: ships; encoded: ship_name));
+        let replay = Replay::new().entity::<ShipId>().insert::<ShipId, String>();
+        let result = Persistence::<State, Allocators>::open(&dir, diff, &replay);
+        assert!(matches!(result, Err(Error::Snapshot(SnapshotError::UnsupportedFormat(99)))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use crate::traits::IdType;
+use crate::entities::RawId;
+use crate::construct::Builder;
+use crate::error::Result;
+
+/// Why a prefab could not be instantiated, nested in `Error::Prefab`. Nothing it created is left behind.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PrefabError {
+    /// No part of the prefab is named by the reference.
+    UnknownRef(String),
+    /// Two parts share a name.
+    DuplicateRef(String),
+    /// The reference names an entity of another id type.
+    WrongRefType { name: String, expected: &'static str },
+}
+
+impl fmt::Display for PrefabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrefabError::UnknownRef(name) => write!(f, "unknown prefab reference: {}", name),
+            PrefabError::DuplicateRef(name) => write!(f, "prefab reference defined twice: {}", name),
+            PrefabError::WrongRefType { name, expected } => write!(f, "prefab reference {} is not a {}", name, expected),
+        }
+    }
+}
+
+impl std::error::Error for PrefabError {}
+
+/// A symbolic name for one part of a prefab, such as `"earth"`, written as a plain string in data files.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct Ref(pub String);
+
+impl From<&str> for Ref {
+    fn from(name: &str) -> Self {
+        Ref(name.to_string())
+    }
+}
+
+/// The ids given to the named parts of a prefab.
+#[derive(Debug, Clone, Default)]
+pub struct Refs {
+    ids: HashMap<String, (TypeId, RawId)>,
+}
+
+impl Refs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn define<ID: IdType + 'static>(&mut self, name: &Ref, id: ID) -> Result<()> {
+        if self.ids.contains_key(&name.0) {
+            return Err(PrefabError::DuplicateRef(name.0.clone()).into());
+        }
+
+        self.ids.insert(name.0.clone(), (TypeId::of::<ID>(), RawId::of(id)));
+        Ok(())
+    }
+
+    pub fn get<ID: IdType + 'static>(&self, name: &Ref) -> Result<ID> {
+        match self.ids.get(&name.0) {
+            Some((table, id)) if *table == TypeId::of::<ID>() => Ok(id.id()),
+            Some(_) => Err(PrefabError::WrongRefType { name: name.0.clone(), expected: type_name::<ID>() }.into()),
+            None => Err(PrefabError::UnknownRef(name.0.clone()).into()),
+        }
+    }
+}
+
+/// A description of entities, usually deserialized from a data file, that can be instantiated in a world.
+///
+/// Instantiation runs in two passes so parts can refer to parts described after them:
+/// `create` makes every entity and names the ones that are referred to, then `link` resolves references.
+pub trait Prefab<S, E> {
+    fn create(&self, builder: &mut Builder<'_, S, E>, refs: &mut Refs) -> Result<()>;
+
+    fn link(&self, _builder: &mut Builder<'_, S, E>, _refs: &Refs) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S, E, P: Prefab<S, E>> Prefab<S, E> for Vec<P> {
+    fn create(&self, builder: &mut Builder<'_, S, E>, refs: &mut Refs) -> Result<()> {
+        self.iter().try_for_each(|part| part.create(builder, refs))
+    }
+
+    fn link(&self, builder: &mut Builder<'_, S, E>, refs: &Refs) -> Result<()> {
+        self.iter().try_for_each(|part| part.link(builder, refs))
+    }
+}
+
+impl<S, E, P: Prefab<S, E>> Prefab<S, E> for Option<P> {
+    fn create(&self, builder: &mut Builder<'_, S, E>, refs: &mut Refs) -> Result<()> {
+        self.iter().try_for_each(|part| part.create(builder, refs))
+    }
+
+    fn link(&self, builder: &mut Builder<'_, S, E>, refs: &Refs) -> Result<()> {
+        self.iter().try_for_each(|part| part.link(builder, refs))
+    }
+}
+
+/// Instantiates `prefab`, returning the ids of its named parts. On error every change is rolled back.
+pub fn instantiate<S: 'static, E: 'static, P: Prefab<S, E>>(prefab: &P, state: &mut S, entities: &mut E) -> Result<Refs> {
+    let mut builder = Builder::new(state, entities);
+    let mut refs = Refs::new();
+
+    prefab.create(&mut builder, &mut refs)?;
+    prefab.link(&mut builder, &refs)?;
+
+    builder.commit();
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::traits::*;
+    use crate::entities::{Allocator, VerifiedEntity};
+    use crate::storage::*;
+
+    id_type!(SystemId);
+    id_type!(BodyId);
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Allocators {
+        systems: Allocator<SystemId>,
+        bodies: Allocator<BodyId>,
+    }
+
+    entities!(Allocators, systems: SystemId, bodies: BodyId);
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    struct Orbit(BodyId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        system_name: IndexedVec<SystemId, String>,
+        system_bodies: IndexedVec<SystemId, EntitySet<BodyId>>,
+        body_name: IndexedVec<BodyId, String>,
+        body_system: IndexedVec<BodyId, SystemId>,
+        body_orbit: EntityMap<BodyId, Orbit>,
+    }
+
+    link_to_many!(SystemId, system_bodies, BodyId, body_system);
+
+    impl Insert<SystemId, String> for State {
+        fn insert(&mut self, id: &VerifiedEntity<SystemId>, name: String) {
+            self.system_name.insert(id, name);
+            self.system_bodies.insert(id, EntitySet::new());
+        }
+    }
+
+    impl Insert<BodyId, String> for State {
+        fn insert(&mut self, id: &VerifiedEntity<BodyId>, name: String) {
+            self.body_name.insert(id, name);
+        }
+    }
+
+    insert_rows!(State, SystemId, String: system_name, system_bodies);
+    insert_rows!(State, BodyId, String: body_name);
+
+    #[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+    struct SystemPrefab {
+        name: String,
+        bodies: Vec<BodyPrefab>,
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+    struct BodyPrefab {
+        key: Ref,
+        name: String,
+        orbits: Option<Ref>,
+    }
+
+    impl Prefab<State, Allocators> for SystemPrefab {
+        fn create(&self, builder: &mut Builder<'_, State, Allocators>, refs: &mut Refs) -> Result<()> {
+            let system: SystemId = builder.create(self.name.clone());
+            for body in &self.bodies {
+                let id: BodyId = builder.create_and_link(system, body.name.clone())?;
+                refs.define(&body.key, id)?;
+            }
+            Ok(())
+        }
+
+        fn link(&self, builder: &mut Builder<'_, State, Allocators>, refs: &Refs) -> Result<()> {
+            for body in &self.bodies {
+                if let Some(orbits) = &body.orbits {
+                    let orbit = Orbit(refs.get(orbits)?);
+                    builder.insert(field!(State, body_orbit), refs.get::<BodyId>(&body.key)?, orbit)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn body(key: &str, orbits: Option<&str>) -> BodyPrefab {
+        BodyPrefab { key: key.into(), name: key.to_string(), orbits: orbits.map(Ref::from) }
+    }
+
+    fn assert_moon_orbits_earth(state: &State, refs: &Refs) {
+        let earth: BodyId = refs.get(&"earth".into()).unwrap();
+        let moon = VerifiedEntity::assert_valid(refs.get::<BodyId>(&"moon".into()).unwrap());
+
+        assert_eq!(Some(&Orbit(earth)), state.body_orbit.get(&moon));
+        assert_eq!(2, state.system_bodies.values[0].len());
+    }
+
+    #[test]
+    fn references_resolve_in_any_order() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let sol = SystemPrefab { name: "Sol".to_string(), bodies: vec![body("moon", Some("earth")), body("earth", None)] };
+
+        let refs = instantiate(&sol, &mut state, &mut entities).unwrap();
+        assert_moon_orbits_earth(&state, &refs);
+    }
+
+    #[test]
+    fn unknown_reference_leaves_no_trace() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let bodies = vec![body("moon", Some("earth")), body("earth", None), body("mars", Some("terra"))];
+        let sol = SystemPrefab { name: "Sol".to_string(), bodies };
+
+        assert!(matches!(instantiate(&sol, &mut state, &mut entities), Err(Error::Prefab(PrefabError::UnknownRef(name))) if name == "terra"));
+        assert_eq!(Allocators::default(), entities);
+        assert!(state.system_name.values.is_empty());
+        assert!(state.system_bodies.values.is_empty());
+        assert!(state.body_name.values.is_empty());
+        assert!(state.body_system.values.is_empty());
+        assert!(state.body_orbit.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn instantiates_ron() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let sol: Vec<SystemPrefab> = ron::from_str(r#"[
+            (
+                name: "Sol",
+                bodies: [
+                    (key: "earth", name: "Earth", orbits: None),
+                    (key: "moon", name: "Moon", orbits: Some("earth")),
+                ],
+            ),
+        ]"#).unwrap();
+
+        let refs = instantiate(&sol, &mut state, &mut entities).unwrap();
+        assert_moon_orbits_earth(&state, &refs);
+        assert_eq!(vec!["Earth", "Moon"], state.body_name.values);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn instantiates_toml() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let sol: SystemPrefab = toml::from_str(r#"
+            name = "Sol"
+
+            [[bodies]]
+            key = "moon"
+            name = "Moon"
+            orbits = "earth"
+
+            [[bodies]]
+            key = "earth"
+            name = "Earth"
+        "#).unwrap();
+
+        let refs = instantiate(&sol, &mut state, &mut entities).unwrap();
+        assert_moon_orbits_earth(&state, &refs);
+    }
+}
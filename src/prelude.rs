@@ -2,3 +2,9 @@ pub use crate::id_type;
 pub use crate::entities::{VerifiedEntity, Allocator};
 pub use crate::traits::*;
 pub use crate::storage::*;
+pub use crate::cascade::{Owns, OnDelete, Nullable, Ownership};
+pub use crate::schema::{Schema, Field};
+pub use crate::reflect::Registry;
+pub use crate::construct::{Builder, ConstructError};
+pub use crate::transaction::Transaction;
+pub use crate::history::History;
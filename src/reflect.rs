@@ -0,0 +1,672 @@
+use std::any::{type_name, TypeId};
+use std::fmt::{Debug, Display};
+use std::marker::PhantomData;
+use crate::traits::{IdType, HasAllocator};
+use crate::entities::{Allocator, RawId, VerifiedEntity};
+use crate::cascade::Owns;
+use crate::schema::{Field, Column, RefColumn, Cardinality, Links, LinkVisitor};
+use crate::diff::{self, Diffable, Change};
+use crate::snapshot::{Tag, Encode, Decode, SnapshotError};
+use crate::error::Result;
+use crate::storage::*;
+
+/// How a column stores its rows.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Storage {
+    IndexedVec,
+    EntityMap,
+    EntitySet,
+    OrderedMap,
+    OrderedSet,
+}
+
+/// Storage whose row for an entity can be read without knowing the column's concrete type.
+///
+/// Sets have a `()` row for each member.
+pub trait Reflect<ID: IdType> {
+    type Value: Debug + 'static;
+    const STORAGE: Storage;
+
+    fn row(&self, id: ID) -> Option<&Self::Value>;
+}
+
+impl<ID: IdType, T: Debug + 'static> Reflect<ID> for IndexedVec<ID, T> {
+    type Value = T;
+    const STORAGE: Storage = Storage::IndexedVec;
+
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(id.index())
+    }
+}
+
+impl<ID: IdType, T: Debug + 'static> Reflect<ID> for EntityMap<ID, T> {
+    type Value = T;
+    const STORAGE: Storage = Storage::EntityMap;
+
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(&id)
+    }
+}
+
+impl<ID: IdType> Reflect<ID> for EntitySet<ID> {
+    type Value = ();
+    const STORAGE: Storage = Storage::EntitySet;
+
+    fn row(&self, id: ID) -> Option<&()> {
+        if self.contains(&id) { Some(&()) } else { None }
+    }
+}
+
+impl<ID: IdType, T: Debug + 'static> Reflect<ID> for OrderedMap<ID, T> {
+    type Value = T;
+    const STORAGE: Storage = Storage::OrderedMap;
+
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(&id)
+    }
+}
+
+impl<ID: IdType> Reflect<ID> for OrderedSet<ID> {
+    type Value = ();
+    const STORAGE: Storage = Storage::OrderedSet;
+
+    fn row(&self, id: ID) -> Option<&()> {
+        if self.contains(&id) { Some(&()) } else { None }
+    }
+}
+
+/// A registered allocator.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TableInfo {
+    /// The name of the allocator's field.
+    pub name: &'static str,
+    pub id_type: &'static str,
+}
+
+/// A registered column.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ColumnInfo {
+    pub name: &'static str,
+    pub id_type: &'static str,
+    pub value_type: &'static str,
+    pub storage: Storage,
+    /// Whether rows can be read with `Registry::encode`.
+    pub encoded: bool,
+    /// The id type the column refers to, if it was registered with `reference`.
+    pub target: Option<&'static str>,
+    /// How many entities each row refers to, for reference columns.
+    pub cardinality: Option<Cardinality>,
+    /// Whether the column refers to each entity's owner, declared with `owns:` or `owned_by:` in `links!`.
+    pub owner: bool,
+}
+
+pub(crate) trait AnyTable<E> {
+    fn info(&self) -> TableInfo;
+    fn id_type(&self) -> TypeId;
+    fn living(&self, entities: &E) -> Vec<RawId>;
+    fn is_alive(&self, entities: &E, id: RawId) -> bool;
+    /// How many indices have been handed out, living or dead.
+    fn len(&self, entities: &E) -> usize;
+    /// The id at `index` with its current generation, whether or not it is alive.
+    fn id_at(&self, entities: &E, index: usize) -> RawId;
+    fn alive_at(&self, entities: &E, index: usize) -> bool;
+    /// The dead list, whose order decides which index is reused next.
+    fn dead(&self, entities: &E) -> Vec<u32>;
+    fn diff(&self, old: &E, new: &E) -> Option<Change>;
+    fn check(&self, entities: &E, change: &Change) -> Result<()>;
+    fn patch(&self, entities: &mut E, change: &Change) -> Result<()>;
+    /// Makes `id` alive or dead without allocating, for mirrors that only hold some entities.
+    fn set_alive(&self, entities: &mut E, id: RawId, alive: bool);
+}
+
+impl<E, ID> AnyTable<E> for Field<E, Allocator<ID>>
+where
+    ID: IdType + Tag + Encode + Decode + 'static,
+{
+    fn info(&self) -> TableInfo {
+        TableInfo { name: self.name, id_type: type_name::<ID>() }
+    }
+
+    fn id_type(&self) -> TypeId {
+        TypeId::of::<ID>()
+    }
+
+    fn living(&self, entities: &E) -> Vec<RawId> {
+        (self.get)(entities).ids().map(|id| RawId::of(id.entity)).collect()
+    }
+
+    fn is_alive(&self, entities: &E, id: RawId) -> bool {
+        (self.get)(entities).is_alive(id.id::<ID>())
+    }
+
+    fn len(&self, entities: &E) -> usize {
+        (self.get)(entities).generations.len()
+    }
+
+    fn id_at(&self, entities: &E, index: usize) -> RawId {
+        RawId { index, generation: (self.get)(entities).generations[index] }
+    }
+
+    fn alive_at(&self, entities: &E, index: usize) -> bool {
+        (self.get)(entities).living[index].is_some()
+    }
+
+    fn dead(&self, entities: &E) -> Vec<u32> {
+        (self.get)(entities).dead.iter().map(|index| *index as u32).collect()
+    }
+
+    fn diff(&self, old: &E, new: &E) -> Option<Change> {
+        diff::change(self.name, (self.get)(old), (self.get)(new))
+    }
+
+    fn check(&self, entities: &E, change: &Change) -> Result<()> {
+        diff::check((self.get)(entities), change)
+    }
+
+    fn patch(&self, entities: &mut E, change: &Change) -> Result<()> {
+        diff::patch((self.get_mut)(entities), change)
+    }
+
+    fn set_alive(&self, entities: &mut E, id: RawId, alive: bool) {
+        (self.get_mut)(entities).set_alive(id.id::<ID>(), alive);
+    }
+}
+
+pub(crate) trait AnyColumn<S> {
+    fn info(&self) -> ColumnInfo;
+    fn id_type(&self) -> TypeId;
+    fn target(&self) -> Option<TypeId>;
+    fn value<'a>(&self, state: &'a S, id: RawId) -> Option<&'a dyn Debug>;
+    fn encode(&self, state: &S, id: RawId) -> Option<Vec<u8>>;
+    /// The change to the whole column from `old` to `new`, for encoded columns.
+    fn diff(&self, old: &S, new: &S) -> Option<Change>;
+    /// Fails where `patch` would, without changing `state`.
+    fn check(&self, state: &S, change: &Change) -> Result<()>;
+    fn patch(&self, state: &mut S, change: &Change) -> Result<()>;
+    fn refs(&self, state: &S, id: RawId) -> Vec<RawId>;
+    /// Whether every living entity is expected to have a row.
+    fn required(&self, state: &S) -> bool;
+    fn has_row(&self, state: &S, id: RawId) -> bool;
+    /// Removes rows of dead entities and references to dead entities, where the storage can drop them.
+    fn sweep(&self, state: &mut S, key_alive: &dyn Fn(RawId) -> bool, target_alive: &dyn Fn(RawId) -> bool);
+    fn set_owner(&mut self);
+}
+
+type EncodeRow<C, ID> = fn(&C, ID) -> Option<Vec<u8>>;
+type DiffColumn<C> = fn(&str, &C, &C) -> Option<Change>;
+type CheckColumn<C> = fn(&C, &Change) -> Result<()>;
+type PatchColumn<C> = fn(&mut C, &Change) -> Result<()>;
+type Text<S> = Box<dyn Fn(&S, RawId) -> Option<String>>;
+type RefsOf<C, ID> = fn(&C, ID) -> Vec<RawId>;
+type RetainRefs<C> = fn(&mut C, &dyn Fn(RawId) -> bool);
+
+/// The id type a reference column refers to and how to read its ids.
+struct Target<C, ID> {
+    id_type: TypeId,
+    name: &'static str,
+    cardinality: Cardinality,
+    refs: RefsOf<C, ID>,
+    retain: RetainRefs<C>,
+}
+
+impl<C, ID: IdType> Target<C, ID> {
+    fn of<T: IdType + 'static>() -> Self
+    where
+        C: RefColumn<ID, T>,
+    {
+        Target {
+            id_type: TypeId::of::<T>(),
+            name: type_name::<T>(),
+            cardinality: C::CARDINALITY,
+            refs: refs_of::<ID, T, C>,
+            retain: retain_refs::<ID, T, C>,
+        }
+    }
+}
+
+/// How to encode a column's rows and diff and patch the whole column.
+struct Encoding<C, ID> {
+    row: EncodeRow<C, ID>,
+    diff: DiffColumn<C>,
+    check: CheckColumn<C>,
+    patch: PatchColumn<C>,
+}
+
+impl<C, ID: IdType> Encoding<C, ID> {
+    fn of() -> Self
+    where
+        C: Reflect<ID> + Diffable,
+        C::Value: Encode,
+    {
+        Encoding {
+            row: encode_row::<ID, C>,
+            diff: diff::change::<C>,
+            check: diff::check::<C>,
+            patch: diff::patch::<C>,
+        }
+    }
+}
+
+struct ReflectField<S, ID, C> {
+    field: Field<S, C>,
+    encoding: Option<Encoding<C, ID>>,
+    target: Option<Target<C, ID>>,
+    owner: bool,
+    marker: PhantomData<ID>,
+}
+
+impl<S, ID, C> ReflectField<S, ID, C> {
+    fn new(field: Field<S, C>, encoding: Option<Encoding<C, ID>>, target: Option<Target<C, ID>>) -> Self {
+        ReflectField { field, encoding, target, owner: false, marker: PhantomData }
+    }
+}
+
+fn encode_row<ID: IdType, C: Reflect<ID>>(column: &C, id: ID) -> Option<Vec<u8>>
+where
+    C::Value: Encode,
+{
+    column.row(id).map(diff::encoded)
+}
+
+fn refs_of<ID: IdType, T: IdType, C: RefColumn<ID, T>>(column: &C, id: ID) -> Vec<RawId> {
+    column.refs(id).into_iter().map(RawId::of).collect()
+}
+
+fn retain_refs<ID: IdType, T: IdType, C: RefColumn<ID, T>>(column: &mut C, is_alive: &dyn Fn(RawId) -> bool) {
+    column.retain_refs(&|id| is_alive(RawId::of(id)));
+}
+
+impl<S, ID, C> AnyColumn<S> for ReflectField<S, ID, C>
+where
+    ID: IdType + 'static,
+    C: Reflect<ID> + Column<ID> + 'static,
+{
+    fn info(&self) -> ColumnInfo {
+        ColumnInfo {
+            name: self.field.name,
+            id_type: type_name::<ID>(),
+            value_type: type_name::<C::Value>(),
+            storage: C::STORAGE,
+            encoded: self.encoding.is_some(),
+            target: self.target.as_ref().map(|target| target.name),
+            cardinality: self.target.as_ref().map(|target| target.cardinality),
+            owner: self.owner,
+        }
+    }
+
+    fn id_type(&self) -> TypeId {
+        TypeId::of::<ID>()
+    }
+
+    fn target(&self) -> Option<TypeId> {
+        self.target.as_ref().map(|target| target.id_type)
+    }
+
+    fn value<'a>(&self, state: &'a S, id: RawId) -> Option<&'a dyn Debug> {
+        (self.field.get)(state).row(id.id()).map(|value| value as &dyn Debug)
+    }
+
+    fn encode(&self, state: &S, id: RawId) -> Option<Vec<u8>> {
+        self.encoding.as_ref().and_then(|encoding| (encoding.row)((self.field.get)(state), id.id()))
+    }
+
+    fn diff(&self, old: &S, new: &S) -> Option<Change> {
+        let encoding = self.encoding.as_ref()?;
+        (encoding.diff)(self.field.name, (self.field.get)(old), (self.field.get)(new))
+    }
+
+    fn check(&self, state: &S, change: &Change) -> Result<()> {
+        match &self.encoding {
+            Some(encoding) => (encoding.check)((self.field.get)(state), change),
+            None => Err(SnapshotError::MissingSection(change.name.clone()).into()),
+        }
+    }
+
+    fn patch(&self, state: &mut S, change: &Change) -> Result<()> {
+        match &self.encoding {
+            Some(encoding) => (encoding.patch)((self.field.get_mut)(state), change),
+            None => Err(SnapshotError::MissingSection(change.name.clone()).into()),
+        }
+    }
+
+    fn refs(&self, state: &S, id: RawId) -> Vec<RawId> {
+        match &self.target {
+            Some(target) => (target.refs)((self.field.get)(state), id.id()),
+            None => vec![],
+        }
+    }
+
+    fn required(&self, state: &S) -> bool {
+        (self.field.get)(state).required()
+    }
+
+    fn has_row(&self, state: &S, id: RawId) -> bool {
+        (self.field.get)(state).has_row(id.id())
+    }
+
+    fn sweep(&self, state: &mut S, key_alive: &dyn Fn(RawId) -> bool, target_alive: &dyn Fn(RawId) -> bool) {
+        let column = (self.field.get_mut)(state);
+        column.retain_rows(&|id| key_alive(RawId::of(id)));
+
+        if let Some(target) = &self.target {
+            (target.retain)(column, target_alive);
+        }
+    }
+
+    fn set_owner(&mut self) {
+        self.owner = true;
+    }
+}
+
+/// Runtime description of the allocators in `E`, the columns in `S` and the links between them,
+/// for tools that read any column by name. Usually built with `registry!`.
+pub struct Registry<S, E> {
+    tables: Vec<Box<dyn AnyTable<E>>>,
+    columns: Vec<Box<dyn AnyColumn<S>>>,
+    /// The forward and back column of each link, by position in `columns`.
+    links: Vec<(usize, usize)>,
+    /// How to write the rows of the columns registered with `text`, by column name.
+    texts: Vec<(&'static str, Text<S>)>,
+}
+
+impl<S, E> Default for Registry<S, E> {
+    fn default() -> Self {
+        Registry { tables: vec![], columns: vec![], links: vec![], texts: vec![] }
+    }
+}
+
+impl<S: 'static, E: 'static> Registry<S, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn table<ID>(mut self, field: Field<E, Allocator<ID>>) -> Self
+    where
+        ID: IdType + Tag + Encode + Decode + 'static,
+    {
+        self.tables.push(Box::new(field));
+        self
+    }
+
+    /// A column whose rows can be read as `Debug`.
+    pub fn column<ID, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        C: Reflect<ID> + Column<ID> + 'static,
+    {
+        self.columns.push(Box::new(ReflectField::new(field, None, None)));
+        self
+    }
+
+    /// A column whose rows can also be encoded in the snapshot format, and which deltas cover.
+    pub fn encoded<ID, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        C: Reflect<ID> + Column<ID> + Diffable + 'static,
+        C::Value: Encode,
+    {
+        self.columns.push(Box::new(ReflectField::new(field, Some(Encoding::of()), None)));
+        self
+    }
+
+    /// A column holding ids of another table, which inspection can follow. It is encoded like `encoded` columns,
+    /// and replaces a column registered under the same name.
+    pub fn reference<ID, T, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        T: IdType + 'static,
+        C: Reflect<ID> + RefColumn<ID, T> + Diffable + 'static,
+        C::Value: Encode,
+    {
+        let column = Box::new(ReflectField::new(field, Some(Encoding::of()), Some(Target::of::<T>())));
+
+        match self.columns.iter().position(|registered| registered.info().name == field.name) {
+            Some(i) => self.columns[i] = column,
+            None => self.columns.push(column),
+        }
+        self
+    }
+
+    /// Records every link declared with `links!` and marks the columns that refer to an owner.
+    ///
+    /// Panics unless both columns of each link are already registered with `reference`.
+    pub fn links(mut self) -> Self
+    where
+        S: Links<E>,
+    {
+        S::visit_links(&mut self);
+        self
+    }
+
+    /// Lets the rows of a column be written as text with `Display`, for CSV export.
+    /// Registers the column as with `column` unless it is already registered.
+    pub fn text<ID, C>(mut self, field: Field<S, C>) -> Self
+    where
+        ID: IdType + 'static,
+        C: Reflect<ID> + Column<ID> + 'static,
+        C::Value: Display,
+    {
+        if self.column_named(field.name).is_none() {
+            self = self.column(field);
+        }
+
+        let text = move |state: &S, id: RawId| (field.get)(state).row(id.id()).map(|value| value.to_string());
+        self.texts.push((field.name, Box::new(text)));
+        self
+    }
+
+    /// The position of the reference column `name`, keyed by `ID` and referring to `T`.
+    fn link_column<ID: IdType + 'static, T: IdType + 'static>(&self, name: &str) -> usize {
+        self.columns.iter()
+            .position(|column| column.info().name == name && column.id_type() == TypeId::of::<ID>() && column.target() == Some(TypeId::of::<T>()))
+            .unwrap_or_else(|| panic!("link column {} is registered with reference before links", name))
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = TableInfo> + '_ {
+        self.tables.iter().map(|table| table.info())
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = ColumnInfo> + '_ {
+        self.columns.iter().map(|column| column.info())
+    }
+
+    /// The columns keyed by `ID`, in the order they were registered.
+    pub fn columns_of<ID: IdType + 'static>(&self) -> impl Iterator<Item = ColumnInfo> + '_ {
+        self.columns_keyed(TypeId::of::<ID>()).map(|column| column.info())
+    }
+
+    pub fn column_info(&self, name: &str) -> Option<ColumnInfo> {
+        self.column_named(name).map(|column| column.info())
+    }
+
+    /// The living entities of the table named `table`, or `None` if it is not registered.
+    pub fn living(&self, entities: &E, table: &str) -> Option<Vec<RawId>> {
+        self.table_named(table).map(|table| table.living(entities))
+    }
+
+    /// Whether `id` is a living entity of the table named `table`.
+    pub fn is_alive(&self, entities: &E, table: &str, id: RawId) -> bool {
+        self.table_named(table).is_some_and(|table| table.is_alive(entities, id))
+    }
+
+    /// The row of `id` in the column named `column`, or `None` if it has none or the column is not keyed by `ID`.
+    pub fn get<'a, ID: IdType + 'static>(&self, state: &'a S, column: &str, id: &VerifiedEntity<ID>) -> Option<&'a dyn Debug> {
+        self.column_named(column)
+            .filter(|column| column.id_type() == TypeId::of::<ID>())
+            .and_then(|column| column.value(state, RawId::of(id.entity)))
+    }
+
+    /// Every row of `id` in the columns keyed by `ID`.
+    pub fn row<'a, ID: IdType + 'static>(&self, state: &'a S, id: &VerifiedEntity<ID>) -> Vec<(&'static str, &'a dyn Debug)> {
+        self.columns_keyed(TypeId::of::<ID>())
+            .filter_map(|column| Some((column.info().name, column.value(state, RawId::of(id.entity))?)))
+            .collect()
+    }
+
+    /// The encoded row of `id` in the column named `column`, if it was registered with `encoded`.
+    pub fn encode<ID: IdType + 'static>(&self, state: &S, column: &str, id: &VerifiedEntity<ID>) -> Option<Vec<u8>> {
+        self.column_named(column)
+            .filter(|column| column.id_type() == TypeId::of::<ID>())
+            .and_then(|column| column.encode(state, RawId::of(id.entity)))
+    }
+
+    pub(crate) fn table_of(&self, id_type: TypeId) -> Option<&dyn AnyTable<E>> {
+        self.tables.iter().find(|table| table.id_type() == id_type).map(|table| table.as_ref())
+    }
+
+    fn table_named(&self, name: &str) -> Option<&dyn AnyTable<E>> {
+        self.tables.iter().find(|table| table.info().name == name).map(|table| table.as_ref())
+    }
+
+    fn column_named(&self, name: &str) -> Option<&dyn AnyColumn<S>> {
+        self.columns.iter().find(|column| column.info().name == name).map(|column| column.as_ref())
+    }
+
+    pub(crate) fn columns_keyed(&self, id_type: TypeId) -> impl Iterator<Item = &dyn AnyColumn<S>> + '_ {
+        self.columns.iter().filter(move |column| column.id_type() == id_type).map(|column| column.as_ref())
+    }
+
+    pub(crate) fn all_tables(&self) -> impl Iterator<Item = &dyn AnyTable<E>> + '_ {
+        self.tables.iter().map(|table| table.as_ref())
+    }
+
+    pub(crate) fn all_columns(&self) -> impl Iterator<Item = &dyn AnyColumn<S>> + '_ {
+        self.columns.iter().map(|column| column.as_ref())
+    }
+
+    /// The names of the columns keyed by `id_type` that were registered with `text`, and how to write their rows.
+    pub(crate) fn texts_keyed(&self, id_type: TypeId) -> impl Iterator<Item = (&'static str, &Text<S>)> + '_ {
+        self.texts.iter()
+            .filter(move |(name, _)| self.column_named(name).is_some_and(|column| column.id_type() == id_type))
+            .map(|(name, text)| (*name, text))
+    }
+
+    /// The forward and back column of every link.
+    pub(crate) fn linked_columns(&self) -> impl Iterator<Item = (&dyn AnyColumn<S>, &dyn AnyColumn<S>)> + '_ {
+        self.links.iter().map(move |&(a, b)| (self.columns[a].as_ref(), self.columns[b].as_ref()))
+    }
+}
+
+impl<S: 'static, E: 'static> LinkVisitor<S, E> for Registry<S, E> {
+    fn link<A, B, CA, CB>(&mut self, forward: Field<S, CA>, back: Field<S, CB>)
+    where
+        A: IdType + 'static,
+        B: IdType + 'static,
+        CA: RefColumn<A, B> + 'static,
+        CB: RefColumn<B, A> + 'static,
+    {
+        let forward = self.link_column::<A, B>(forward.name);
+        let back = self.link_column::<B, A>(back.name);
+        self.links.push((forward, back));
+    }
+
+    fn owns<OWNER, OWNED>(&mut self)
+    where
+        S: Owns<OWNER, OWNED>,
+        E: HasAllocator<OWNER> + HasAllocator<OWNED>,
+        OWNER: IdType + 'static,
+        OWNED: IdType + 'static,
+    {
+        let &(forward, back) = self.links.last().expect("owns is visited after its link");
+
+        for i in [forward, back] {
+            let column = &mut self.columns[i];
+            if column.id_type() == TypeId::of::<OWNED>() && column.target() == Some(TypeId::of::<OWNER>()) {
+                column.set_owner();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::snapshot::Decode;
+
+    id_type!(ShipId, snapshot);
+    id_type!(CrewId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        ships: Allocator<ShipId>,
+        crew: Allocator<CrewId>,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    struct Hull(u32);
+
+    #[derive(Debug, Default)]
+    struct State {
+        ship_name: IndexedVec<ShipId, String>,
+        ship_hull: EntityMap<ShipId, Hull>,
+        ship_docked: EntitySet<ShipId>,
+        crew_ship: IndexedVec<CrewId, ShipId>,
+    }
+
+    fn registry() -> Registry<State, Allocators> {
+        registry!(State, Allocators;
+            tables: ships, crew;
+            columns: ship_hull, ship_docked;
+            encoded: ship_name;
+            references: crew_ship)
+    }
+
+    fn world() -> (State, Allocators, ShipId) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+
+        let ship = entities.ships.create_entity();
+        state.ship_name.insert(&ship, "Beagle".to_string());
+        state.ship_hull.insert(&ship, Hull(80));
+        let ship = ship.entity;
+
+        let crew = entities.crew.create_entity();
+        state.crew_ship.insert(&crew, ship);
+
+        (state, entities, ship)
+    }
+
+    #[test]
+    fn describes_tables_and_columns() {
+        let registry = registry();
+
+        let tables: Vec<_> = registry.tables().map(|table| table.name).collect();
+        assert_eq!(vec!["ships", "crew"], tables);
+
+        let columns: Vec<_> = registry.columns_of::<ShipId>().map(|column| (column.name, column.storage, column.encoded)).collect();
+        assert_eq!(vec![
+            ("ship_hull", Storage::EntityMap, false),
+            ("ship_docked", Storage::EntitySet, false),
+            ("ship_name", Storage::IndexedVec, true),
+        ], columns);
+
+        let crew_ship = registry.column_info("crew_ship").unwrap();
+        assert!(crew_ship.id_type.ends_with("CrewId"));
+        assert!(crew_ship.value_type.ends_with("ShipId"));
+        assert!(crew_ship.target.unwrap().ends_with("ShipId"));
+    }
+
+    #[test]
+    fn reads_rows_by_name() {
+        let (state, entities, ship) = world();
+        let ship = entities.ships.verify(ship).unwrap();
+        let registry = registry();
+
+        let row: Vec<_> = registry.row(&state, &ship).into_iter().map(|(name, value)| format!("{}: {:?}", name, value)).collect();
+        assert_eq!(vec!["ship_hull: Hull(80)", "ship_name: \"Beagle\""], row);
+
+        assert!(registry.get(&state, "crew_ship", &ship).is_none());
+        assert_eq!(Some(vec![RawId::of(ship.entity)]), registry.living(&entities, "ships"));
+    }
+
+    #[test]
+    fn encodes_registered_rows() {
+        let (state, entities, ship) = world();
+        let ship = entities.ships.verify(ship).unwrap();
+        let registry = registry();
+
+        let bytes = registry.encode(&state, "ship_name", &ship).unwrap();
+        assert_eq!("Beagle", String::decode(&mut &bytes[..]).unwrap());
+        assert_eq!(None, registry.encode(&state, "ship_hull", &ship));
+    }
+}
@@ -0,0 +1,946 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use crate::entities::RawId;
+use crate::diff::{Delta, Row, allocator_slot};
+use crate::snapshot::{Tag, Decode, SnapshotReader, SnapshotError, decode_len};
+use crate::error::{Error, Result};
+
+/// The type a snapshot tag names. Names that are not built in are id types or types the tag does not describe.
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Bool,
+    Unit,
+    String,
+    Unsigned(u8),
+    Signed(u8),
+    Float(u8),
+    Option(Box<Type>),
+    List(Box<Type>),
+    Pair(Box<Type>, Box<Type>),
+    Named(String),
+    Allocator(String),
+    Vec(String, Box<Type>),
+    Map(String, Box<Type>),
+    Set(String),
+    Delta,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Bool => write!(f, "bool"),
+            Type::Unit => write!(f, "unit"),
+            Type::String => write!(f, "string"),
+            Type::Unsigned(bits) => write!(f, "u{}", bits),
+            Type::Signed(bits) => write!(f, "i{}", bits),
+            Type::Float(bits) => write!(f, "f{}", bits),
+            Type::Option(inner) => write!(f, "option<{}>", inner),
+            Type::List(inner) => write!(f, "list<{}>", inner),
+            Type::Pair(a, b) => write!(f, "({},{})", a, b),
+            Type::Named(name) => write!(f, "{}", name),
+            Type::Allocator(id) => write!(f, "allocator<{}>", id),
+            Type::Vec(id, value) => write!(f, "vec<{},{}>", id, value),
+            Type::Map(id, value) => write!(f, "map<{},{}>", id, value),
+            Type::Set(id) => write!(f, "set<{}>", id),
+            Type::Delta => write!(f, "delta"),
+        }
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn eat(&mut self, token: char) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        let end = self.rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).unwrap_or(self.rest.len());
+        let (name, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        name
+    }
+
+    fn ty(&mut self) -> Option<Type> {
+        if self.eat('(') {
+            let a = self.ty()?;
+            if !self.eat(',') {
+                return None;
+            }
+            let b = self.ty()?;
+            return if self.eat(')') { Some(Type::Pair(Box::new(a), Box::new(b))) } else { None };
+        }
+
+        let name = self.name();
+        if name.is_empty() {
+            return None;
+        }
+
+        if !self.eat('<') {
+            return Some(match name {
+                "bool" => Type::Bool,
+                "unit" => Type::Unit,
+                "string" => Type::String,
+                "delta" => Type::Delta,
+                "u8" => Type::Unsigned(8),
+                "u16" => Type::Unsigned(16),
+                "u32" => Type::Unsigned(32),
+                "u64" => Type::Unsigned(64),
+                "i8" => Type::Signed(8),
+                "i16" => Type::Signed(16),
+                "i32" => Type::Signed(32),
+                "i64" => Type::Signed(64),
+                "f32" => Type::Float(32),
+                "f64" => Type::Float(64),
+                _ => Type::Named(name.to_string()),
+            });
+        }
+
+        let first = self.ty()?;
+        let second = if self.eat(',') { Some(self.ty()?) } else { None };
+        if !self.eat('>') {
+            return None;
+        }
+
+        match (name, first, second) {
+            ("option", inner, None) => Some(Type::Option(Box::new(inner))),
+            ("list", inner, None) => Some(Type::List(Box::new(inner))),
+            ("allocator", Type::Named(id), None) => Some(Type::Allocator(id)),
+            ("set", Type::Named(id), None) => Some(Type::Set(id)),
+            ("vec", Type::Named(id), Some(value)) => Some(Type::Vec(id, Box::new(value))),
+            ("map", Type::Named(id), Some(value)) => Some(Type::Map(id, Box::new(value))),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a tag written by `snapshot::Tag`. Tags that cannot be parsed are treated as one unknown name.
+fn parse(tag: &str) -> Type {
+    let mut parser = Parser { rest: tag };
+    match parser.ty() {
+        Some(ty) if parser.rest.is_empty() => ty,
+        _ => Type::Named(tag.to_string()),
+    }
+}
+
+/// How to decode values whose tags name a type with its own `Encode`, given as the tag of a type
+/// encoded the same way.
+#[derive(Debug, Clone, Default)]
+pub struct Layouts {
+    types: HashMap<String, Type>,
+}
+
+impl Layouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes values tagged `tag` as if they were tagged `layout`, such as `mass` as `(f64,f64)`.
+    pub fn layout(mut self, tag: &str, layout: &str) -> Self {
+        self.types.insert(tag.to_string(), parse(layout));
+        self
+    }
+
+    /// Decodes values of `T` as values of `L`, for a `T` that encodes exactly as `L` does.
+    pub fn of<T: Tag, L: Tag>(self) -> Self {
+        self.layout(&T::tag(), &L::tag())
+    }
+}
+
+/// The names a world's tags use besides the built in types: its id types and the layouts it was read with.
+struct Names<'a> {
+    ids: HashSet<String>,
+    layouts: &'a Layouts,
+}
+
+impl Names<'_> {
+    /// The layout of `name`. A layout that only names another type that is not an id is ignored,
+    /// so layouts cannot refer to each other in a loop.
+    fn layout(&self, name: &str) -> Option<&Type> {
+        self.layouts.types
+            .get(name)
+            .filter(|layout| !matches!(layout, Type::Named(other) if !self.ids.contains(other)))
+    }
+}
+
+/// A value decoded from its tag alone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Unit,
+    String(String),
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Option(Option<Box<Value>>),
+    List(Vec<Value>),
+    Pair(Box<Value>, Box<Value>),
+    /// An id, with the name of its id type.
+    Id(String, RawId),
+    /// A value whose tag does not describe its encoding, such as a component with its own `Encode`.
+    Opaque(String),
+}
+
+impl Value {
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Unsigned(value) => Some(*value as f64),
+            Value::Signed(value) => Some(*value as f64),
+            Value::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn ids(&self) -> Vec<(&str, RawId)> {
+        match self {
+            Value::Id(id_type, id) => vec![(id_type.as_str(), *id)],
+            Value::Option(value) => value.iter().flat_map(|value| value.ids()).collect(),
+            Value::List(values) => values.iter().flat_map(Value::ids).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter<'_>, values: &[Value]| -> fmt::Result {
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", value)?;
+            }
+            Ok(())
+        };
+
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Unit => write!(f, "()"),
+            Value::String(value) => write!(f, "{:?}", value),
+            Value::Unsigned(value) => write!(f, "{}", value),
+            Value::Signed(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Option(None) => write!(f, "None"),
+            Value::Option(Some(value)) => write!(f, "Some({})", value),
+            Value::List(values) => {
+                write!(f, "[")?;
+                list(f, values)?;
+                write!(f, "]")
+            }
+            Value::Pair(a, b) => write!(f, "({}, {})", a, b),
+            Value::Id(_, id) => write!(f, "{}", id),
+            Value::Opaque(tag) => write!(f, "<{}>", tag),
+        }
+    }
+}
+
+fn decode_value(ty: &Type, names: &Names, input: &mut &[u8]) -> Result<Value> {
+    Ok(match ty {
+        Type::Bool => Value::Bool(bool::decode(input)?),
+        Type::Unit => Value::Unit,
+        Type::String => Value::String(String::decode(input)?),
+        Type::Unsigned(8) => Value::Unsigned(u8::decode(input)?.into()),
+        Type::Unsigned(16) => Value::Unsigned(u16::decode(input)?.into()),
+        Type::Unsigned(32) => Value::Unsigned(u32::decode(input)?.into()),
+        Type::Unsigned(_) => Value::Unsigned(u64::decode(input)?),
+        Type::Signed(8) => Value::Signed(i8::decode(input)?.into()),
+        Type::Signed(16) => Value::Signed(i16::decode(input)?.into()),
+        Type::Signed(32) => Value::Signed(i32::decode(input)?.into()),
+        Type::Signed(_) => Value::Signed(i64::decode(input)?),
+        Type::Float(32) => Value::Float(f32::decode(input)?.into()),
+        Type::Float(_) => Value::Float(f64::decode(input)?),
+        Type::Option(inner) => match bool::decode(input)? {
+            true => Value::Option(Some(Box::new(decode_value(inner, names, input)?))),
+            false => Value::Option(None),
+        },
+        Type::List(inner) => {
+            let len = decode_len(input)?;
+            Value::List((0..len).map(|_| decode_value(inner, names, input)).collect::<Result<_>>()?)
+        }
+        Type::Set(id_type) => decode_value(&Type::List(Box::new(Type::Named(id_type.clone()))), names, input)?,
+        Type::Pair(a, b) => Value::Pair(Box::new(decode_value(a, names, input)?), Box::new(decode_value(b, names, input)?)),
+        Type::Named(id_type) if names.ids.contains(id_type) => Value::Id(id_type.clone(), RawId::decode(input)?),
+        Type::Named(name) => match names.layout(name) {
+            Some(layout) => decode_value(layout, names, input)?,
+            None => return Err(SnapshotError::Corrupt("tag does not describe the encoding").into()),
+        },
+        _ => return Err(SnapshotError::Corrupt("tag does not describe the encoding").into()),
+    })
+}
+
+/// Decodes one row, which is `Opaque` if its tag does not describe it.
+fn decode_row(ty: &Type, names: &Names, bytes: &[u8]) -> Value {
+    let mut input = bytes;
+    match decode_value(ty, names, &mut input) {
+        Ok(value) if input.is_empty() => value,
+        _ => Value::Opaque(ty.to_string()),
+    }
+}
+
+/// The id type that every value of a column of `ty` refers to, if it only holds ids.
+fn target(ty: &Type, names: &Names) -> Option<String> {
+    match ty {
+        Type::Named(id_type) | Type::Set(id_type) if names.ids.contains(id_type) => Some(id_type.clone()),
+        Type::Named(name) => target(names.layout(name)?, names),
+        Type::Option(inner) | Type::List(inner) => target(inner, names),
+        _ => None,
+    }
+}
+
+enum Contents {
+    /// A section holding a whole allocator or column.
+    Whole(Vec<u8>),
+    /// The rows of a column in a delta, applied to an empty column.
+    Rows(Vec<Row>),
+}
+
+/// An allocator read from a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub name: String,
+    pub id_type: String,
+    pub living: Vec<RawId>,
+}
+
+/// A column read from a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub id_type: String,
+    pub tag: String,
+    /// The id type the column refers to, if its values only hold ids.
+    pub target: Option<String>,
+    /// False if the tag does not describe how the section is encoded, so no row could be read.
+    pub readable: bool,
+    /// Rows by index, with the id they belong to for columns keyed by id.
+    rows: BTreeMap<usize, (Option<RawId>, Value)>,
+}
+
+impl Column {
+    pub fn get(&self, id: RawId) -> Option<&Value> {
+        match self.rows.get(&id.index)? {
+            (Some(key), _) if *key != id => None,
+            (_, value) => Some(value),
+        }
+    }
+}
+
+/// A world read from a snapshot by the tags of its sections, without the types that wrote it.
+///
+/// Reads snapshots written section by section and those kept by `Persistence`, whose world is one delta.
+/// Only the snapshot is read: the mutations `Persistence` logged since that checkpoint, in the matching
+/// `log-N` file, are not replayed, since its entries name row types rather than columns.
+///
+/// Values of types with their own `Encode` are `Opaque` unless `Layouts` describes their encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedWorld {
+    pub schema_version: u32,
+    pub tables: Vec<Table>,
+    pub columns: Vec<Column>,
+    /// Sections that are neither allocators nor columns, such as a tick.
+    pub other: Vec<(String, Value)>,
+}
+
+impl SavedWorld {
+    pub fn read<R: Read>(input: R) -> Result<Self> {
+        Self::read_with(input, &Layouts::new())
+    }
+
+    pub fn read_with<R: Read>(input: R, layouts: &Layouts) -> Result<Self> {
+        Self::from_reader_with(&SnapshotReader::new(input)?, layouts)
+    }
+
+    /// Reads the snapshot at `path`. Files kept by `Persistence`, which end in `.recs`, have their checksum checked first.
+    pub fn open<P: AsRef<Path>>(path: P, layouts: &Layouts) -> Result<Self> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|extension| extension == "recs") {
+            Self::from_reader_with(&crate::persistence::read_snapshot(path)?, layouts)
+        } else {
+            Self::read_with(BufReader::new(File::open(path)?), layouts)
+        }
+    }
+
+    pub fn from_reader(reader: &SnapshotReader) -> Result<Self> {
+        Self::from_reader_with(reader, &Layouts::new())
+    }
+
+    pub fn from_reader_with(reader: &SnapshotReader, layouts: &Layouts) -> Result<Self> {
+        let mut sections = vec![];
+        for name in reader.names() {
+            let tag = reader.tag(name).unwrap_or_default();
+
+            if parse(tag) == Type::Delta {
+                for change in reader.read::<Delta>(name)?.changes {
+                    sections.push((change.name, parse(&change.tag), Contents::Rows(change.rows)));
+                }
+            } else {
+                let bytes = reader.bytes(name).unwrap_or_default().to_vec();
+                sections.push((name.to_string(), parse(tag), Contents::Whole(bytes)));
+            }
+        }
+
+        let mut world = SavedWorld { schema_version: reader.schema_version(), tables: vec![], columns: vec![], other: vec![] };
+        for (name, ty, contents) in &sections {
+            if let Type::Allocator(id_type) = ty {
+                world.tables.push(Table { name: name.clone(), id_type: id_type.clone(), living: living(contents)? });
+            }
+        }
+
+        let ids = world.tables.iter().map(|table| table.id_type.clone()).collect();
+        let names = Names { ids, layouts };
+        for (name, ty, contents) in sections {
+            let (id_type, value, keyed) = match &ty {
+                Type::Allocator(_) => continue,
+                Type::Vec(id_type, value) => (id_type, value.as_ref().clone(), false),
+                Type::Map(id_type, value) => (id_type, value.as_ref().clone(), true),
+                Type::Set(id_type) => (id_type, Type::Unit, true),
+                _ => {
+                    let value = match &contents {
+                        Contents::Whole(bytes) => decode_row(&ty, &names, bytes),
+                        Contents::Rows(_) => Value::Opaque(ty.to_string()),
+                    };
+                    world.other.push((name, value));
+                    continue;
+                }
+            };
+
+            let rows = rows(&value, keyed, &names, &contents);
+            world.columns.push(Column {
+                name,
+                id_type: id_type.clone(),
+                tag: ty.to_string(),
+                target: target(&value, &names),
+                readable: rows.is_ok(),
+                rows: rows.unwrap_or_default(),
+            });
+        }
+
+        Ok(world)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|table| table.name == name)
+    }
+
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+
+    fn table_of(&self, id_type: &str) -> Option<&Table> {
+        self.tables.iter().find(|table| table.id_type == id_type)
+    }
+
+    fn columns_of<'a>(&'a self, id_type: &'a str) -> impl Iterator<Item = &'a Column> + 'a {
+        self.columns.iter().filter(move |column| column.id_type == id_type)
+    }
+}
+
+fn living(contents: &Contents) -> Result<Vec<RawId>> {
+    let mut living = vec![];
+
+    match contents {
+        Contents::Whole(bytes) => {
+            // generations and the free list come before the living ids
+            let input = &mut &bytes[..];
+            Vec::<u32>::decode(input)?;
+            Vec::<u32>::decode(input)?;
+            living.extend(Vec::<Option<RawId>>::decode(input)?.into_iter().flatten());
+        }
+        Contents::Rows(rows) => {
+            let mut indices = BTreeMap::new();
+            for row in rows {
+                if let Some((index, generation, alive)) = allocator_slot(row)? {
+                    indices.insert(index, (RawId { index, generation }, alive));
+                }
+            }
+            living.extend(indices.values().filter(|(_, alive)| *alive).map(|(id, _)| *id));
+        }
+    }
+
+    Ok(living)
+}
+
+/// Sets are read as columns keyed by id whose rows are all `Unit`.
+fn rows(value: &Type, keyed: bool, names: &Names, contents: &Contents) -> Result<BTreeMap<usize, (Option<RawId>, Value)>> {
+    let mut rows = BTreeMap::new();
+
+    match contents {
+        Contents::Whole(bytes) => {
+            let input = &mut &bytes[..];
+            for index in 0..decode_len(input)? {
+                if !keyed {
+                    rows.insert(index, (None, decode_value(value, names, input)?));
+                    continue;
+                }
+
+                let id = RawId::decode(input)?;
+                rows.insert(id.index, (Some(id), decode_value(value, names, input)?));
+            }
+            if !input.is_empty() {
+                return Err(SnapshotError::Corrupt("trailing bytes in section").into());
+            }
+        }
+        Contents::Rows(changes) => {
+            for row in changes {
+                let value = row.value.as_ref().map(|bytes| decode_row(value, names, bytes));
+
+                if keyed {
+                    let id = RawId::decode(&mut &row.key[..])?;
+                    match value {
+                        Some(value) => rows.insert(id.index, (Some(id), value)),
+                        None => rows.remove(&id.index),
+                    };
+                } else {
+                    let index = u32::decode(&mut &row.key[..])? as usize;
+                    match value {
+                        Some(value) => {
+                            rows.insert(index, (None, value));
+                        }
+                        None => {
+                            rows.split_off(&index);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Why a command could not run, nested in `Error::Command`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    Usage(&'static str),
+    UnknownTable(String),
+    UnknownColumn(String),
+    /// The table has no living entity with the id.
+    UnknownEntity(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(command) => write!(f, "unknown command: {} (try help)", command),
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::UnknownTable(name) => write!(f, "no table named {}", name),
+            CommandError::UnknownColumn(name) => write!(f, "no column named {}", name),
+            CommandError::UnknownEntity(id) => write!(f, "no living entity {}", id),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+const HELP: &str = "\
+tables                          list tables and their living entities
+columns [table]                 list columns and their tags
+count <table>                   count living entities
+inspect <table> <id> [depth]    show every row of an entity, following links depth levels deep
+follow <table> <id> <column>    show the entities a column of an entity refers to
+where <column> <op> <value>     list entities whose row compares, with op one of = != < <= > >=
+info                            show the schema version and other sections
+quit
+";
+
+/// Commands for exploring a `SavedWorld`, as run by the `relational_ecs-repl` binary.
+///
+/// Ids are written as `index` for the living entity at that index, or as `index`v`generation`.
+pub struct Repl {
+    world: SavedWorld,
+}
+
+impl Repl {
+    pub fn new(world: SavedWorld) -> Self {
+        Repl { world }
+    }
+
+    pub fn world(&self) -> &SavedWorld {
+        &self.world
+    }
+
+    /// Reads commands from `input` until it ends or says `quit`, writing results and errors to `output`.
+    pub fn run<R: BufRead, W: Write>(&self, input: R, mut output: W) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "quit" | "exit") {
+                break;
+            }
+
+            match self.eval(&line) {
+                Ok(text) => write!(output, "{}", text)?,
+                Err(error) => writeln!(output, "error: {}", error)?,
+            }
+
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs one command and returns what it prints.
+    pub fn eval(&self, line: &str) -> Result<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let mut out = String::new();
+
+        match words.as_slice() {
+            [] => {}
+            ["help"] => out += HELP,
+            ["tables"] => {
+                for table in &self.world.tables {
+                    out += &format!("{} ({}): {} living\n", table.name, table.id_type, table.living.len());
+                }
+            }
+            ["columns"] => self.columns(&mut out, None),
+            ["columns", table] => self.columns(&mut out, Some(self.table(table)?)),
+            ["count", table] => out += &format!("{}\n", self.table(table)?.living.len()),
+            ["inspect", table, id] => self.inspect(&mut out, table, id, 0)?,
+            ["inspect", table, id, depth] => {
+                let depth = depth.parse().map_err(|_| CommandError::Usage("inspect <table> <id> [depth]"))?;
+                self.inspect(&mut out, table, id, depth)?;
+            }
+            ["follow", table, id, column] => {
+                let table = self.table(table)?;
+                let id = self.entity(table, id)?;
+                let column = self.column(column)?;
+
+                for (id_type, target) in column.get(id).map(Value::ids).unwrap_or_default() {
+                    if let Some(target_table) = self.world.table_of(id_type) {
+                        self.write_entity(&mut out, target_table, target, 0, 0, &mut HashSet::new());
+                    }
+                }
+            }
+            ["where", column, op, value @ ..] if !value.is_empty() => {
+                let column = self.column(column)?;
+                self.filter(&mut out, column, op, rest(line, 3).trim_matches('"'))?;
+            }
+            ["info"] => {
+                out += &format!("schema version {}\n", self.world.schema_version);
+                for (name, value) in &self.world.other {
+                    out += &format!("{}: {}\n", name, value);
+                }
+            }
+            ["tables", ..] | ["count", ..] | ["inspect", ..] | ["follow", ..] | ["where", ..] | ["columns", ..] => {
+                return Err(CommandError::Usage(usage(words[0])).into());
+            }
+            [command, ..] => return Err(CommandError::Unknown(command.to_string()).into()),
+        }
+
+        Ok(out)
+    }
+
+    fn table(&self, name: &str) -> Result<&Table> {
+        self.world.table(name).ok_or_else(|| CommandError::UnknownTable(name.to_string()).into())
+    }
+
+    fn column(&self, name: &str) -> Result<&Column> {
+        self.world.column(name).ok_or_else(|| CommandError::UnknownColumn(name.to_string()).into())
+    }
+
+    fn entity(&self, table: &Table, text: &str) -> Result<RawId> {
+        let unknown = || Error::from(CommandError::UnknownEntity(text.to_string()));
+
+        let (index, generation) = match text.split_once('v') {
+            Some((index, generation)) => (index, Some(generation.parse::<u32>().map_err(|_| unknown())?)),
+            None => (text, None),
+        };
+        let index = index.parse::<usize>().map_err(|_| unknown())?;
+
+        table.living.iter()
+            .copied()
+            .find(|id| id.index == index && generation.is_none_or(|generation| id.generation.value() == generation))
+            .ok_or_else(unknown)
+    }
+
+    fn columns(&self, out: &mut String, table: Option<&Table>) {
+        for column in &self.world.columns {
+            if table.is_none_or(|table| table.id_type == column.id_type) {
+                let unreadable = if column.readable { "" } else { " (not readable)" };
+                *out += &format!("{}: {}{}\n", column.name, column.tag, unreadable);
+            }
+        }
+    }
+
+    fn inspect(&self, out: &mut String, table: &str, id: &str, depth: usize) -> Result<()> {
+        let table = self.table(table)?;
+        let id = self.entity(table, id)?;
+
+        let mut seen = HashSet::new();
+        seen.insert((table.id_type.as_str(), id));
+        self.write_entity(out, table, id, depth, 0, &mut seen);
+        Ok(())
+    }
+
+    /// Writes an entity in the layout of `inspect::Node`.
+    fn write_entity<'a>(&'a self, out: &mut String, table: &'a Table, id: RawId, depth: usize, indent: usize, seen: &mut HashSet<(&'a str, RawId)>) {
+        if indent == 0 {
+            *out += &format!("{} {}\n", table.name, id);
+        }
+        let pad = "  ".repeat(indent + 1);
+
+        for column in self.world.columns_of(&table.id_type) {
+            let value = match column.get(id) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let target_table = column.target.as_ref().and_then(|target| self.world.table_of(target));
+            let target_table = match target_table {
+                Some(target_table) => target_table,
+                None => {
+                    *out += &format!("{}{}: {}\n", pad, column.name, value);
+                    continue;
+                }
+            };
+
+            for (_, target) in value.ids() {
+                let alive = target_table.living.contains(&target);
+                let dead = if alive { "" } else { " (dead)" };
+                *out += &format!("{}{} -> {} {}{}\n", pad, column.name, target_table.name, target, dead);
+
+                if alive && depth > 0 && seen.insert((&target_table.id_type, target)) {
+                    self.write_entity(out, target_table, target, depth - 1, indent + 1, seen);
+                }
+            }
+        }
+    }
+
+    fn filter(&self, out: &mut String, column: &Column, op: &str, literal: &str) -> Result<()> {
+        let accept: fn(Ordering) -> bool = match op {
+            "=" | "==" => |ordering| ordering == Ordering::Equal,
+            "!=" => |ordering| ordering != Ordering::Equal,
+            "<" => |ordering| ordering == Ordering::Less,
+            "<=" => |ordering| ordering != Ordering::Greater,
+            ">" => |ordering| ordering == Ordering::Greater,
+            ">=" => |ordering| ordering != Ordering::Less,
+            _ => return Err(CommandError::Usage(usage("where")).into()),
+        };
+
+        let table = self.world.table_of(&column.id_type).ok_or_else(|| CommandError::UnknownTable(column.id_type.clone()))?;
+        let mut matching = 0;
+
+        for &id in &table.living {
+            if let Some(value) = column.get(id) {
+                if compare(value, literal).is_some_and(accept) {
+                    *out += &format!("{} {}: {}\n", table.name, id, value);
+                    matching += 1;
+                }
+            }
+        }
+
+        *out += &format!("{} matching\n", matching);
+        Ok(())
+    }
+}
+
+fn usage(command: &str) -> &'static str {
+    match command {
+        "columns" => "columns [table]",
+        "count" => "count <table>",
+        "inspect" => "inspect <table> <id> [depth]",
+        "follow" => "follow <table> <id> <column>",
+        "where" => "where <column> <op> <value>",
+        _ => "tables",
+    }
+}
+
+/// What follows the first `count` words of `line`, with its spacing kept.
+fn rest(line: &str, count: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..count {
+        rest = rest.trim_start_matches(|c: char| !c.is_whitespace()).trim_start();
+    }
+    rest.trim_end()
+}
+
+/// How `value` compares to the text of a literal. Numbers compare by value, ids and strings by their text.
+fn compare(value: &Value, literal: &str) -> Option<Ordering> {
+    match value {
+        Value::Option(Some(value)) => compare(value, literal),
+        Value::Bool(value) => literal.parse::<bool>().ok().map(|literal| value.cmp(&literal)),
+        Value::String(value) => Some(value.as_str().cmp(literal)),
+        Value::Id(_, id) => Some(id.to_string().as_str().cmp(literal)),
+        _ => value.number()?.partial_cmp(&literal.parse::<f64>().ok()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+    use crate::diff::Diff;
+    use crate::snapshot::{Encode, SnapshotWriter};
+
+    id_type!(BodyId, snapshot);
+    id_type!(SurfaceId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        bodies: Allocator<BodyId>,
+        surfaces: Allocator<SurfaceId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        body_name: IndexedVec<BodyId, String>,
+        body_mass: IndexedVec<BodyId, f64>,
+        body_surface: EntityMap<BodyId, SurfaceId>,
+        surface_body: IndexedVec<SurfaceId, BodyId>,
+        surface_albedo: IndexedVec<SurfaceId, Albedo>,
+    }
+
+    /// A component with its own tag, which the REPL cannot decode without a layout.
+    #[derive(Debug, Default, Copy, Clone, PartialEq)]
+    struct Albedo(f32);
+
+    impl crate::snapshot::Tag for Albedo {
+        fn tag() -> String {
+            "albedo".to_string()
+        }
+    }
+
+    impl Encode for Albedo {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.0.encode(out);
+        }
+    }
+
+    impl Decode for Albedo {
+        fn decode(input: &mut &[u8]) -> Result<Self> {
+            f32::decode(input).map(Albedo)
+        }
+    }
+
+    fn world() -> (State, Allocators) {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+
+        for (name, mass) in &[("Sun", 1.989e30), ("Earth", 5.972e24), ("Moon", 7.342e22)] {
+            let body = entities.bodies.create_entity();
+            state.body_name.insert(&body, name.to_string());
+            state.body_mass.insert(&body, *mass);
+        }
+
+        let earth = BodyId::new(1);
+        let surface = entities.surfaces.create_entity();
+        state.surface_body.insert(&surface, earth);
+        state.surface_albedo.insert(&surface, Albedo(0.3));
+        state.body_surface.values.insert(earth, surface.entity);
+
+        (state, entities)
+    }
+
+    fn snapshot() -> Vec<u8> {
+        let (state, entities) = world();
+        save(&state, &entities)
+    }
+
+    fn save(state: &State, entities: &Allocators) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(vec![], 3).unwrap();
+        writer.write("bodies", &entities.bodies).unwrap();
+        writer.write("surfaces", &entities.surfaces).unwrap();
+        writer.write("body_name", &state.body_name).unwrap();
+        writer.write("body_mass", &state.body_mass).unwrap();
+        writer.write("body_surface", &state.body_surface).unwrap();
+        writer.write("surface_body", &state.surface_body).unwrap();
+        writer.write("surface_albedo", &state.surface_albedo).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn sections() -> Repl {
+        Repl::new(SavedWorld::read(&snapshot()[..]).unwrap())
+    }
+
+    #[test]
+    fn lists_and_inspects_sections() {
+        let repl = sections();
+
+        assert_eq!("bodies (BodyId): 3 living\nsurfaces (SurfaceId): 1 living\n", repl.eval("tables").unwrap());
+        assert_eq!("surface_body: vec<SurfaceId,BodyId>\nsurface_albedo: vec<SurfaceId,albedo> (not readable)\n", repl.eval("columns surfaces").unwrap());
+
+        assert_eq!(concat!(
+            "bodies 1v1\n",
+            "  body_name: \"Earth\"\n",
+            "  body_mass: 5.972e24\n",
+            "  body_surface -> surfaces 0v1\n",
+            "    surface_body -> bodies 1v1\n",
+        ), repl.eval("inspect bodies 1 1").unwrap());
+
+        assert_eq!("surfaces 0v1\n  surface_body -> bodies 1v1\n", repl.eval("follow bodies 1v1 body_surface").unwrap());
+        assert!(matches!(repl.eval("inspect bodies 1v2"), Err(Error::Command(CommandError::UnknownEntity(id))) if id == "1v2"));
+    }
+
+    #[test]
+    fn filters_rows() {
+        let repl = sections();
+
+        assert_eq!("bodies 0v1: 1.989e30\nbodies 1v1: 5.972e24\n2 matching\n", repl.eval("where body_mass > 1e23").unwrap());
+        assert_eq!("bodies 2v1: \"Moon\"\n1 matching\n", repl.eval("where body_name = \"Moon\"").unwrap());
+        assert!(matches!(repl.eval("where body_mass ~ 1"), Err(Error::Command(CommandError::Usage("where <column> <op> <value>")))));
+    }
+
+    #[test]
+    fn where_keeps_the_spacing_of_literals() {
+        let (mut state, entities) = world();
+        state.body_name.insert(&entities.bodies.verify(BodyId::new(2)).unwrap(), "Alpha  Centauri".to_string());
+        let repl = Repl::new(SavedWorld::read(&save(&state, &entities)[..]).unwrap());
+
+        assert_eq!("bodies 2v1: \"Alpha  Centauri\"\n1 matching\n", repl.eval("where body_name = \"Alpha  Centauri\"").unwrap());
+        assert_eq!("0 matching\n", repl.eval("where body_name = Alpha Centauri").unwrap());
+    }
+
+    #[test]
+    fn layouts_decode_components_with_their_own_tags() {
+        let layouts = Layouts::new().of::<Albedo, f32>();
+        let repl = Repl::new(SavedWorld::read_with(&snapshot()[..], &layouts).unwrap());
+
+        assert_eq!("surface_body: vec<SurfaceId,BodyId>\nsurface_albedo: vec<SurfaceId,albedo>\n", repl.eval("columns surfaces").unwrap());
+        assert_eq!("surfaces 0v1: 0.30000001192092896\n1 matching\n", repl.eval("where surface_albedo > 0.2").unwrap());
+    }
+
+    #[test]
+    fn reads_world_deltas() {
+        let (state, mut entities) = world();
+        entities.bodies.kill(BodyId::new(0));
+
+        let diff = Diff::new(registry!(State, Allocators;
+            tables: bodies, surfaces;
+            encoded: body_mass, surface_albedo;
+            references: body_surface));
+        let delta = diff.between((&State::default(), &Allocators::default()), (&state, &entities));
+
+        let mut writer = SnapshotWriter::new(vec![], 1).unwrap();
+        writer.write("tick", &7u64).unwrap();
+        writer.write("world", &delta).unwrap();
+        let repl = Repl::new(SavedWorld::read(&writer.finish().unwrap()[..]).unwrap());
+
+        assert_eq!("2\n", repl.eval("count bodies").unwrap());
+        assert_eq!("schema version 1\ntick: 7\n", repl.eval("info").unwrap());
+        assert_eq!(concat!(
+            "surfaces 0v1\n",
+            "  surface_albedo: <albedo>\n",
+        ), repl.eval("inspect surfaces 0").unwrap());
+
+        let mut output = vec![];
+        repl.run(&b"count surfaces\nbogus\nquit\ncount bodies\n"[..], &mut output).unwrap();
+        assert_eq!("> 1\n> error: unknown command: bogus (try help)\n> ", String::from_utf8(output).unwrap());
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use crate::entities::RawId;
+use crate::reflect::{Registry, AnyTable};
+use crate::diff::{Diff, Delta};
+use crate::snapshot::{Encode, Decode, SnapshotError};
+use crate::error::Result;
+
+/// An entity appearing or disappearing on the client.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Lifecycle {
+    Created { allocator: &'static str, id: RawId },
+    Killed { allocator: &'static str, id: RawId },
+}
+
+/// The tables and encoded columns of a registry that are replicated. The server and its clients must use the same registry.
+pub struct Replication<S, E> {
+    diff: Diff<S, E>,
+}
+
+impl<S: 'static, E: 'static> Replication<S, E> {
+    pub fn new(registry: Registry<S, E>) -> Self {
+        Replication { diff: Diff::new(registry) }
+    }
+
+    pub(crate) fn diff(&self) -> &Diff<S, E> {
+        &self.diff
+    }
+
+    /// Applies `delta` and lists the entities it created and killed in the allocators it changed.
+    pub(crate) fn apply(&self, delta: &Delta, state: &mut S, entities: &mut E) -> Result<Vec<Lifecycle>> {
+        let living = |table: &dyn AnyTable<E>, entities: &E| table.living(entities).into_iter().collect::<BTreeSet<_>>();
+        let changed: Vec<_> = self.diff.registry().all_tables()
+            .filter(|table| delta.change(table.info().name).is_some())
+            .collect();
+        let before: Vec<_> = changed.iter().map(|table| living(*table, entities)).collect();
+
+        self.diff.apply(delta, state, entities)?;
+
+        let mut events = vec![];
+        for (table, before) in changed.iter().zip(before) {
+            let after = living(*table, entities);
+            let name = table.info().name;
+
+            events.extend(before.difference(&after).map(|id| Lifecycle::Killed { allocator: name, id: *id }));
+            events.extend(after.difference(&before).map(|id| Lifecycle::Created { allocator: name, id: *id }));
+        }
+        Ok(events)
+    }
+}
+
+/// Frames are a `u64` length followed by the tick and the encoded body.
+pub(crate) fn write_frame<W: Write, T: Encode>(output: &mut W, tick: u64, body: &T) -> Result<()> {
+    let body = {
+        let mut bytes = vec![];
+        tick.encode(&mut bytes);
+        body.encode(&mut bytes);
+        bytes
+    };
+
+    let mut frame = vec![];
+    (body.len() as u64).encode(&mut frame);
+    frame.extend_from_slice(&body);
+
+    output.write_all(&frame)?;
+    output.flush()?;
+    Ok(())
+}
+
+pub(crate) fn read_frame<R: Read, T: Decode>(input: &mut R) -> Result<(u64, T)> {
+    let mut len = [0; 8];
+    input.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+
+    // read no more than arrives, so a bad length cannot allocate more than the stream holds
+    let mut body = vec![];
+    input.take(len).read_to_end(&mut body)?;
+    if (body.len() as u64) < len {
+        return Err(SnapshotError::Corrupt("frame shorter than its length").into());
+    }
+
+    let mut body = &body[..];
+    let tick = u64::decode(&mut body)?;
+    let value = T::decode(&mut body)?;
+    if !body.is_empty() {
+        return Err(SnapshotError::Corrupt("trailing bytes in frame").into());
+    }
+    Ok((tick, value))
+}
+
+/// Sends one client the changes to the replicated state since the previous tick.
+///
+/// Keeps a mirror of what the client has been sent, so each tick only carries what changed.
+pub struct Server<S, E> {
+    replication: Replication<S, E>,
+    mirror: (S, E),
+    tick: u64,
+}
+
+impl<S: Default + 'static, E: Default + 'static> Server<S, E> {
+    pub fn new(replication: Replication<S, E>) -> Self {
+        Server {
+            replication,
+            mirror: Default::default(),
+            tick: 0,
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Writes the delta from the mirror to the current state, even if it is empty, and returns the tick sent.
+    ///
+    /// The mirror only changes once the frame is written, so a failed write is resent in full next tick.
+    pub fn send<W: Write>(&mut self, output: &mut W, state: &S, entities: &E) -> Result<u64> {
+        let delta = self.replication.diff().between((&self.mirror.0, &self.mirror.1), (state, entities));
+        write_frame(output, self.tick + 1, &delta)?;
+
+        self.replication.diff().apply(&delta, &mut self.mirror.0, &mut self.mirror.1)?;
+        self.tick += 1;
+        Ok(self.tick)
+    }
+}
+
+/// What one received tick changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Received {
+    pub tick: u64,
+    pub events: Vec<Lifecycle>,
+}
+
+/// Holds a mirrored copy of the replicated state, with allocators whose generations match the server's.
+pub struct Client<S, E> {
+    replication: Replication<S, E>,
+    state: S,
+    entities: E,
+    tick: u64,
+}
+
+impl<S: Default + 'static, E: Default + 'static> Client<S, E> {
+    pub fn new(replication: Replication<S, E>) -> Self {
+        Client {
+            replication,
+            state: S::default(),
+            entities: E::default(),
+            tick: 0,
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn entities(&self) -> &E {
+        &self.entities
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Reads and applies one tick, blocking until it arrives.
+    pub fn receive<R: Read>(&mut self, input: &mut R) -> Result<Received> {
+        let (tick, delta): (u64, Delta) = read_frame(input)?;
+        if tick != self.tick + 1 {
+            return Err(SnapshotError::Corrupt("tick out of order").into());
+        }
+
+        let events = self.replication.apply(&delta, &mut self.state, &mut self.entities)?;
+        self.tick = tick;
+        Ok(Received { tick, events })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::net::{TcpListener, TcpStream};
+    use crate::traits::*;
+    use crate::entities::Allocator;
+    use crate::storage::*;
+
+    id_type!(ShipId, snapshot);
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Allocators {
+        ships: Allocator<ShipId>,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        ship_name: IndexedVec<ShipId, String>,
+        ship_escort: EntityMap<ShipId, ShipId>,
+        ship_secret: IndexedVec<ShipId, u32>,
+    }
+
+    fn replication() -> Replication<State, Allocators> {
+        Replication::new(registry!(State, Allocators;
+            tables: ships;
+            columns: ship_secret;
+            encoded: ship_name;
+            references: ship_escort))
+    }
+
+    fn spawn(state: &mut State, entities: &mut Allocators, name: &str) -> ShipId {
+        let ship = entities.ships.create_entity();
+        state.ship_name.insert(&ship, name.to_string());
+        state.ship_secret.insert(&ship, 42);
+        ship.entity
+    }
+
+    #[test]
+    fn client_mirrors_server() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let mut server = Server::new(replication());
+        let mut client = Client::new(replication());
+        let mut pipe = vec![];
+
+        let victory = spawn(&mut state, &mut entities, "Victory");
+        let beagle = spawn(&mut state, &mut entities, "Beagle");
+        state.ship_escort.values.insert(victory, beagle);
+        server.send(&mut pipe, &state, &entities).unwrap();
+
+        entities.ships.kill(beagle);
+        state.ship_escort.values.remove(&victory);
+        let bounty = spawn(&mut state, &mut entities, "Bounty");
+        server.send(&mut pipe, &state, &entities).unwrap();
+        server.send(&mut pipe, &state, &entities).unwrap();
+
+        let mut input = &pipe[..];
+        let first = client.receive(&mut input).unwrap();
+        assert_eq!(2, first.events.len());
+
+        let second = client.receive(&mut input).unwrap();
+        assert_eq!(vec![
+            Lifecycle::Killed { allocator: "ships", id: RawId::of(beagle) },
+            Lifecycle::Created { allocator: "ships", id: RawId::of(bounty) },
+        ], second.events);
+
+        let third = client.receive(&mut input).unwrap();
+        assert_eq!(Received { tick: 3, events: vec![] }, third);
+
+        assert_eq!(entities, *client.entities());
+        assert_eq!(state.ship_name.values, client.state().ship_name.values);
+        assert!(client.state().ship_escort.is_empty());
+        assert!(client.state().ship_secret.values.is_empty());
+    }
+
+    #[test]
+    fn skipped_tick_is_an_error() {
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let mut server = Server::new(replication());
+        let mut pipe = vec![];
+
+        server.send(&mut pipe, &state, &entities).unwrap();
+        let start = pipe.len();
+        spawn(&mut state, &mut entities, "Victory");
+        server.send(&mut pipe, &state, &entities).unwrap();
+
+        let mut client = Client::new(replication());
+        assert!(client.receive(&mut &pipe[start..]).is_err());
+    }
+
+    #[test]
+    fn bad_frames_are_errors() {
+        let mut frame = vec![];
+        u64::MAX.encode(&mut frame);
+        frame.extend_from_slice(&[0; 16]);
+        assert!(matches!(read_frame::<_, Delta>(&mut &frame[..]), Err(Error::Snapshot(SnapshotError::Corrupt(_)))));
+    }
+
+    #[test]
+    fn failed_send_is_resent() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (mut state, mut entities) = (State::default(), Allocators::default());
+        let mut server = Server::new(replication());
+        spawn(&mut state, &mut entities, "Victory");
+
+        assert!(server.send(&mut Broken, &state, &entities).is_err());
+        assert_eq!(0, server.tick());
+
+        let mut pipe = vec![];
+        server.send(&mut pipe, &state, &entities).unwrap();
+
+        let mut client = Client::new(replication());
+        client.receive(&mut &pipe[..]).unwrap();
+        assert_eq!(vec!["Victory"], client.state().ship_name.values);
+    }
+
+    #[test]
+    fn replicates_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut state, mut entities) = (State::default(), Allocators::default());
+            let mut server = Server::new(replication());
+
+            spawn(&mut state, &mut entities, "Victory");
+            server.send(&mut stream, &state, &entities).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut client = Client::new(replication());
+        client.receive(&mut stream).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(vec!["Victory"], client.state().ship_name.values);
+    }
+}
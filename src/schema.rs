@@ -0,0 +1,775 @@
+use std::fmt;
+use std::io::Write;
+use crate::traits::*;
+use crate::entities::{RawId, VerifiedEntity};
+use crate::error::{Error, Result};
+use crate::storage::*;
+use crate::cascade::Owns;
+use crate::reflect::{Registry, AnyTable, AnyColumn};
+
+/// A named field of `S` holding a `T`, usually created with `field!`.
+pub struct Field<S, T> {
+    pub name: &'static str,
+    pub get: fn(&S) -> &T,
+    pub get_mut: fn(&mut S) -> &mut T,
+}
+
+impl<S, T> Clone for Field<S, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S, T> Copy for Field<S, T> {}
+
+impl<S, T> Field<S, T> {
+    pub fn new(name: &'static str, get: fn(&S) -> &T, get_mut: fn(&mut S) -> &mut T) -> Self {
+        Field { name, get, get_mut }
+    }
+
+    /// The value for `id` in this column, or `MissingComponent` naming the column.
+    pub fn try_get<'s, ID: IdType, V>(&self, state: &'s S, id: &VerifiedEntity<ID>) -> Result<&'s V>
+    where
+        T: Get<ID, V> + 's,
+        V: 's,
+    {
+        (self.get)(state).get(id).ok_or_else(|| self.missing(id))
+    }
+
+    pub fn try_get_mut<'s, ID: IdType, V>(&self, state: &'s mut S, id: &VerifiedEntity<ID>) -> Result<&'s mut V>
+    where
+        T: Get<ID, V> + 's,
+        V: 's,
+    {
+        let missing = self.missing(id);
+        (self.get_mut)(state).get_mut(id).ok_or(missing)
+    }
+
+    fn missing<ID: IdType>(&self, id: &VerifiedEntity<ID>) -> Error {
+        Error::MissingComponent { column: self.name, id: RawId::of(id.entity) }
+    }
+}
+
+/// Storage viewed as rows keyed by `ID`.
+pub trait Column<ID: IdType> {
+    /// Whether every living entity is expected to have a row.
+    fn required(&self) -> bool;
+    fn has_row(&self, id: ID) -> bool;
+
+    /// Removes the rows of dead entities, if the storage keeps rows by id.
+    fn retain_rows(&mut self, _is_alive: &dyn Fn(ID) -> bool) {}
+}
+
+impl<ID: IdType, T> Column<ID> for IndexedVec<ID, T> {
+    fn required(&self) -> bool {
+        true
+    }
+
+    fn has_row(&self, id: ID) -> bool {
+        id.index() < self.values.len()
+    }
+}
+
+impl<ID: IdType, T> Column<ID> for EntityMap<ID, T> {
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn has_row(&self, id: ID) -> bool {
+        self.values.contains_key(&id)
+    }
+
+    fn retain_rows(&mut self, is_alive: &dyn Fn(ID) -> bool) {
+        self.values.retain(|id, _| is_alive(*id));
+    }
+}
+
+impl<ID: IdType> Column<ID> for EntitySet<ID> {
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn has_row(&self, id: ID) -> bool {
+        self.contains(&id)
+    }
+
+    fn retain_rows(&mut self, is_alive: &dyn Fn(ID) -> bool) {
+        self.values.retain(|id| is_alive(*id));
+    }
+}
+
+impl<ID: IdType, T> Column<ID> for OrderedMap<ID, T> {
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn has_row(&self, id: ID) -> bool {
+        self.values.contains_key(&id)
+    }
+
+    fn retain_rows(&mut self, is_alive: &dyn Fn(ID) -> bool) {
+        self.values.retain(|id, _| is_alive(*id));
+    }
+}
+
+impl<ID: IdType> Column<ID> for OrderedSet<ID> {
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn has_row(&self, id: ID) -> bool {
+        self.contains(&id)
+    }
+
+    fn retain_rows(&mut self, is_alive: &dyn Fn(ID) -> bool) {
+        self.values.retain(|id| is_alive(*id));
+    }
+}
+
+/// How many entities a row of a reference column can refer to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Cardinality {
+    One,
+    Optional,
+    Many,
+}
+
+/// A column whose values refer to entities of type `T`.
+pub trait RefColumn<ID: IdType, T: IdType>: Column<ID> {
+    /// How many entities each living entity refers to.
+    const CARDINALITY: Cardinality;
+
+    /// The ids held in the row for `id`, which must be alive.
+    fn refs(&self, id: ID) -> Vec<T>;
+
+    /// Removes references to dead entities, if the storage can hold no reference.
+    fn retain_refs(&mut self, _is_alive: &dyn Fn(T) -> bool) {}
+
+    /// Removes the reference from `id` to `target`, if the storage can hold no reference.
+    fn unset(&mut self, _id: ID, _target: T) {}
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for IndexedVec<ID, T> {
+    const CARDINALITY: Cardinality = Cardinality::One;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(id.index()).into_iter().copied().collect()
+    }
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for IndexedVec<ID, Option<T>> {
+    const CARDINALITY: Cardinality = Cardinality::Optional;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(id.index()).copied().flatten().into_iter().collect()
+    }
+
+    fn retain_refs(&mut self, is_alive: &dyn Fn(T) -> bool) {
+        for value in self.values.iter_mut() {
+            if let Some(id) = value {
+                if !is_alive(*id) {
+                    *value = None;
+                }
+            }
+        }
+    }
+
+    fn unset(&mut self, id: ID, target: T) {
+        if let Some(value) = self.values.get_mut(id.index()) {
+            if *value == Some(target) {
+                *value = None;
+            }
+        }
+    }
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for IndexedVec<ID, EntitySet<T>> {
+    const CARDINALITY: Cardinality = Cardinality::Many;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(id.index())
+            .map(|set| set.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn retain_refs(&mut self, is_alive: &dyn Fn(T) -> bool) {
+        for set in self.values.iter_mut() {
+            set.values.retain(|id| is_alive(*id));
+        }
+    }
+
+    fn unset(&mut self, id: ID, target: T) {
+        if let Some(set) = self.values.get_mut(id.index()) {
+            set.remove(&target);
+        }
+    }
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for EntityMap<ID, T> {
+    const CARDINALITY: Cardinality = Cardinality::Optional;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(&id).into_iter().copied().collect()
+    }
+
+    fn retain_refs(&mut self, is_alive: &dyn Fn(T) -> bool) {
+        self.values.retain(|_, id| is_alive(*id));
+    }
+
+    fn unset(&mut self, id: ID, target: T) {
+        if self.values.get(&id) == Some(&target) {
+            self.values.remove(&id);
+        }
+    }
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for IndexedVec<ID, OrderedSet<T>> {
+    const CARDINALITY: Cardinality = Cardinality::Many;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(id.index())
+            .map(|set| set.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn retain_refs(&mut self, is_alive: &dyn Fn(T) -> bool) {
+        for set in self.values.iter_mut() {
+            set.values.retain(|id| is_alive(*id));
+        }
+    }
+
+    fn unset(&mut self, id: ID, target: T) {
+        if let Some(set) = self.values.get_mut(id.index()) {
+            set.remove(&target);
+        }
+    }
+}
+
+impl<ID: IdType, T: IdType> RefColumn<ID, T> for OrderedMap<ID, T> {
+    const CARDINALITY: Cardinality = Cardinality::Optional;
+
+    fn refs(&self, id: ID) -> Vec<T> {
+        self.values.get(&id).into_iter().copied().collect()
+    }
+
+    fn retain_refs(&mut self, is_alive: &dyn Fn(T) -> bool) {
+        self.values.retain(|_, id| is_alive(*id));
+    }
+
+    fn unset(&mut self, id: ID, target: T) {
+        if self.values.get(&id) == Some(&target) {
+            self.values.remove(&id);
+        }
+    }
+}
+
+/// Receives the links of a state declared with `links!`, one call per link and per ownership edge.
+pub trait LinkVisitor<S, E> {
+    /// The two columns of a link: `forward` is keyed by `A` and refers to `B`, `back` the other way.
+    fn link<A, B, CA, CB>(&mut self, forward: Field<S, CA>, back: Field<S, CB>)
+    where
+        A: IdType + 'static,
+        B: IdType + 'static,
+        CA: RefColumn<A, B> + 'static,
+        CB: RefColumn<B, A> + 'static;
+
+    /// An ownership edge of the link visited just before, declared with `owns:` or `owned_by:`.
+    fn owns<OWNER, OWNED>(&mut self)
+    where
+        S: Owns<OWNER, OWNED>,
+        E: HasAllocator<OWNER> + HasAllocator<OWNED>,
+        OWNER: IdType + 'static,
+        OWNED: IdType + 'static;
+}
+
+/// The links of a state whose allocators are held by `E`. Implemented by `links!`.
+pub trait Links<E>: Sized {
+    fn visit_links<V: LinkVisitor<Self, E>>(visitor: &mut V);
+}
+
+/// The table a column is keyed by, and the table it refers to if it is a reference column.
+type Tables<'a, E> = (&'a dyn AnyTable<E>, Option<&'a dyn AnyTable<E>>);
+
+/// Integrity checks and diagrams over the tables, columns and links of a `Registry`.
+pub struct Schema<S, E> {
+    registry: Registry<S, E>,
+}
+
+impl<S: 'static, E: 'static> Schema<S, E> {
+    pub fn new(registry: Registry<S, E>) -> Self {
+        Schema { registry }
+    }
+
+    pub fn registry(&self) -> &Registry<S, E> {
+        &self.registry
+    }
+
+    /// Checks every registered column against the living entities.
+    pub fn check(&self, state: &S, entities: &E) -> Report {
+        let mut report = Report::default();
+
+        for column in self.registry.all_columns() {
+            let info = column.info();
+            let (table, target) = match self.tables_of(column) {
+                Some(tables) => tables,
+                None => {
+                    report.push(Problem::Unregistered { column: info.name });
+                    continue;
+                }
+            };
+
+            for row in table.living(entities) {
+                if !column.has_row(state, row) {
+                    if column.required(state) {
+                        report.push(Problem::Missing { column: info.name, row });
+                    } else if info.owner {
+                        report.push(Problem::Orphan { column: info.name, row });
+                    }
+                    continue;
+                }
+
+                if let Some(target) = target {
+                    for id in column.refs(state, row) {
+                        if target.is_alive(entities, id) {
+                            continue;
+                        }
+
+                        let problem = if info.owner {
+                            Problem::Orphan { column: info.name, row }
+                        } else {
+                            Problem::Dangling { column: info.name, row, target: id }
+                        };
+                        report.push(problem);
+                    }
+                }
+            }
+        }
+
+        for (a, b) in self.registry.linked_columns() {
+            self.check_link(state, entities, a, b, &mut report);
+            self.check_link(state, entities, b, a, &mut report);
+        }
+
+        report
+    }
+
+    /// Removes dead entities from every registered column that can drop them:
+    /// rows of `EntityMap` and `EntitySet`, `Option` references, and ids inside nested sets.
+    ///
+    /// Returns the references to dead entities that remain because their column cannot hold nothing.
+    pub fn sweep(&self, state: &mut S, entities: &E) -> Report {
+        let mut report = Report::default();
+
+        for column in self.registry.all_columns() {
+            let name = column.info().name;
+            let (table, target) = match self.tables_of(column) {
+                Some(tables) => tables,
+                None => {
+                    report.push(Problem::Unregistered { column: name });
+                    continue;
+                }
+            };
+
+            let key_alive = |id| table.is_alive(entities, id);
+            let target_alive = |id| target.is_none_or(|t| t.is_alive(entities, id));
+
+            column.sweep(state, &key_alive, &target_alive);
+
+            for row in table.living(entities) {
+                for id in column.refs(state, row) {
+                    if !target_alive(id) {
+                        report.push(Problem::Dangling { column: name, row, target: id });
+                    }
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Writes a Mermaid ER diagram of the registered tables, their columns and the relationships between them.
+    ///
+    /// Columns linked in `links!` become one relationship with the cardinality of each side, other reference
+    /// columns a relationship from their table only. Ownership is drawn as a solid line.
+    ///
+    /// Writes nothing and returns `Error::Unregistered` if a column's tables were not registered.
+    pub fn write_diagram<W: Write>(&self, mut output: W) -> Result<()> {
+        if let Some(column) = self.registry.all_columns().find(|column| self.tables_of(*column).is_none()) {
+            return Err(unregistered(column));
+        }
+
+        writeln!(output, "erDiagram")?;
+
+        for table in self.registry.all_tables() {
+            let mut columns = self.registry.columns_keyed(table.id_type()).peekable();
+            let name = short_name(table.info().id_type);
+            if columns.peek().is_none() {
+                writeln!(output, "    {}", name)?;
+                continue;
+            }
+
+            writeln!(output, "    {} {{", name)?;
+            for column in columns {
+                let info = column.info();
+                match (info.target, info.owner) {
+                    (None, _) => writeln!(output, "        value {}", info.name)?,
+                    (Some(_), false) => writeln!(output, "        ref {} FK", info.name)?,
+                    (Some(_), true) => writeln!(output, "        owner {} FK", info.name)?,
+                }
+            }
+            writeln!(output, "    }}")?;
+        }
+
+        for (a, b) in self.registry.linked_columns() {
+            let label = format!("{} / {}", a.info().name, b.info().name);
+            self.write_relationship(&mut output, a, Some(b), &label)?;
+        }
+
+        for column in self.registry.all_columns().filter(|column| column.target().is_some()) {
+            let name = column.info().name;
+            let linked = self.registry.linked_columns().any(|(a, b)| a.info().name == name || b.info().name == name);
+            if !linked {
+                self.write_relationship(&mut output, column, None, name)?;
+            }
+        }
+
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Writes the relationship of `forward` and its linked `back` column. Without one, any number of
+    /// entities may refer to each target.
+    fn write_relationship<W: Write>(
+        &self,
+        output: &mut W,
+        forward: &dyn AnyColumn<S>,
+        back: Option<&dyn AnyColumn<S>>,
+        label: &str,
+    ) -> Result<()> {
+        let (from, to) = self.tables_of(forward).ok_or_else(|| unregistered(forward))?;
+        let from = short_name(from.info().id_type);
+        let to = to.map(|to| short_name(to.info().id_type)).unwrap_or(from);
+
+        let (forward, back) = (forward.info(), back.map(|back| back.info()));
+        let left = match back.and_then(|back| back.cardinality) {
+            Some(Cardinality::One) => "||",
+            Some(Cardinality::Optional) => "|o",
+            _ => "}o",
+        };
+        let right = match forward.cardinality {
+            Some(Cardinality::One) => "||",
+            Some(Cardinality::Optional) => "o|",
+            _ => "o{",
+        };
+        let owned = forward.owner || back.is_some_and(|back| back.owner);
+        let line = if owned { "--" } else { ".." };
+
+        writeln!(output, "    {} {}{}{} {} : \"{}\"", from, left, line, right, to, label)?;
+        Ok(())
+    }
+
+    fn check_link(
+        &self,
+        state: &S,
+        entities: &E,
+        forward: &dyn AnyColumn<S>,
+        back: &dyn AnyColumn<S>,
+        report: &mut Report,
+    ) {
+        let (table, target) = match (self.tables_of(forward), self.tables_of(back)) {
+            (Some((table, _)), Some((target, _))) => (table, target),
+            _ => return,
+        };
+
+        for row in table.living(entities) {
+            for id in forward.refs(state, row) {
+                if !target.is_alive(entities, id) {
+                    continue;
+                }
+
+                if !back.has_row(state, id) || !back.refs(state, id).contains(&row) {
+                    report.push(Problem::Asymmetric {
+                        column: forward.info().name,
+                        row,
+                        target: id,
+                        back: back.info().name,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The table `column` is keyed by and the table it refers to, or `None` if either is not registered.
+    fn tables_of(&self, column: &dyn AnyColumn<S>) -> Option<Tables<'_, E>> {
+        let table = self.registry.table_of(column.id_type())?;
+        let target = match column.target() {
+            Some(target) => Some(self.registry.table_of(target)?),
+            None => None,
+        };
+        Some((table, target))
+    }
+}
+
+fn unregistered<S>(column: &dyn AnyColumn<S>) -> Error {
+    Error::Unregistered { column: column.info().name }
+}
+
+/// The id type's name without its module path.
+fn short_name(id_type: &'static str) -> &'static str {
+    id_type.rsplit("::").next().unwrap_or_default()
+}
+
+/// A single integrity violation found by `Schema::check`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Problem {
+    /// A living entity's row refers to a dead entity.
+    Dangling { column: &'static str, row: RawId, target: RawId },
+    /// One side of a link refers to an entity that does not refer back.
+    Asymmetric { column: &'static str, row: RawId, target: RawId, back: &'static str },
+    /// A living entity has no row in a column every entity needs.
+    Missing { column: &'static str, row: RawId },
+    /// A living entity's owner is missing or dead.
+    Orphan { column: &'static str, row: RawId },
+    /// The column's table, or the table it refers to, is not registered.
+    Unregistered { column: &'static str },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Dangling { column, row, target } =>
+                write!(f, "{}[{}] refers to dead entity {}", column, row, target),
+            Problem::Asymmetric { column, row, target, back } =>
+                write!(f, "{}[{}] refers to {}, but {}[{}] does not refer back", column, row, target, back, target),
+            Problem::Missing { column, row } =>
+                write!(f, "{}[{}] is missing", column, row),
+            Problem::Orphan { column, row } =>
+                write!(f, "{}[{}] has no living owner", column, row),
+            Problem::Unregistered { column } =>
+                write!(f, "{} refers to a table that is not registered", column),
+        }
+    }
+}
+
+/// The result of `Schema::check` or `Schema::sweep`.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, problem: Problem) {
+        self.problems.push(problem);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Allocator;
+    use crate::cascade::{Owns, OnDelete};
+
+    id_type!(ShipId, snapshot);
+    id_type!(CrewId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct Allocators {
+        ships: Allocator<ShipId>,
+        crew: Allocator<CrewId>,
+    }
+
+    entities!(Allocators, ships: ShipId, crew: CrewId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        ship_name: IndexedVec<ShipId, &'static str>,
+        ship_crew: IndexedVec<ShipId, EntitySet<CrewId>>,
+        ship_captain: EntityMap<ShipId, CrewId>,
+        crew_ship: IndexedVec<CrewId, ShipId>,
+    }
+
+    links! {
+        link_to_many(ShipId, ship_crew, CrewId, crew_ship, owns: Cascade);
+    }
+
+    fn with_columns(tables: Registry<State, Allocators>) -> Schema<State, Allocators> {
+        Schema::new(tables
+            .column(field!(State, ship_name))
+            .reference(field!(State, ship_captain))
+            .reference(field!(State, ship_crew))
+            .reference(field!(State, crew_ship))
+            .links())
+    }
+
+    fn schema() -> Schema<State, Allocators> {
+        with_columns(registry!(State, Allocators; tables: ships, crew))
+    }
+
+    fn setup() -> (State, Allocators, ShipId, CrewId) {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+
+        let ship = entities.ships.create_entity();
+        let crew = entities.crew.create_entity();
+        state.ship_name.insert(&ship, "Beagle");
+        state.ship_crew.insert(&ship, EntitySet::new());
+        Link::<ShipId, CrewId>::link(&mut state, &ship, &crew);
+        state.ship_captain.insert(&ship, crew.entity);
+
+        let (ship, crew) = (ship.entity, crew.entity);
+        (state, entities, ship, crew)
+    }
+
+    #[test]
+    fn field_errors_name_the_field() {
+        let (mut state, mut entities, ship, _) = setup();
+        let other = entities.ships.create_entity().entity;
+        let (ship, other) = (entities.ships.verify(ship).unwrap(), entities.ships.verify(other).unwrap());
+
+        assert_eq!("Beagle", *field!(State, ship_name).try_get(&state, &ship).unwrap());
+        *field!(State, ship_name).try_get_mut(&mut state, &ship).unwrap() = "Endeavour";
+        assert_eq!("Endeavour", state.ship_name[&ship]);
+        assert!(matches!(
+            field!(State, ship_captain).try_get(&state, &other),
+            Err(Error::MissingComponent { column: "ship_captain", .. })
+        ));
+    }
+
+    #[test]
+    fn consistent_state_is_ok() {
+        let (state, entities, _, _) = setup();
+
+        assert!(schema().check(&state, &entities).is_ok());
+    }
+
+    #[test]
+    fn dead_reference_is_dangling() {
+        let (state, mut entities, ship, crew) = setup();
+        entities.crew.kill(crew);
+
+        let report = schema().check(&state, &entities);
+
+        let ship = RawId::of(ship);
+        let crew = RawId::of(crew);
+        assert_eq!(vec![
+            Problem::Dangling { column: "ship_captain", row: ship, target: crew },
+            Problem::Dangling { column: "ship_crew", row: ship, target: crew },
+        ], report.problems);
+    }
+
+    #[test]
+    fn dead_owner_makes_orphan() {
+        let (state, mut entities, ship, crew) = setup();
+        entities.ships.kill(ship);
+
+        let report = schema().check(&state, &entities);
+
+        assert_eq!(vec![Problem::Orphan { column: "crew_ship", row: RawId::of(crew) }], report.problems);
+    }
+
+    #[test]
+    fn missing_dense_row() {
+        let (state, mut entities, _, _) = setup();
+        let ship = RawId::of(entities.ships.create_entity().entity);
+
+        let report = schema().check(&state, &entities);
+
+        assert_eq!(vec![
+            Problem::Missing { column: "ship_name", row: ship },
+            Problem::Missing { column: "ship_crew", row: ship },
+        ], report.problems);
+    }
+
+    #[test]
+    #[should_panic]
+    fn links_need_their_columns_registered() {
+        registry!(State, Allocators; tables: ships, crew).links();
+    }
+
+    #[test]
+    fn unregistered_table_is_reported() {
+        let (state, entities, _, _) = setup();
+        let schema = with_columns(registry!(State, Allocators; tables: ships));
+
+        let report = schema.check(&state, &entities);
+
+        assert_eq!(vec![
+            Problem::Unregistered { column: "ship_captain" },
+            Problem::Unregistered { column: "ship_crew" },
+            Problem::Unregistered { column: "crew_ship" },
+        ], report.problems);
+    }
+
+    #[test]
+    fn sweep_removes_dead_sparse_rows() {
+        let (mut state, mut entities, ship, crew) = setup();
+        entities.ships.kill(ship);
+
+        let report = schema().sweep(&mut state, &entities);
+
+        let crew = entities.crew.verify(crew).unwrap();
+        assert!(state.ship_captain.is_empty());
+        assert_eq!(vec![Problem::Dangling {
+            column: "crew_ship",
+            row: RawId::of(crew.entity),
+            target: RawId::of(ship),
+        }], report.problems);
+    }
+
+    #[test]
+    fn diagram_shows_columns_and_cardinalities() {
+        let mut diagram = vec![];
+        schema().write_diagram(&mut diagram).unwrap();
+
+        assert_eq!(concat!(
+            "erDiagram\n",
+            "    ShipId {\n",
+            "        value ship_name\n",
+            "        ref ship_captain FK\n",
+            "        ref ship_crew FK\n",
+            "    }\n",
+            "    CrewId {\n",
+            "        owner crew_ship FK\n",
+            "    }\n",
+            "    ShipId ||--o{ CrewId : \"ship_crew / crew_ship\"\n",
+            "    ShipId }o..o| CrewId : \"ship_captain\"\n",
+        ), String::from_utf8(diagram).unwrap());
+    }
+
+    #[test]
+    fn diagram_of_unregistered_table_is_an_error() {
+        let mut diagram = vec![];
+        let error = with_columns(registry!(State, Allocators; tables: ships)).write_diagram(&mut diagram).unwrap_err();
+
+        assert!(matches!(error, Error::Unregistered { column: "ship_captain" }));
+        assert!(diagram.is_empty());
+    }
+
+    #[test]
+    fn one_sided_link_is_asymmetric() {
+        let (mut state, entities, ship, crew) = setup();
+        state.ship_crew.values[0] = EntitySet::new();
+
+        let report = schema().check(&state, &entities);
+
+        assert_eq!(vec![Problem::Asymmetric {
+            column: "crew_ship",
+            row: RawId::of(crew),
+            target: RawId::of(ship),
+            back: "ship_crew",
+        }], report.problems);
+    }
+}
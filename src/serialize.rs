@@ -0,0 +1,155 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as _;
+use crate::traits::IdType;
+use crate::entities::{Allocator, Generation};
+use crate::error::Error;
+use crate::storage::{EntityMap, OrderedMap};
+
+impl Serialize for Generation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Generation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u32::deserialize(deserializer)?;
+
+        Generation::from_value(value).ok_or_else(|| D::Error::custom("generation must be non-zero"))
+    }
+}
+
+/// The fields of a serialized `Allocator`, checked by `Allocator::from_parts` before use.
+#[derive(Deserialize)]
+pub(crate) struct AllocatorParts<ID> {
+    generations: Vec<Generation>,
+    dead: Vec<usize>,
+    living: Vec<Option<ID>>,
+}
+
+impl<ID: IdType> std::convert::TryFrom<AllocatorParts<ID>> for Allocator<ID> {
+    type Error = Error;
+
+    fn try_from(parts: AllocatorParts<ID>) -> Result<Self, Error> {
+        Allocator::from_parts(parts.generations, parts.dead, parts.living)
+    }
+}
+
+/// Serialized as a sequence of `(id, value)` pairs, since ids are not valid map keys in every format.
+impl<ID: IdType + Serialize, T: Serialize> Serialize for EntityMap<ID, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.values.iter())
+    }
+}
+
+impl<'de, ID: IdType + Deserialize<'de>, T: Deserialize<'de>> Deserialize<'de> for EntityMap<ID, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pairs = Vec::<(ID, T)>::deserialize(deserializer)?;
+        Ok(EntityMap { values: pairs.into_iter().collect() })
+    }
+}
+
+impl<ID: IdType + Serialize, T: Serialize> Serialize for OrderedMap<ID, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.values.iter())
+    }
+}
+
+impl<'de, ID: IdType + Deserialize<'de>, T: Deserialize<'de>> Deserialize<'de> for OrderedMap<ID, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pairs = Vec::<(ID, T)>::deserialize(deserializer)?;
+        Ok(OrderedMap { values: pairs.into_iter().collect() })
+    }
+}
+
+/// Used by `id_type!` to serialize an id as `(index, generation)`.
+pub fn serialize_id<ID: IdType, S: Serializer>(id: &ID, serializer: S) -> Result<S::Ok, S::Error> {
+    (id.index() as u32, id.generation()).serialize(serializer)
+}
+
+/// Used by `id_type!` to deserialize an id from `(index, generation)`.
+pub fn deserialize_id<'de, ID: IdType, D: Deserializer<'de>>(deserializer: D) -> Result<ID, D::Error> {
+    let (index, generation) = <(u32, Generation)>::deserialize(deserializer)?;
+    Ok(ID::create(index as usize, generation))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    id_type!(ShipId);
+
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+        let json = serde_json::to_string(value).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn allocator() -> Allocator<ShipId> {
+        let mut ships = Allocator::new();
+        let first = ships.create_entity().entity;
+        ships.create_entity();
+        ships.create_entity();
+        ships.kill(first);
+        ships
+    }
+
+    #[test]
+    fn ids_are_index_and_generation() {
+        let mut ships = allocator();
+        let reused = ships.create_entity().entity;
+
+        assert_eq!("[0,2]", serde_json::to_string(&reused).unwrap());
+        assert_eq!(reused, round_trip(&reused));
+    }
+
+    #[test]
+    fn zero_generation_is_rejected() {
+        assert!(serde_json::from_str::<ShipId>("[0,0]").is_err());
+    }
+
+    #[test]
+    fn allocator_round_trips_exactly() {
+        let ships = allocator();
+        let mut loaded = round_trip(&ships);
+
+        assert_eq!(ships, loaded);
+        for id in ships.ids() {
+            assert!(loaded.is_alive(id.entity));
+        }
+
+        let mut ships = ships;
+        assert_eq!(ships.create_entity().entity, loaded.create_entity().entity);
+    }
+
+    #[test]
+    fn inconsistent_allocator_is_rejected() {
+        let json = serde_json::to_string(&allocator()).unwrap();
+        assert_eq!(r#"{"generations":[2,1,1],"dead":[0],"living":[null,[1,1],[2,1]]}"#, json);
+
+        let dead_twice = json.replace(r#""dead":[0]"#, r#""dead":[0,0]"#);
+        let living_in_wrong_slot = json.replace("[1,1],[2,1]", "[2,1],[1,1]");
+        assert!(serde_json::from_str::<Allocator<ShipId>>(&dead_twice).is_err());
+        assert!(serde_json::from_str::<Allocator<ShipId>>(&living_in_wrong_slot).is_err());
+    }
+
+    #[test]
+    fn storage_round_trips() {
+        let mut ships = Allocator::new();
+        ships.create_entity();
+        ships.create_entity();
+        let ids: Vec<_> = ships.ids().collect();
+
+        let mut names = IndexedVec::<ShipId, String>::new();
+        let mut escorts = EntityMap::<ShipId, ShipId>::new();
+        let mut docked = EntitySet::<ShipId>::new();
+        for id in &ids {
+            names.insert(id, format!("ship {}", id.entity.index()));
+            docked.insert(id.entity);
+        }
+        escorts.insert(&ids[0], ids[1].entity);
+
+        assert_eq!(names.values, round_trip(&names).values);
+        assert_eq!(escorts.values, round_trip(&escorts).values);
+        assert_eq!(docked.values, round_trip(&docked).values);
+    }
+}
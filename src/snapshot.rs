@@ -0,0 +1,768 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Write};
+use crate::traits::IdType;
+use crate::entities::{Allocator, Generation, RawId};
+use crate::storage::*;
+use crate::error::{Error, Result};
+
+const MAGIC: &[u8; 4] = b"RECS";
+const FORMAT_VERSION: u16 = 1;
+
+/// Why a snapshot could not be written or read, nested in `Error::Snapshot`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// The input does not start with the snapshot header.
+    NotASnapshot,
+    /// The snapshot was written by a newer, unknown version of the format.
+    UnsupportedFormat(u16),
+    MissingSection(String),
+    /// Two sections share a name.
+    DuplicateSection(String),
+    /// Sections cannot be named with the empty string, which marks the end of a snapshot.
+    EmptySectionName,
+    /// The snapshot's schema version is newer than the one being loaded.
+    NewerSchema(u32),
+    /// No migration step is registered for the schema version.
+    MissingMigration(u32),
+    /// The section holds a different type than the one asked for.
+    TypeMismatch { name: String, expected: String, found: String },
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedFormat(version) => write!(f, "unsupported snapshot format: {}", version),
+            SnapshotError::MissingSection(name) => write!(f, "missing snapshot section: {}", name),
+            SnapshotError::DuplicateSection(name) => write!(f, "snapshot section written twice: {}", name),
+            SnapshotError::EmptySectionName => write!(f, "snapshot sections need a name"),
+            SnapshotError::NewerSchema(version) => write!(f, "snapshot schema {} is newer than this build", version),
+            SnapshotError::MissingMigration(version) => write!(f, "no migration from schema {}", version),
+            SnapshotError::TypeMismatch { name, expected, found } =>
+                write!(f, "{}: expected {} found {}", name, expected, found),
+            SnapshotError::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Names the encoded type of a value, so a section is only decoded as the type it was written as.
+pub trait Tag {
+    fn tag() -> String;
+}
+
+pub trait Encode: Tag {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Tag + Sized {
+    fn decode(input: &mut &[u8]) -> Result<Self>;
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(SnapshotError::Corrupt("unexpected end of section").into());
+    }
+
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    (len as u64).encode(out);
+}
+
+/// Reads a length without trusting it: elements are decoded one at a time, so a corrupt length
+/// fails at the end of the section instead of allocating for elements that are not there.
+pub(crate) fn decode_len(input: &mut &[u8]) -> Result<usize> {
+    Ok(u64::decode(input)? as usize)
+}
+
+macro_rules! number {
+    ($($type:ty),*) => {
+        $(
+            impl Tag for $type {
+                fn tag() -> String {
+                    stringify!($type).to_string()
+                }
+            }
+
+            impl Encode for $type {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Decode for $type {
+                fn decode(input: &mut &[u8]) -> Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$type>()];
+                    bytes.copy_from_slice(take(input, std::mem::size_of::<$type>())?);
+                    Ok(<$type>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Tag for bool {
+    fn tag() -> String {
+        "bool".to_string()
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode(out);
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt("invalid bool").into()),
+        }
+    }
+}
+
+impl Tag for () {
+    fn tag() -> String {
+        "unit".to_string()
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode(_: &mut &[u8]) -> Result<Self> {
+        Ok(())
+    }
+}
+
+impl Tag for String {
+    fn tag() -> String {
+        "string".to_string()
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = decode_len(input)?;
+        let bytes = take(input, len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| SnapshotError::Corrupt("invalid utf-8").into())
+    }
+}
+
+impl<T: Tag> Tag for Option<T> {
+    fn tag() -> String {
+        format!("option<{}>", T::tag())
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                true.encode(out);
+                value.encode(out);
+            }
+            None => false.encode(out),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        if bool::decode(input)? {
+            T::decode(input).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Tag> Tag for Vec<T> {
+    fn tag() -> String {
+        format!("list<{}>", T::tag())
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        for value in self {
+            value.encode(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = decode_len(input)?;
+        (0..len).map(|_| T::decode(input)).collect()
+    }
+}
+
+impl<A: Tag, B: Tag> Tag for (A, B) {
+    fn tag() -> String {
+        format!("({},{})", A::tag(), B::tag())
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+/// Used by `id_type!` to encode an id as its index and generation.
+pub fn encode_id<ID: IdType>(id: &ID, out: &mut Vec<u8>) {
+    (id.index() as u32).encode(out);
+    id.generation().value().encode(out);
+}
+
+/// Used by `id_type!` to decode an id from its index and generation.
+pub fn decode_id<ID: IdType>(input: &mut &[u8]) -> Result<ID> {
+    let index = u32::decode(input)?;
+    let generation = decode_generation(input)?;
+
+    Ok(ID::create(index as usize, generation))
+}
+
+fn decode_generation(input: &mut &[u8]) -> Result<Generation> {
+    Generation::from_value(u32::decode(input)?).ok_or_else(|| SnapshotError::Corrupt("zero generation").into())
+}
+
+impl Tag for RawId {
+    fn tag() -> String {
+        "id".to_string()
+    }
+}
+
+impl Encode for RawId {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.index as u32).encode(out);
+        self.generation.value().encode(out);
+    }
+}
+
+impl Decode for RawId {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let index = u32::decode(input)? as usize;
+        let generation = Generation::from_value(u32::decode(input)?).ok_or(SnapshotError::Corrupt("zero generation"))?;
+        Ok(RawId { index, generation })
+    }
+}
+
+impl<ID: IdType + Tag> Tag for Allocator<ID> {
+    fn tag() -> String {
+        format!("allocator<{}>", ID::tag())
+    }
+}
+
+impl<ID: IdType + Encode> Encode for Allocator<ID> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.generations.len(), out);
+        for generation in &self.generations {
+            generation.value().encode(out);
+        }
+
+        encode_len(self.dead.len(), out);
+        for index in &self.dead {
+            (*index as u32).encode(out);
+        }
+
+        self.living.encode(out);
+    }
+}
+
+impl<ID: IdType + Decode> Decode for Allocator<ID> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = decode_len(input)?;
+        let generations = (0..len).map(|_| decode_generation(input)).collect::<Result<Vec<_>>>()?;
+
+        let len = decode_len(input)?;
+        let dead = (0..len).map(|_| u32::decode(input).map(|index| index as usize)).collect::<Result<Vec<_>>>()?;
+
+        let living = Vec::<Option<ID>>::decode(input)?;
+
+        Allocator::from_parts(generations, dead, living).map_err(|error| match error {
+            Error::Invalid(reason) => SnapshotError::Corrupt(reason).into(),
+            error => error,
+        })
+    }
+}
+
+impl<ID: IdType + Tag, T: Tag> Tag for IndexedVec<ID, T> {
+    fn tag() -> String {
+        format!("vec<{},{}>", ID::tag(), T::tag())
+    }
+}
+
+impl<ID: IdType + Tag, T: Encode> Encode for IndexedVec<ID, T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.values.encode(out);
+    }
+}
+
+impl<ID: IdType + Tag, T: Decode> Decode for IndexedVec<ID, T> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let mut column = IndexedVec::new();
+        column.values = Vec::decode(input)?;
+        Ok(column)
+    }
+}
+
+impl<ID: IdType + Tag, T: Tag> Tag for EntityMap<ID, T> {
+    fn tag() -> String {
+        format!("map<{},{}>", ID::tag(), T::tag())
+    }
+}
+
+/// Rows are written in id order, so equal maps give equal snapshots.
+impl<ID: IdType + Encode, T: Encode> Encode for EntityMap<ID, T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut rows: Vec<_> = self.values.iter().collect();
+        rows.sort_unstable_by_key(|(id, _)| **id);
+
+        encode_len(rows.len(), out);
+        for (id, value) in rows {
+            id.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl<ID: IdType + Decode, T: Decode> Decode for EntityMap<ID, T> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = decode_len(input)?;
+
+        let mut column = EntityMap::new();
+        for _ in 0..len {
+            let id = ID::decode(input)?;
+            column.values.insert(id, T::decode(input)?);
+        }
+        Ok(column)
+    }
+}
+
+impl<ID: IdType + Tag> Tag for EntitySet<ID> {
+    fn tag() -> String {
+        format!("set<{}>", ID::tag())
+    }
+}
+
+impl<ID: IdType + Encode> Encode for EntitySet<ID> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut ids: Vec<_> = self.values.iter().copied().collect();
+        ids.sort_unstable();
+        ids.encode(out);
+    }
+}
+
+impl<ID: IdType + Decode> Decode for EntitySet<ID> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let ids = Vec::<ID>::decode(input)?;
+        Ok(EntitySet { values: ids.into_iter().collect() })
+    }
+}
+
+/// Shares its tag and bytes with `EntityMap`, so a column can switch between the two.
+impl<ID: IdType + Tag, T: Tag> Tag for OrderedMap<ID, T> {
+    fn tag() -> String {
+        EntityMap::<ID, T>::tag()
+    }
+}
+
+impl<ID: IdType + Encode, T: Encode> Encode for OrderedMap<ID, T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.values.len(), out);
+        for (id, value) in &self.values {
+            id.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl<ID: IdType + Decode, T: Decode> Decode for OrderedMap<ID, T> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = decode_len(input)?;
+
+        let mut column = OrderedMap::new();
+        for _ in 0..len {
+            let id = ID::decode(input)?;
+            column.values.insert(id, T::decode(input)?);
+        }
+        Ok(column)
+    }
+}
+
+/// Shares its tag and bytes with `EntitySet`, so a column can switch between the two.
+impl<ID: IdType + Tag> Tag for OrderedSet<ID> {
+    fn tag() -> String {
+        EntitySet::<ID>::tag()
+    }
+}
+
+impl<ID: IdType + Encode> Encode for OrderedSet<ID> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_len(self.values.len(), out);
+        for id in &self.values {
+            id.encode(out);
+        }
+    }
+}
+
+impl<ID: IdType + Decode> Decode for OrderedSet<ID> {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let ids = Vec::<ID>::decode(input)?;
+        Ok(OrderedSet { values: ids.into_iter().collect() })
+    }
+}
+
+/// Writes a snapshot section by section, straight from the world's storage.
+///
+/// The header holds a magic number, the format version and the caller's schema version.
+/// Each section holds a name, the tag of its type and its encoded bytes.
+pub struct SnapshotWriter<W: Write> {
+    output: W,
+    buffer: Vec<u8>,
+    names: HashSet<String>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(mut output: W, schema_version: u32) -> Result<Self> {
+        let mut buffer = MAGIC.to_vec();
+        FORMAT_VERSION.encode(&mut buffer);
+        schema_version.encode(&mut buffer);
+        output.write_all(&buffer)?;
+
+        Ok(SnapshotWriter { output, buffer: vec![], names: HashSet::new() })
+    }
+
+    /// Writes `value`, usually an allocator or a column, as the section `name`.
+    /// The empty name is reserved for the end of the snapshot, and each other name can be written once.
+    pub fn write<T: Encode>(&mut self, name: &str, value: &T) -> Result<()> {
+        if name.is_empty() {
+            return Err(SnapshotError::EmptySectionName.into());
+        }
+        if !self.names.insert(name.to_string()) {
+            return Err(SnapshotError::DuplicateSection(name.to_string()).into());
+        }
+        self.buffer.clear();
+        value.encode(&mut self.buffer);
+
+        self.section(name, &T::tag())
+    }
+
+    fn section(&mut self, name: &str, tag: &str) -> Result<()> {
+        let mut header = vec![];
+        name.to_string().encode(&mut header);
+        tag.to_string().encode(&mut header);
+        encode_len(self.buffer.len(), &mut header);
+
+        self.output.write_all(&header)?;
+        self.output.write_all(&self.buffer)?;
+        Ok(())
+    }
+
+    /// Ends the snapshot and returns the output.
+    pub fn finish(mut self) -> Result<W> {
+        self.buffer.clear();
+        self.section("", "")?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+struct Section {
+    tag: String,
+    bytes: Vec<u8>,
+}
+
+/// Reads every section of a snapshot, then decodes them by name.
+///
+/// Sections can be read in any order, and sections that are never asked for are ignored,
+/// so a reader can check `schema_version` and `tag` to load older snapshots.
+pub struct SnapshotReader {
+    schema_version: u32,
+    names: Vec<String>,
+    sections: HashMap<String, Section>,
+}
+
+impl SnapshotReader {
+    pub fn new<R: Read>(mut input: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic).map_err(|_| SnapshotError::NotASnapshot)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot.into());
+        }
+
+        let format_version = u16::decode(&mut &read_exact(&mut input, 2)?[..])?;
+        if format_version > FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedFormat(format_version).into());
+        }
+        let schema_version = u32::decode(&mut &read_exact(&mut input, 4)?[..])?;
+
+        let mut names = vec![];
+        let mut sections = HashMap::new();
+        loop {
+            let name = read_string(&mut input)?;
+            let tag = read_string(&mut input)?;
+            let len = u64::decode(&mut &read_exact(&mut input, 8)?[..])? as usize;
+            let bytes = read_exact(&mut input, len)?;
+
+            if name.is_empty() {
+                break;
+            }
+            if sections.contains_key(&name) {
+                return Err(SnapshotError::DuplicateSection(name).into());
+            }
+            names.push(name.clone());
+            sections.insert(name, Section { tag, bytes });
+        }
+
+        Ok(SnapshotReader { schema_version, names, sections })
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Section names in the order they were written.
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.names.iter().map(|name| name.as_str())
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.sections.get(name).map(|section| section.tag.as_str())
+    }
+
+    /// The encoded bytes of the section `name`, for tools that decode sections by their tag.
+    pub fn bytes(&self, name: &str) -> Option<&[u8]> {
+        self.sections.get(name).map(|section| &section.bytes[..])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sections.contains_key(name)
+    }
+
+    /// Decodes the section `name`, which must have been written as a `T`.
+    pub fn read<T: Decode>(&self, name: &str) -> Result<T> {
+        let section = self.sections.get(name)
+            .ok_or_else(|| SnapshotError::MissingSection(name.to_string()))?;
+
+        let expected = T::tag();
+        if section.tag != expected {
+            return Err(SnapshotError::TypeMismatch {
+                name: name.to_string(),
+                expected,
+                found: section.tag.clone(),
+            }.into());
+        }
+
+        let mut input = &section.bytes[..];
+        let value = T::decode(&mut input)?;
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt("trailing bytes in section").into());
+        }
+        Ok(value)
+    }
+
+    /// Adds the section `name`, or replaces it keeping its place.
+    pub fn insert<T: Encode>(&mut self, name: &str, value: &T) {
+        let mut bytes = vec![];
+        value.encode(&mut bytes);
+
+        if !self.contains(name) {
+            self.names.push(name.to_string());
+        }
+        self.sections.insert(name.to_string(), Section { tag: T::tag(), bytes });
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.sections.remove(name).ok_or_else(|| SnapshotError::MissingSection(name.to_string()))?;
+        self.names.retain(|n| n != name);
+        Ok(())
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let section = self.sections.remove(from).ok_or_else(|| SnapshotError::MissingSection(from.to_string()))?;
+
+        self.names.retain(|n| n != to);
+        for name in &mut self.names {
+            if name == from {
+                *name = to.to_string();
+            }
+        }
+        self.sections.insert(to.to_string(), section);
+        Ok(())
+    }
+
+    pub(crate) fn set_schema_version(&mut self, version: u32) {
+        self.schema_version = version;
+    }
+}
+
+fn read_exact<R: Read>(input: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    input.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() < len {
+        return Err(SnapshotError::Corrupt("unexpected end of snapshot").into());
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(input: &mut R) -> Result<String> {
+    let len = u64::decode(&mut &read_exact(input, 8)?[..])? as usize;
+    let bytes = read_exact(input, len)?;
+
+    String::from_utf8(bytes).map_err(|_| SnapshotError::Corrupt("invalid utf-8").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::*;
+
+    id_type!(ShipId, snapshot);
+
+    #[derive(Debug, Default)]
+    struct World {
+        ships: Allocator<ShipId>,
+        ship_name: IndexedVec<ShipId, String>,
+        ship_escort: EntityMap<ShipId, ShipId>,
+        docked: EntitySet<ShipId>,
+    }
+
+    fn world() -> World {
+        let mut world = World::default();
+
+        for name in &["Victory", "Beagle", "Endeavour"] {
+            let ship = world.ships.create_entity();
+            world.ship_name.insert(&ship, name.to_string());
+        }
+        let ids: Vec<_> = world.ships.ids().map(|id| id.entity).collect();
+        world.ship_escort.values.insert(ids[0], ids[1]);
+        world.docked.insert(ids[2]);
+        world.ships.kill(ids[1]);
+
+        world
+    }
+
+    fn save(world: &World) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(vec![], 3).unwrap();
+        writer.write("ships", &world.ships).unwrap();
+        writer.write("ship_name", &world.ship_name).unwrap();
+        writer.write("ship_escort", &world.ship_escort).unwrap();
+        writer.write("docked", &world.docked).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let world = world();
+        let reader = SnapshotReader::new(&save(&world)[..]).unwrap();
+
+        assert_eq!(3, reader.schema_version());
+        assert_eq!(vec!["ships", "ship_name", "ship_escort", "docked"], reader.names().collect::<Vec<_>>());
+        assert_eq!(Some("vec<ShipId,string>"), reader.tag("ship_name"));
+
+        assert_eq!(world.ships, reader.read("ships").unwrap());
+        assert_eq!(world.ship_name.values, reader.read::<IndexedVec<ShipId, String>>("ship_name").unwrap().values);
+        assert_eq!(world.ship_escort.values, reader.read::<EntityMap<ShipId, ShipId>>("ship_escort").unwrap().values);
+        assert_eq!(world.docked.values, reader.read::<EntitySet<ShipId>>("docked").unwrap().values);
+    }
+
+    #[test]
+    fn zero_sized_elements_round_trip() {
+        let units = vec![(); 3];
+        let mut bytes = vec![];
+        units.encode(&mut bytes);
+
+        assert_eq!(units, Vec::<()>::decode(&mut &bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn equal_worlds_give_equal_snapshots() {
+        assert_eq!(save(&world()), save(&world()));
+    }
+
+    #[test]
+    fn ordered_storage_reads_unordered_sections() {
+        let world = world();
+        let reader = SnapshotReader::new(&save(&world)[..]).unwrap();
+
+        let escort = reader.read::<OrderedMap<ShipId, ShipId>>("ship_escort").unwrap();
+        let docked = reader.read::<OrderedSet<ShipId>>("docked").unwrap();
+        assert_eq!(world.ship_escort.values, escort.values.into_iter().collect());
+        assert_eq!(world.docked.values, docked.values.into_iter().collect());
+    }
+
+    #[test]
+    fn wrong_type_is_an_error() {
+        let reader = SnapshotReader::new(&save(&world())[..]).unwrap();
+
+        match reader.read::<IndexedVec<ShipId, u32>>("ship_name") {
+            Err(Error::Snapshot(SnapshotError::TypeMismatch { found, .. })) => assert_eq!("vec<ShipId,string>", found),
+            other => panic!("expected a type mismatch: {:?}", other.map(|column| column.values)),
+        }
+        assert!(matches!(reader.read::<Allocator<ShipId>>("fleets"), Err(Error::Snapshot(SnapshotError::MissingSection(_)))));
+    }
+
+    #[test]
+    fn bad_input_is_an_error() {
+        let mut bytes = save(&world());
+
+        assert!(matches!(SnapshotReader::new(&b"PNG"[..]), Err(Error::Snapshot(SnapshotError::NotASnapshot))));
+        assert!(matches!(SnapshotReader::new(&bytes[..bytes.len() - 1]), Err(Error::Snapshot(SnapshotError::Corrupt(_)))));
+
+        bytes[4] = 99;
+        assert!(matches!(SnapshotReader::new(&bytes[..]), Err(Error::Snapshot(SnapshotError::UnsupportedFormat(99)))));
+    }
+
+    #[test]
+    fn section_names_are_checked() {
+        let world = world();
+        let mut writer = SnapshotWriter::new(vec![], 3).unwrap();
+
+        assert!(matches!(writer.write("", &world.ships), Err(Error::Snapshot(SnapshotError::EmptySectionName))));
+
+        writer.write("ships", &world.ships).unwrap();
+        assert!(matches!(writer.write("ships", &world.ships), Err(Error::Snapshot(SnapshotError::DuplicateSection(_)))));
+        writer.write("shipz", &world.ships).unwrap();
+        let mut bytes = writer.finish().unwrap();
+
+        let renamed = bytes.windows(5).rposition(|name| name == b"shipz").unwrap();
+        bytes[renamed + 4] = b's';
+        match SnapshotReader::new(&bytes[..]) {
+            Err(Error::Snapshot(SnapshotError::DuplicateSection(name))) => assert_eq!("ships", name),
+            other => panic!("expected a duplicate section: {:?}", other.map(|reader| reader.schema_version())),
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use super::*;
 use rustc_hash::FxHashMap;
-use crate::entities::Allocator;
+use crate::entities::{Allocator, RawId};
+use crate::error::{Error, Result};
 
-#[derive(Debug, Clone)]
+/// Iteration order depends on insertion history. Use `OrderedMap` where runs must iterate identically.
+#[derive(Debug, Clone, PartialEq)]
 pub struct EntityMap<ID: IdType, T> { pub values: FxHashMap<ID, T> }
 
 impl<ID: IdType, T> Default for EntityMap<ID, T> {
@@ -16,7 +18,7 @@ impl<ID: IdType, T> EntityMap<ID, T> {
         Self::default()
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<ID, T> {
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, ID, T> {
         self.values.iter()
     }
 
@@ -32,6 +34,44 @@ impl<ID: IdType, T> EntityMap<ID, T> {
         self.values.remove(&id.entity)
     }
 
+    /// Inserts `value` for `id`, failing if `id` already has a value.
+    pub fn try_insert(&mut self, id: &VerifiedEntity<ID>, value: T) -> Result<()> {
+        if self.values.contains_key(&id.entity) {
+            return Err(Error::LinkConflict {
+                column: std::any::type_name::<Self>(),
+                id: RawId::of(id.entity),
+            });
+        }
+
+        self.values.insert(id.entity, value);
+        Ok(())
+    }
+
+    pub fn try_remove(&mut self, id: &VerifiedEntity<ID>) -> Result<T> {
+        self.values
+            .remove(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    pub fn try_index(&self, id: &VerifiedEntity<ID>) -> Result<&T> {
+        self.values
+            .get(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    pub fn try_index_mut(&mut self, id: &VerifiedEntity<ID>) -> Result<&mut T> {
+        self.values
+            .get_mut(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    fn missing(id: ID) -> Error {
+        Error::MissingComponent {
+            column: std::any::type_name::<Self>(),
+            id: RawId::of(id),
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.values.len()
     }
@@ -44,7 +84,7 @@ impl<ID: IdType, T> EntityMap<ID, T> {
         self.values.retain(|id, _| allocator.is_alive(*id))
     }
 
-    pub fn retain_verified<'a>(&'a mut self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &T)> {
+    pub fn retain_verified<'a>(&'a mut self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &'a T)> {
         self.retain(allocator);
         self.values
             .iter()
@@ -53,7 +93,7 @@ impl<ID: IdType, T> EntityMap<ID, T> {
             })
     }
 
-    pub fn verified<'a>(&'a self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &T)> {
+    pub fn verified<'a>(&'a self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &'a T)> {
         self.values
             .iter()
             .filter_map(move |(id, t)| {
@@ -104,4 +144,28 @@ impl<ID: IdType, T> Get<ID, T> for EntityMap<ID, T> {
     fn get_mut(&mut self, id: &VerifiedEntity<ID>) -> Option<&mut T> {
         self.values.get_mut(&id.entity)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    id_type!(ShipId);
+
+    #[test]
+    fn try_methods_report_missing_and_existing_values() {
+        let mut ships = Allocator::<ShipId>::new();
+        let id = ships.create_entity().entity;
+        let id = ships.verify(id).unwrap();
+        let mut names = EntityMap::new();
+
+        assert!(matches!(names.try_index(&id), Err(Error::MissingComponent { .. })));
+        names.try_insert(&id, "Victory").unwrap();
+        assert!(matches!(names.try_insert(&id, "Beagle"), Err(Error::LinkConflict { .. })));
+
+        *names.try_index_mut(&id).unwrap() = "Beagle";
+        assert_eq!("Beagle", *names.try_index(&id).unwrap());
+        assert_eq!("Beagle", names.try_remove(&id).unwrap());
+        assert!(matches!(names.try_remove(&id), Err(Error::MissingComponent { .. })));
+    }
+}
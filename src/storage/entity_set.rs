@@ -1,8 +1,11 @@
 use super::*;
 use rustc_hash::FxHashSet;
-use crate::entities::Allocator;
+use crate::entities::{Allocator, RawId};
+use crate::error::{Error, Result};
 
-#[derive(Debug, Clone)]
+/// Iteration order depends on insertion history. Use `OrderedSet` where runs must iterate identically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
 pub struct EntitySet<ID: IdType> { pub values: FxHashSet<ID> }
 
 impl<ID: IdType> Default for EntitySet<ID> {
@@ -16,7 +19,7 @@ impl<ID: IdType> EntitySet<ID> {
         Self::default()
     }
 
-    pub fn iter(&self) -> std::collections::hash_set::Iter<ID> {
+    pub fn iter(&self) -> std::collections::hash_set::Iter<'_, ID> {
         self.values.iter()
     }
 
@@ -36,6 +39,20 @@ impl<ID: IdType> EntitySet<ID> {
         }
     }
 
+    /// Inserts `value`, failing if it is already in the set.
+    pub fn try_insert(&mut self, value: ID) -> Result<()> {
+        if self.values.insert(value) {
+            Ok(())
+        } else {
+            Err(Error::LinkConflict { column: std::any::type_name::<Self>(), id: RawId::of(value) })
+        }
+    }
+
+    pub fn try_remove(&mut self, value: &ID) -> Result<ID> {
+        self.remove(value)
+            .ok_or_else(|| Error::MissingComponent { column: std::any::type_name::<Self>(), id: RawId::of(*value) })
+    }
+
     pub fn contains(&self, value: &ID) -> bool {
         self.values.contains(value)
     }
@@ -65,3 +82,25 @@ impl<ID: IdType> EntitySet<ID> {
             .filter_map(move |id| allocator.verify(*id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    id_type!(ShipId);
+
+    #[test]
+    fn try_methods_report_missing_and_existing_ids() {
+        let mut ships = Allocator::<ShipId>::new();
+        let id = ships.create_entity().entity;
+        let mut docked = EntitySet::new();
+
+        docked.try_insert(id).unwrap();
+        match docked.try_insert(id) {
+            Err(Error::LinkConflict { id: raw, .. }) => assert_eq!(RawId::of(id), raw),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(id, docked.try_remove(&id).unwrap());
+        assert!(matches!(docked.try_remove(&id), Err(Error::MissingComponent { .. })));
+    }
+}
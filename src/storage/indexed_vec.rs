@@ -1,9 +1,11 @@
 use std::marker::PhantomData;
 use super::*;
 use std::ops::{Index, IndexMut};
-use crate::entities::Allocator;
+use crate::entities::{Allocator, RawId};
+use crate::error::{Error, Result};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
 pub struct IndexedVec<ID: IdType, T> {
     pub values: Vec<T>,
     marker: PhantomData<ID>,
@@ -29,6 +31,43 @@ impl<ID: IdType, T> IndexedVec<ID, T> {
             marker: PhantomData,
         }
     }
+
+    /// Adds the row for `id`, failing if `id` already has a row or if that would leave a gap in the rows.
+    /// `insert` overwrites an existing row instead.
+    pub fn try_insert(&mut self, id: &VerifiedEntity<ID>, value: T) -> Result<()> {
+        let index = id.entity.index();
+        match self.values.len() {
+            len if len > index => return Err(Error::LinkConflict {
+                column: std::any::type_name::<Self>(),
+                id: RawId::of(id.entity),
+            }),
+            len if len == index => self.values.push(value),
+            len => return Err(self.out_of_bounds(id.entity, len)),
+        };
+        Ok(())
+    }
+
+    pub fn try_index(&self, id: &VerifiedEntity<ID>) -> Result<&T> {
+        let len = self.values.len();
+        self.values
+            .get(id.entity.index())
+            .ok_or_else(|| self.out_of_bounds(id.entity, len))
+    }
+
+    pub fn try_index_mut(&mut self, id: &VerifiedEntity<ID>) -> Result<&mut T> {
+        let error = self.out_of_bounds(id.entity, self.values.len());
+        self.values
+            .get_mut(id.entity.index())
+            .ok_or(error)
+    }
+
+    fn out_of_bounds(&self, id: ID, len: usize) -> Error {
+        Error::IndexOutOfBounds {
+            column: std::any::type_name::<Self>(),
+            id: RawId::of(id),
+            len,
+        }
+    }
 }
 
 impl<ID: IdType, T> Get<ID, T> for IndexedVec<ID, T> {
@@ -43,12 +82,12 @@ impl<ID: IdType, T> Get<ID, T> for IndexedVec<ID, T> {
 
 impl<ID: IdType, T> Insert<ID, T> for IndexedVec<ID, T> {
     fn insert(&mut self, id: &VerifiedEntity<ID>, value: T) {
-        let id = id.entity;
-        match self.values.len() {
-            len if len > id.index() => self.values[id.index()] = value,
-            len if len == id.index() => self.values.push(value),
-            _ => panic!(format!("entity index out of bounds: {:?} len: {}", id, self.values.len())),
-        };
+        match self.values.get_mut(id.entity.index()) {
+            Some(row) => *row = value,
+            None => if let Err(error) = self.try_insert(id, value) {
+                panic!("{}", error);
+            },
+        }
     }
 }
 
@@ -85,7 +124,7 @@ impl<A: IdType, B: IdType> IndexedVec<A, B> {
         &'a self,
         allocator_a: &'a Allocator<A>,
         allocator_b: &'a Allocator<B>,
-    ) -> impl Iterator<Item=(VerifiedEntity<A>, VerifiedEntity<B>)> {
+    ) -> impl Iterator<Item=(VerifiedEntity<'a, A>, VerifiedEntity<'a, B>)> {
         allocator_a
             .ids()
             .filter_map(move |a| {
@@ -128,6 +167,42 @@ mod tests {
         storage.insert(&id, 5);
     }
 
+    #[test]
+    fn try_insert_invalid_entity_is_out_of_bounds() {
+        let mut allocator = Allocator::<TestId>::new();
+        let mut storage = IndexedVec::<TestId, u32>::new();
+
+        let _id = allocator.create_entity();
+        let id = allocator.create_entity();
+
+        match storage.try_insert(&id, 5) {
+            Err(Error::IndexOutOfBounds { id: raw, len: 0, .. }) => assert_eq!(RawId::of(id.entity), raw),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn try_insert_existing_row_is_a_conflict() {
+        let mut allocator = Allocator::<TestId>::new();
+        let mut storage = IndexedVec::<TestId, u32>::new();
+
+        let id = allocator.create_entity();
+
+        storage.try_insert(&id, 2).unwrap();
+        assert!(matches!(storage.try_insert(&id, 3), Err(Error::LinkConflict { .. })));
+        assert_eq!(Some(&2), storage.get(&id));
+    }
+
+    #[test]
+    fn try_index_missing_row() {
+        let mut allocator = Allocator::<TestId>::new();
+        let storage = IndexedVec::<TestId, u32>::new();
+
+        let id = allocator.create_entity();
+
+        assert!(storage.try_index(&id).is_err());
+    }
+
     #[test]
     fn insert_to_update_value() {
         let mut allocator = Allocator::<TestId>::new();
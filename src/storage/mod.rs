@@ -4,7 +4,11 @@ use crate::entities::VerifiedEntity;
 pub use self::indexed_vec::IndexedVec;
 pub use self::entity_set::EntitySet;
 pub use self::entity_map::EntityMap;
+pub use self::ordered_set::OrderedSet;
+pub use self::ordered_map::OrderedMap;
 
 mod indexed_vec;
 mod entity_set;
-mod entity_map;
\ No newline at end of file
+mod entity_map;
+mod ordered_set;
+mod ordered_map;
\ No newline at end of file
@@ -0,0 +1,207 @@
+use super::*;
+use std::collections::BTreeMap;
+use crate::entities::{Allocator, RawId};
+use crate::error::{Error, Result};
+
+/// An `EntityMap` that always iterates in id order, by index and then generation.
+///
+/// The order does not depend on insertion history, so runs that hold the same values iterate
+/// identically, as lockstep simulations and replays require. Lookups are `O(log n)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderedMap<ID: IdType, T> { pub values: BTreeMap<ID, T> }
+
+impl<ID: IdType, T> Default for OrderedMap<ID, T> {
+    fn default() -> Self {
+        OrderedMap { values: Default::default() }
+    }
+}
+
+impl<ID: IdType, T> OrderedMap<ID, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, ID, T> {
+        self.values.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn insert(&mut self, id: &VerifiedEntity<ID>, value: T) {
+        self.values.insert(id.entity, value);
+    }
+
+    pub fn remove(&mut self, id: &VerifiedEntity<ID>) -> Option<T> {
+        self.values.remove(&id.entity)
+    }
+
+    /// Inserts `value` for `id`, failing if `id` already has a value.
+    pub fn try_insert(&mut self, id: &VerifiedEntity<ID>, value: T) -> Result<()> {
+        if self.values.contains_key(&id.entity) {
+            return Err(Error::LinkConflict {
+                column: std::any::type_name::<Self>(),
+                id: RawId::of(id.entity),
+            });
+        }
+
+        self.values.insert(id.entity, value);
+        Ok(())
+    }
+
+    pub fn try_remove(&mut self, id: &VerifiedEntity<ID>) -> Result<T> {
+        self.values
+            .remove(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    pub fn try_index(&self, id: &VerifiedEntity<ID>) -> Result<&T> {
+        self.values
+            .get(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    pub fn try_index_mut(&mut self, id: &VerifiedEntity<ID>) -> Result<&mut T> {
+        self.values
+            .get_mut(&id.entity)
+            .ok_or_else(|| Self::missing(id.entity))
+    }
+
+    fn missing(id: ID) -> Error {
+        Error::MissingComponent {
+            column: std::any::type_name::<Self>(),
+            id: RawId::of(id),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn retain(&mut self, allocator: &Allocator<ID>) {
+        self.values.retain(|id, _| allocator.is_alive(*id))
+    }
+
+    pub fn retain_verified<'a>(&'a mut self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &'a T)> {
+        self.retain(allocator);
+        self.values
+            .iter()
+            .map(|(id, t)| {
+                (VerifiedEntity::assert_valid(*id), t)
+            })
+    }
+
+    pub fn verified<'a>(&'a self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=(VerifiedEntity<'a, ID>, &'a T)> {
+        self.values
+            .iter()
+            .filter_map(move |(id, t)| {
+                let id = allocator.verify(*id)?;
+                Some((id, t))
+            })
+    }
+}
+
+impl<A: IdType, B: IdType> OrderedMap<A, B> {
+    pub fn retain_verified_both<'a>(
+        &'a mut self,
+        allocator_a: &'a Allocator<A>,
+        allocator_b: &'a Allocator<B>,
+    ) -> impl Iterator<Item=(VerifiedEntity<'a, A>, VerifiedEntity<'a, B>)> {
+        self.retain_both(allocator_a, allocator_b);
+        self.values
+            .iter()
+            .map(|(a, b)| {
+                (VerifiedEntity::assert_valid(*a), VerifiedEntity::assert_valid(*b))
+            })
+    }
+
+    pub fn verified_both<'a>(
+        &'a self,
+        allocator_a: &'a Allocator<A>,
+        allocator_b: &'a Allocator<B>,
+    ) -> impl Iterator<Item=(VerifiedEntity<'a, A>, VerifiedEntity<'a, B>)> {
+        self.values
+            .iter()
+            .filter_map(move |(a, b)| {
+                let a = allocator_a.verify(*a)?;
+                let b = allocator_b.verify(*b)?;
+                Some((a, b))
+            })
+    }
+
+    pub fn retain_both(&mut self, allocator_a: &Allocator<A>, allocator_b: &Allocator<B>) {
+        self.values.retain(|a, b| allocator_a.is_alive(*a) && allocator_b.is_alive(*b));
+    }
+}
+
+impl<ID: IdType, T> Get<ID, T> for OrderedMap<ID, T> {
+    fn get(&self, id: &VerifiedEntity<ID>) -> Option<&T> {
+        self.values.get(&id.entity)
+    }
+
+    fn get_mut(&mut self, id: &VerifiedEntity<ID>) -> Option<&mut T> {
+        self.values.get_mut(&id.entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    id_type!(ShipId);
+
+    #[test]
+    fn iterates_in_id_order_whatever_the_insertion_order() {
+        let mut ships = Allocator::<ShipId>::new();
+        let ids: Vec<_> = (0..4).map(|_| ships.create_entity().entity).collect();
+
+        let mut forwards = OrderedMap::new();
+        let mut backwards = OrderedMap::new();
+        for id in &ids {
+            forwards.insert(&VerifiedEntity::assert_valid(*id), id.index());
+        }
+        for id in ids.iter().rev() {
+            backwards.insert(&VerifiedEntity::assert_valid(*id), id.index());
+        }
+
+        assert_eq!(vec![0, 1, 2, 3], forwards.iter().map(|(_, i)| *i).collect::<Vec<_>>());
+        assert!(forwards.iter().eq(backwards.iter()));
+    }
+
+    #[test]
+    fn retain_both_drops_pairs_with_a_dead_side() {
+        let mut ships = Allocator::<ShipId>::new();
+        let ids: Vec<_> = (0..3).map(|_| ships.create_entity().entity).collect();
+
+        let mut escort = OrderedMap::new();
+        escort.insert(&VerifiedEntity::assert_valid(ids[0]), ids[1]);
+        escort.insert(&VerifiedEntity::assert_valid(ids[1]), ids[2]);
+        escort.insert(&VerifiedEntity::assert_valid(ids[2]), ids[0]);
+        ships.kill(ids[2]);
+
+        assert_eq!(1, escort.verified_both(&ships, &ships).count());
+        let kept: Vec<_> = escort.retain_verified_both(&ships, &ships).map(|(a, b)| (a.entity, b.entity)).collect();
+        assert_eq!(vec![(ids[0], ids[1])], kept);
+        assert_eq!(1, escort.len());
+    }
+
+    #[test]
+    fn try_index_reports_missing_values() {
+        let mut ships = Allocator::<ShipId>::new();
+        let named = ships.create_entity().entity;
+        let unnamed = ships.create_entity().entity;
+        let (named, unnamed) = (ships.verify(named).unwrap(), ships.verify(unnamed).unwrap());
+
+        let mut names = OrderedMap::new();
+        names.insert(&named, "Victory");
+        *names.try_index_mut(&named).unwrap() = "Beagle";
+
+        assert_eq!("Beagle", *names.try_index(&named).unwrap());
+        assert!(matches!(names.try_index(&unnamed), Err(Error::MissingComponent { .. })));
+    }
+}
@@ -0,0 +1,137 @@
+use super::*;
+use std::collections::BTreeSet;
+use crate::entities::{Allocator, RawId};
+use crate::error::{Error, Result};
+
+/// An `EntitySet` that always iterates in id order, by index and then generation.
+///
+/// The order does not depend on insertion history, so runs that hold the same ids iterate
+/// identically, as lockstep simulations and replays require. Lookups are `O(log n)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct OrderedSet<ID: IdType> { pub values: BTreeSet<ID> }
+
+impl<ID: IdType> Default for OrderedSet<ID> {
+    fn default() -> Self {
+        OrderedSet { values: Default::default() }
+    }
+}
+
+impl<ID: IdType> OrderedSet<ID> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn iter(&self) -> std::collections::btree_set::Iter<'_, ID> {
+        self.values.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn insert(&mut self, value: ID) {
+        self.values.insert(value);
+    }
+
+    pub fn remove(&mut self, value: &ID) -> Option<ID> {
+        if self.values.remove(value) {
+            Some(*value)
+        } else {
+            None
+        }
+    }
+
+    /// Inserts `value`, failing if it is already in the set.
+    pub fn try_insert(&mut self, value: ID) -> Result<()> {
+        if self.values.insert(value) {
+            Ok(())
+        } else {
+            Err(Error::LinkConflict { column: std::any::type_name::<Self>(), id: RawId::of(value) })
+        }
+    }
+
+    pub fn try_remove(&mut self, value: &ID) -> Result<ID> {
+        self.remove(value)
+            .ok_or_else(|| Error::MissingComponent { column: std::any::type_name::<Self>(), id: RawId::of(*value) })
+    }
+
+    pub fn contains(&self, value: &ID) -> bool {
+        self.values.contains(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn retain(&mut self, allocator: &Allocator<ID>) {
+        self.values.retain(|id| allocator.is_alive(*id));
+    }
+
+    pub fn retain_verified<'a>(&'a mut self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=VerifiedEntity<'a, ID>> {
+        self.retain(allocator);
+        self.values
+            .iter()
+            .map(|id| VerifiedEntity::assert_valid(*id))
+    }
+
+    pub fn verified<'a>(&'a self, allocator: &'a Allocator<ID>) -> impl Iterator<Item=VerifiedEntity<'a, ID>> {
+        self.values
+            .iter()
+            .filter_map(move |id| allocator.verify(*id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    id_type!(ShipId);
+
+    #[test]
+    fn iterates_in_id_order_whatever_the_insertion_order() {
+        let mut ships = Allocator::<ShipId>::new();
+        let ids: Vec<_> = (0..4).map(|_| ships.create_entity().entity).collect();
+
+        let forwards: OrderedSet<_> = OrderedSet { values: ids.iter().cloned().collect() };
+        let mut backwards = OrderedSet::new();
+        for id in ids.iter().rev() {
+            backwards.insert(*id);
+        }
+
+        assert_eq!(ids, backwards.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(forwards, backwards);
+    }
+
+    #[test]
+    fn retain_verified_drops_dead_ids() {
+        let mut ships = Allocator::<ShipId>::new();
+        let ids: Vec<_> = (0..3).map(|_| ships.create_entity().entity).collect();
+        let mut docked = OrderedSet::new();
+        for id in &ids {
+            docked.insert(*id);
+        }
+        ships.kill(ids[1]);
+
+        assert_eq!(2, docked.verified(&ships).count());
+        let kept: Vec<_> = docked.retain_verified(&ships).map(|id| id.entity).collect();
+        assert_eq!(vec![ids[0], ids[2]], kept);
+        assert!(!docked.contains(&ids[1]));
+    }
+
+    #[test]
+    fn try_insert_and_try_remove_report_conflicts() {
+        let mut ships = Allocator::<ShipId>::new();
+        let id = ships.create_entity().entity;
+        let mut docked = OrderedSet::new();
+
+        docked.try_insert(id).unwrap();
+        assert!(matches!(docked.try_insert(id), Err(Error::LinkConflict { .. })));
+        assert_eq!(id, docked.try_remove(&id).unwrap());
+        assert!(matches!(docked.try_remove(&id), Err(Error::MissingComponent { .. })));
+    }
+}
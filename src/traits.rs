@@ -1,5 +1,6 @@
 use std::hash::Hash;
 use crate::entities::*;
+use crate::construct::ConstructError;
 use std::fmt::Debug;
 
 pub trait IdType: Debug + Copy + Eq + Hash + Ord {
@@ -10,12 +11,36 @@ pub trait IdType: Debug + Copy + Eq + Hash + Ord {
 }
 
 pub trait Entities<ID: IdType> {
-    fn verify(&self, id: ID) -> Option<VerifiedEntity<ID>>;
+    fn verify(&self, id: ID) -> Option<VerifiedEntity<'_, ID>>;
     fn is_alive(&self, id: ID) -> bool;
-    fn create(&mut self) -> VerifiedEntity<ID>;
+    fn create(&mut self) -> VerifiedEntity<'_, ID>;
     fn delete(&mut self, id: ID);
 }
 
+/// Gives access to the allocator for `ID`, for code that must restore it exactly. Implemented by `entities!`.
+pub trait HasAllocator<ID: IdType> {
+    fn allocator(&self) -> &Allocator<ID>;
+    fn allocator_mut(&mut self) -> &mut Allocator<ID>;
+}
+
+impl<ID: IdType, E: HasAllocator<ID>> Entities<ID> for E {
+    fn verify(&self, id: ID) -> Option<VerifiedEntity<'_, ID>> {
+        self.allocator().verify(id)
+    }
+
+    fn is_alive(&self, id: ID) -> bool {
+        self.allocator().is_alive(id)
+    }
+
+    fn create(&mut self) -> VerifiedEntity<'_, ID> {
+        self.allocator_mut().create_entity()
+    }
+
+    fn delete(&mut self, id: ID) {
+        self.allocator_mut().kill(id);
+    }
+}
+
 pub trait Get<ID: IdType, T> {
     fn get(&self, id: &VerifiedEntity<ID>) -> Option<&T>;
     fn get_mut(&mut self, id: &VerifiedEntity<ID>) -> Option<&mut T>;
@@ -37,6 +62,10 @@ pub trait Link<A: IdType, B: IdType> {
     fn link(&mut self, a: &VerifiedEntity<A>, b: &VerifiedEntity<B>);
 }
 
+pub trait Unlink<A: IdType, B: IdType> {
+    fn unlink(&mut self, a: &VerifiedEntity<A>, b: &VerifiedEntity<B>);
+}
+
 //impl<A: IdType, B: IdType, L: Link<B, A>> Link<A, B> for L {}
 
 pub trait Create<'a, ID: IdType, T> : Insert<ID, T> {
@@ -88,6 +117,11 @@ pub trait Construct<ID: IdType, T> {
     fn construct(&mut self, value: T) -> ID;
 }
 
+/// Like `Construct`, but leaves no trace if construction fails.
+pub trait TryConstruct<ID: IdType, T> {
+    fn try_construct(&mut self, value: T) -> Result<ID, ConstructError>;
+}
+
 pub trait Delete<OWNER: IdType, OWNED: IdType> : Get<OWNER, OWNED> + Remove<OWNER, OWNED> {
     fn delete(&mut self, id: &VerifiedEntity<OWNER>, allocator: &mut Allocator<OWNED>) {
         if let Some(owned) = self.get(id) {
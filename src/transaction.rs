@@ -0,0 +1,493 @@
+use crate::traits::*;
+use crate::entities::VerifiedEntity;
+use crate::storage::*;
+use crate::schema::Field;
+use crate::error::{Error, Result};
+
+/// Storage whose rows can be put back exactly as they were.
+pub trait Restore<ID: IdType, T> {
+    fn row(&self, id: ID) -> Option<&T>;
+
+    /// Sets the row for `id` to `value`, or removes it if `value` is `None`, returning the previous row.
+    /// Fails, changing nothing, if the storage cannot hold that row.
+    fn swap_row(&mut self, id: ID, value: Option<T>) -> Result<Option<T>>;
+}
+
+impl<ID: IdType, T> Restore<ID, T> for IndexedVec<ID, T> {
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(id.index())
+    }
+
+    /// Rows can only be added and removed at the end, which is where rows inserted last are undone.
+    fn swap_row(&mut self, id: ID, value: Option<T>) -> Result<Option<T>> {
+        let index = id.index();
+        let len = self.values.len();
+
+        match value {
+            Some(value) if index < len => Ok(Some(std::mem::replace(&mut self.values[index], value))),
+            Some(value) => self.try_insert(&VerifiedEntity::assert_valid(id), value).map(|_| None),
+            None if index + 1 == len => Ok(self.values.pop()),
+            None => Err(Error::Invalid("only the last row of an IndexedVec can be removed")),
+        }
+    }
+}
+
+impl<ID: IdType, T> Restore<ID, T> for EntityMap<ID, T> {
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(&id)
+    }
+
+    fn swap_row(&mut self, id: ID, value: Option<T>) -> Result<Option<T>> {
+        Ok(match value {
+            Some(value) => self.values.insert(id, value),
+            None => self.values.remove(&id),
+        })
+    }
+}
+
+impl<ID: IdType> Restore<ID, ()> for EntitySet<ID> {
+    fn row(&self, id: ID) -> Option<&()> {
+        if self.contains(&id) { Some(&()) } else { None }
+    }
+
+    fn swap_row(&mut self, id: ID, value: Option<()>) -> Result<Option<()>> {
+        let existed = match value {
+            Some(()) => !self.values.insert(id),
+            None => self.values.remove(&id),
+        };
+
+        Ok(if existed { Some(()) } else { None })
+    }
+}
+
+impl<ID: IdType, T> Restore<ID, T> for OrderedMap<ID, T> {
+    fn row(&self, id: ID) -> Option<&T> {
+        self.values.get(&id)
+    }
+
+    fn swap_row(&mut self, id: ID, value: Option<T>) -> Result<Option<T>> {
+        Ok(match value {
+            Some(value) => self.values.insert(id, value),
+            None => self.values.remove(&id),
+        })
+    }
+}
+
+impl<ID: IdType> Restore<ID, ()> for OrderedSet<ID> {
+    fn row(&self, id: ID) -> Option<&()> {
+        if self.contains(&id) { Some(&()) } else { None }
+    }
+
+    fn swap_row(&mut self, id: ID, value: Option<()>) -> Result<Option<()>> {
+        let existed = match value {
+            Some(()) => !self.values.insert(id),
+            None => self.values.remove(&id),
+        };
+
+        Ok(if existed { Some(()) } else { None })
+    }
+}
+
+/// The columns `link!` and `link_to_many!` write, so that a transaction can put back the rows a link overwrote.
+pub trait LinkRows<A: IdType, B: IdType>: Sized + 'static {
+    /// Logs the rows of `a` and `b` that linking or unlinking them changes.
+    fn save_link_rows<E: 'static>(tx: &mut Transaction<'_, Self, E>, a: A, b: B);
+}
+
+/// The columns an `Insert` writes, so that a transaction can put back the rows an insert overwrote.
+/// Implemented with `insert_rows!`.
+pub trait InsertRows<ID: IdType, T>: Sized + 'static {
+    /// Logs the rows of `id` that inserting a `T` changes.
+    fn save_insert_rows<E: 'static>(tx: &mut Transaction<'_, Self, E>, id: ID);
+}
+
+/// One logged change. Undoing and redoing must each leave the world exactly as it was before the other.
+///
+/// Steps are undone newest first, so each finds the world as it left it.
+pub(crate) trait Step<S, E> {
+    fn undo(&mut self, state: &mut S, entities: &mut E);
+    fn redo(&mut self, state: &mut S, entities: &mut E);
+}
+
+pub(crate) type Log<S, E> = Vec<Box<dyn Step<S, E>>>;
+
+struct Create<ID>(ID);
+
+impl<S, E: HasAllocator<ID>, ID: IdType> Step<S, E> for Create<ID> {
+    fn undo(&mut self, _: &mut S, entities: &mut E) {
+        entities.allocator_mut().undo_create(self.0);
+    }
+
+    fn redo(&mut self, _: &mut S, entities: &mut E) {
+        let id = entities.allocator_mut().create_entity().entity;
+        debug_assert_eq!(self.0, id, "allocator changed outside of its log");
+    }
+}
+
+struct Kill<ID>(ID);
+
+impl<S, E: HasAllocator<ID>, ID: IdType> Step<S, E> for Kill<ID> {
+    fn undo(&mut self, _: &mut S, entities: &mut E) {
+        entities.allocator_mut().undo_kill(self.0);
+    }
+
+    fn redo(&mut self, _: &mut S, entities: &mut E) {
+        entities.allocator_mut().kill(self.0);
+    }
+}
+
+/// Holds the row that is not currently in the column. Swapping it in is both the undo and the redo.
+struct Row<S, C, ID, T> {
+    column: Field<S, C>,
+    id: ID,
+    value: Option<T>,
+}
+
+impl<S, C, ID, T> Row<S, C, ID, T>
+where
+    C: Restore<ID, T>,
+    ID: IdType,
+{
+    fn swap(&mut self, state: &mut S) -> Result<()> {
+        let column = self.column.name;
+        self.value = (self.column.get_mut)(state)
+            .swap_row(self.id, self.value.take())
+            .map_err(|error| error.in_column(column))?;
+        Ok(())
+    }
+}
+
+impl<S, E, C, ID, T> Step<S, E> for Row<S, C, ID, T>
+where
+    C: Restore<ID, T>,
+    ID: IdType,
+{
+    fn undo(&mut self, state: &mut S, _: &mut E) {
+        let swapped = self.swap(state);
+        debug_assert!(swapped.is_ok());
+    }
+
+    fn redo(&mut self, state: &mut S, _: &mut E) {
+        let swapped = self.swap(state);
+        debug_assert!(swapped.is_ok());
+    }
+}
+
+/// A point to roll back to without ending the transaction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Savepoint(usize);
+
+/// Logs the old value of every change made through it, so that all of them can be reversed.
+///
+/// Rolling back restores rows, allocator generations and free lists exactly, including ids that were
+/// created and killed. Dropping an uncommitted transaction rolls it back.
+pub struct Transaction<'a, S, E> {
+    state: &'a mut S,
+    entities: &'a mut E,
+    log: Log<S, E>,
+}
+
+impl<'a, S: 'static, E: 'static> Transaction<'a, S, E> {
+    pub fn new(state: &'a mut S, entities: &'a mut E) -> Self {
+        Transaction { state, entities, log: vec![] }
+    }
+
+    pub fn state(&self) -> &S {
+        self.state
+    }
+
+    pub fn entities(&self) -> &E {
+        self.entities
+    }
+
+    pub fn create_entity<ID: IdType + 'static>(&mut self) -> ID
+    where
+        E: HasAllocator<ID>,
+    {
+        let id = self.entities.allocator_mut().create_entity().entity;
+
+        self.log.push(Box::new(Create(id)));
+        id
+    }
+
+    pub fn kill<ID: IdType + 'static>(&mut self, id: ID) -> Result<()>
+    where
+        E: HasAllocator<ID>,
+    {
+        self.entities.allocator_mut().try_kill(id)?;
+
+        self.log.push(Box::new(Kill(id)));
+        Ok(())
+    }
+
+    /// Sets the row for a living `id` in `column`, returning the previous row.
+    pub fn insert<ID, T, C>(&mut self, column: Field<S, C>, id: ID, value: T) -> Result<Option<T>>
+    where
+        ID: IdType + 'static,
+        T: Clone + 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        let old = (column.get)(self.state).row(id).cloned();
+        self.swap(column, id, Some(value))?;
+        Ok(old)
+    }
+
+    /// Removes the row for a living `id` from `column`, returning it.
+    pub fn remove<ID, T, C>(&mut self, column: Field<S, C>, id: ID) -> Result<Option<T>>
+    where
+        ID: IdType + 'static,
+        T: Clone + 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        let old = (column.get)(self.state).row(id).cloned();
+        self.swap(column, id, None)?;
+        Ok(old)
+    }
+
+    /// Sets or removes the row for a living `id`, logging the row it replaced.
+    pub(crate) fn swap<ID, T, C>(&mut self, column: Field<S, C>, id: ID, value: Option<T>) -> Result<()>
+    where
+        ID: IdType + 'static,
+        T: 'static,
+        C: Restore<ID, T> + 'static,
+        E: HasAllocator<ID>,
+    {
+        self.entities.allocator().try_verify(id)?;
+
+        let mut row = Row { column, id, value };
+        row.swap(self.state)?;
+
+        self.log.push(Box::new(row));
+        Ok(())
+    }
+
+    /// Inserts `row` for a living `id` through `Insert`. Rolling back puts back every row it wrote.
+    pub fn insert_row<ID, T>(&mut self, id: ID, row: T) -> Result<()>
+    where
+        ID: IdType + 'static,
+        S: Insert<ID, T> + InsertRows<ID, T>,
+        E: HasAllocator<ID>,
+    {
+        self.entities.allocator().try_verify(id)?;
+
+        S::save_insert_rows(self, id);
+        self.state.insert(&VerifiedEntity::assert_valid(id), row);
+        Ok(())
+    }
+
+    /// Logs the current row of `id` in `column`, so that rolling back puts it back.
+    /// Used by `link!`, `link_to_many!` and `insert_rows!` before the row changes.
+    #[doc(hidden)]
+    pub fn save_row<ID, T, C>(&mut self, column: Field<S, C>, id: ID)
+    where
+        ID: IdType + 'static,
+        T: Clone + 'static,
+        C: Restore<ID, T> + 'static,
+    {
+        let value = (column.get)(self.state).row(id).cloned();
+        self.log.push(Box::new(Row { column, id, value }));
+    }
+
+    /// Links two living entities. Rolling back puts back both rows the link wrote, including any
+    /// link it replaced.
+    pub fn link<A: IdType + 'static, B: IdType + 'static>(&mut self, a: A, b: B) -> Result<()>
+    where
+        S: Link<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        HasAllocator::<A>::allocator(self.entities).try_verify(a)?;
+        HasAllocator::<B>::allocator(self.entities).try_verify(b)?;
+
+        S::save_link_rows(self, a, b);
+        self.state.link(&VerifiedEntity::assert_valid(a), &VerifiedEntity::assert_valid(b));
+        Ok(())
+    }
+
+    /// Unlinks two living entities. Rolling back puts back both rows.
+    pub fn unlink<A: IdType + 'static, B: IdType + 'static>(&mut self, a: A, b: B) -> Result<()>
+    where
+        S: Unlink<A, B> + LinkRows<A, B>,
+        E: HasAllocator<A> + HasAllocator<B>,
+    {
+        HasAllocator::<A>::allocator(self.entities).try_verify(a)?;
+        HasAllocator::<B>::allocator(self.entities).try_verify(b)?;
+
+        S::save_link_rows(self, a, b);
+        self.state.unlink(&VerifiedEntity::assert_valid(a), &VerifiedEntity::assert_valid(b));
+        Ok(())
+    }
+
+    /// Marks the current point, so that later changes can be rolled back on their own.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.log.len())
+    }
+
+    /// Reverses every change made since `savepoint`, newest first.
+    /// Savepoints taken after `savepoint` are no longer valid.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        while self.log.len() > savepoint.0 {
+            if let Some(mut step) = self.log.pop() {
+                step.undo(self.state, self.entities);
+            }
+        }
+    }
+
+    /// Keeps every change.
+    pub fn commit(mut self) {
+        self.log.clear();
+    }
+
+    /// Keeps every change and returns the log, so it can be undone and redone later.
+    pub(crate) fn commit_log(mut self) -> Log<S, E> {
+        std::mem::take(&mut self.log)
+    }
+
+    /// Reverses every change, newest first.
+    pub fn rollback(mut self) {
+        self.rollback_to(Savepoint(0));
+    }
+}
+
+impl<'a, S, E> Drop for Transaction<'a, S, E> {
+    fn drop(&mut self) {
+        while let Some(mut step) = self.log.pop() {
+            step.undo(self.state, self.entities);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{Allocator, RawId};
+
+    id_type!(UnitId);
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct Allocators {
+        units: Allocator<UnitId>,
+    }
+
+    entities!(Allocators, units: UnitId);
+
+    #[derive(Debug, Default)]
+    struct State {
+        unit_health: IndexedVec<UnitId, u32>,
+        unit_target: EntityMap<UnitId, UnitId>,
+        selected: EntitySet<UnitId>,
+    }
+
+    fn setup() -> (State, Allocators, UnitId) {
+        let mut state = State::default();
+        let mut entities = Allocators::default();
+
+        let unit = entities.units.create_entity();
+        state.unit_health.insert(&unit, 10);
+        let unit = unit.entity;
+
+        let doomed = entities.units.create_entity().entity;
+        entities.units.kill(doomed);
+
+        (state, entities, unit)
+    }
+
+    #[test]
+    fn rollback_restores_allocator_exactly() {
+        let (mut state, mut entities, unit) = setup();
+        let before = entities.clone();
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+        let reused: UnitId = tx.create_entity();
+        let fresh: UnitId = tx.create_entity();
+        tx.kill(unit).unwrap();
+        tx.kill(reused).unwrap();
+        let _again: UnitId = tx.create_entity();
+        assert_ne!(fresh, reused);
+        tx.rollback();
+
+        assert_eq!(before, entities);
+    }
+
+    #[test]
+    fn rollback_restores_rows() {
+        let (mut state, mut entities, unit) = setup();
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+        let other: UnitId = tx.create_entity();
+        assert_eq!(Some(10), tx.insert(field!(State, unit_health), unit, 5).unwrap());
+        tx.insert(field!(State, unit_health), other, 7).unwrap();
+        tx.insert(field!(State, unit_target), unit, other).unwrap();
+        tx.insert(field!(State, selected), other, ()).unwrap();
+        tx.rollback();
+
+        assert_eq!(vec![10], state.unit_health.values);
+        assert!(state.unit_target.is_empty());
+        assert!(state.selected.is_empty());
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let (mut state, mut entities, unit) = setup();
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+        tx.insert(field!(State, unit_health), unit, 5).unwrap();
+        tx.kill(unit).unwrap();
+        tx.commit();
+
+        assert_eq!(vec![5], state.unit_health.values);
+        assert!(!entities.units.is_alive(unit));
+    }
+
+    #[test]
+    fn rollback_to_savepoint() {
+        let (mut state, mut entities, unit) = setup();
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+        tx.insert(field!(State, unit_health), unit, 5).unwrap();
+        let outer = tx.savepoint();
+        tx.insert(field!(State, unit_health), unit, 4).unwrap();
+        let inner = tx.savepoint();
+        tx.kill(unit).unwrap();
+
+        tx.rollback_to(inner);
+        assert!(tx.entities().units.is_alive(unit));
+        assert_eq!(vec![4], tx.state().unit_health.values);
+
+        tx.rollback_to(outer);
+        assert_eq!(vec![5], tx.state().unit_health.values);
+        tx.commit();
+
+        assert_eq!(vec![5], state.unit_health.values);
+    }
+
+    #[test]
+    fn rows_an_indexed_vec_cannot_hold_are_errors() {
+        let (mut state, mut entities, unit) = setup();
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+        let other: UnitId = tx.create_entity();
+        let last: UnitId = tx.create_entity();
+
+        assert!(matches!(tx.insert(field!(State, unit_health), last, 1), Err(Error::IndexOutOfBounds { column: "unit_health", .. })));
+        tx.insert(field!(State, unit_health), other, 7).unwrap();
+        assert!(matches!(tx.remove(field!(State, unit_health), unit), Err(Error::Invalid(_))));
+        assert_eq!(Some(7), tx.remove(field!(State, unit_health), other).unwrap());
+        tx.rollback();
+
+        assert_eq!(vec![10], state.unit_health.values);
+    }
+
+    #[test]
+    fn dead_entity_is_an_error() {
+        let (mut state, mut entities, unit) = setup();
+        entities.units.kill(unit);
+
+        let mut tx = Transaction::new(&mut state, &mut entities);
+
+        let dead = |error: Error| matches!(error, Error::DeadEntity { id, .. } if id == RawId::of(unit));
+        assert!(dead(tx.insert(field!(State, unit_health), unit, 1).unwrap_err()));
+        assert!(dead(tx.kill(unit).unwrap_err()));
+    }
+}